@@ -0,0 +1,328 @@
+use crate::repository::Repository;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, BufWriter, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+/// Кеш хешей в корне target_base: одна JSON-строка на каждый файл, сохраненный
+/// последним запуском, который обходил его директорию
+pub const CACHE_FILE: &str = "hash_cache.jsonl";
+
+/// Состояние файла; пока оно не изменилось, хеш содержимого считается прежним
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileStamp {
+    pub size: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mtime: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mtime_nsec: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub inode: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ctime: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ctime_nsec: Option<i64>,
+}
+
+impl FileStamp {
+    pub fn new(metadata: &fs::Metadata) -> Self {
+        let modified = metadata
+            .modified()
+            .ok()
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok());
+        let (inode, ctime, ctime_nsec) = change_identity(metadata);
+
+        Self {
+            size: metadata.len(),
+            mtime: modified.map(|d| d.as_secs() as i64),
+            mtime_nsec: modified.map(|d| d.subsec_nanos()),
+            inode,
+            ctime,
+            ctime_nsec,
+        }
+    }
+}
+
+/// Номер inode и время изменения метаданных (ctime)
+#[cfg(unix)]
+fn change_identity(metadata: &fs::Metadata) -> (Option<u64>, Option<i64>, Option<i64>) {
+    use std::os::unix::fs::MetadataExt;
+    (
+        Some(metadata.ino()),
+        Some(metadata.ctime()),
+        Some(metadata.ctime_nsec()),
+    )
+}
+
+#[cfg(not(unix))]
+fn change_identity(_metadata: &fs::Metadata) -> (Option<u64>, Option<i64>, Option<i64>) {
+    (None, None, None)
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct CacheRecord {
+    path: PathBuf,
+    #[serde(flatten)]
+    stamp: FileStamp,
+    hash: String,
+}
+
+/// Кеш хешей исходных файлов между запусками. Записи файлов внутри обходимых
+/// директорий заменяются файлами текущего запуска, поэтому удаленные файлы выпадают
+/// из кеша; записи остальных директорий (например, источников другого профиля)
+/// сохраняются без изменений
+#[derive(Debug)]
+pub struct HashCache {
+    path: PathBuf,
+    previous: HashMap<PathBuf, (FileStamp, String)>,
+    current: HashMap<PathBuf, (FileStamp, String)>,
+    /// Записи вне обходимых директорий: не используются, но записываются обратно
+    kept: HashMap<PathBuf, (FileStamp, String)>,
+    /// Ошибка чтения кеша, из-за которой он отброшен при загрузке
+    load_error: Option<anyhow::Error>,
+}
+
+impl HashCache {
+    /// Пустой кеш: все файлы хешируются заново, результат сохраняется для следующего запуска
+    pub fn empty(repository: &Repository) -> Self {
+        Self {
+            path: repository.root().join(CACHE_FILE),
+            previous: HashMap::new(),
+            current: HashMap::new(),
+            kept: HashMap::new(),
+            load_error: None,
+        }
+    }
+
+    /// Загружает кеш хранилища для запуска, обходящего директории `roots`
+    /// (абсолютные пути, как и ключи кеша). Кеш не влияет на корректность снимка,
+    /// поэтому поврежденный кеш отбрасывается; ошибку возвращает take_load_error
+    pub fn load(repository: &Repository, roots: &[PathBuf]) -> Self {
+        let mut cache = Self::empty(repository);
+        match read_records(&cache.path) {
+            Ok(records) => {
+                let (previous, kept) = records
+                    .into_iter()
+                    .map(|r| (r.path, (r.stamp, r.hash)))
+                    .partition(|(path, _)| roots.iter().any(|root| path.starts_with(root)));
+                cache.previous = previous;
+                cache.kept = kept;
+            }
+            Err(e) => cache.load_error = Some(e),
+        }
+        cache
+    }
+
+    /// Путь файла кеша
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Забирает ошибку, из-за которой кеш отброшен при загрузке
+    pub fn take_load_error(&mut self) -> Option<anyhow::Error> {
+        self.load_error.take()
+    }
+
+    /// Забывает прежние хеши обходимых директорий: все их файлы хешируются заново
+    pub fn forget_previous(&mut self) {
+        self.previous.clear();
+    }
+
+    /// Возвращает прежний хеш файла, если его состояние не изменилось
+    pub fn lookup(&self, path: &Path, stamp: &FileStamp) -> Option<&str> {
+        self.previous
+            .get(path)
+            .filter(|(cached, _)| cached == stamp)
+            .map(|(_, hash)| hash.as_str())
+    }
+
+    pub fn insert(&mut self, path: PathBuf, stamp: FileStamp, hash: String) {
+        self.current.insert(path, (stamp, hash));
+    }
+
+    /// Атомарно записывает кеш (через временный файл и переименование): файлы
+    /// текущего запуска и записи директорий, которые он не обходил
+    pub fn save(&self) -> Result<()> {
+        let dir = self.path.parent().unwrap_or(Path::new("."));
+        let temp = tempfile::Builder::new()
+            .prefix(".hash_cache")
+            .tempfile_in(dir)
+            .with_context(|| format!("Failed to create temporary file in {}", dir.display()))?;
+
+        let mut output = BufWriter::new(temp.as_file());
+        let mut entries: Vec<_> = self
+            .kept
+            .iter()
+            .filter(|(path, _)| !self.current.contains_key(*path))
+            .chain(&self.current)
+            .collect();
+        entries.sort_by_key(|(path, _)| *path);
+        for (path, (stamp, hash)) in entries {
+            let record = CacheRecord {
+                path: path.clone(),
+                stamp: stamp.clone(),
+                hash: hash.clone(),
+            };
+            serde_json::to_writer(&mut output, &record)
+                .with_context(|| format!("Failed to write to file: {}", self.path.display()))?;
+            writeln!(output)
+                .with_context(|| format!("Failed to write to file: {}", self.path.display()))?;
+        }
+        output
+            .flush()
+            .with_context(|| format!("Failed to write to file: {}", self.path.display()))?;
+        drop(output);
+
+        temp.persist(&self.path)
+            .map_err(|e| e.error)
+            .with_context(|| format!("Failed to write file: {}", self.path.display()))?;
+        Ok(())
+    }
+}
+
+fn read_records(path: &Path) -> Result<Vec<CacheRecord>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => {
+            return Err(e).with_context(|| format!("Failed to open file: {}", path.display()));
+        }
+    };
+
+    let mut records = Vec::new();
+    for (index, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.with_context(|| format!("Failed to read file: {}", path.display()))?;
+        let record = serde_json::from_str(&line).with_context(|| {
+            format!(
+                "Failed to parse hash cache {} at line {}",
+                path.display(),
+                index + 1
+            )
+        })?;
+        records.push(record);
+    }
+
+    Ok(records)
+}
+
+/// Удаляет кеш хешей хранилища; возвращает true, если кеш существовал.
+/// Вызывается под блокировкой хранилища, чтобы не удалить кеш идущего запуска
+pub fn clear_cache(repository: &Repository) -> Result<bool> {
+    let path = repository.root().join(CACHE_FILE);
+    match fs::remove_file(&path) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e).with_context(|| format!("Failed to remove file: {}", path.display())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    const TEST_MD5: &str = "098f6bcd4621d373cade4e832627b4f6";
+
+    fn create_repository() -> (TempDir, Repository) {
+        let temp_dir = TempDir::new().unwrap();
+        let repository = Repository::open_or_init(temp_dir.path().to_str().unwrap(), None).unwrap();
+        (temp_dir, repository)
+    }
+
+    #[test]
+    fn test_cache_roundtrip() {
+        let (temp_dir, repository) = create_repository();
+        let source = temp_dir.path().join("a.txt");
+        fs::write(&source, "test").unwrap();
+        let stamp = FileStamp::new(&fs::metadata(&source).unwrap());
+
+        let roots = [temp_dir.path().to_path_buf()];
+
+        let mut cache = HashCache::load(&repository, &roots);
+        assert_eq!(cache.lookup(&source, &stamp), None);
+        cache.insert(source.clone(), stamp.clone(), TEST_MD5.to_string());
+        cache.save().unwrap();
+
+        let cache = HashCache::load(&repository, &roots);
+        assert_eq!(cache.lookup(&source, &stamp), Some(TEST_MD5));
+    }
+
+    #[test]
+    fn test_cache_miss_on_changed_file() {
+        let (temp_dir, repository) = create_repository();
+        let source = temp_dir.path().join("a.txt");
+        fs::write(&source, "test").unwrap();
+        let stamp = FileStamp::new(&fs::metadata(&source).unwrap());
+
+        let mut cache = HashCache::empty(&repository);
+        cache.insert(source.clone(), stamp.clone(), TEST_MD5.to_string());
+        cache.save().unwrap();
+
+        fs::write(&source, "changed").unwrap();
+        let changed = FileStamp::new(&fs::metadata(&source).unwrap());
+
+        let cache = HashCache::load(&repository, &[temp_dir.path().to_path_buf()]);
+        assert_eq!(cache.lookup(&source, &changed), None);
+    }
+
+    #[test]
+    fn test_cache_keeps_roots_not_walked() {
+        let (temp_dir, repository) = create_repository();
+        let stamp = FileStamp::new(&fs::metadata(temp_dir.path()).unwrap());
+        let photos = [PathBuf::from("/photos")];
+        let docs = [PathBuf::from("/docs")];
+
+        let mut cache = HashCache::empty(&repository);
+        cache.insert(
+            PathBuf::from("/photos/a.jpg"),
+            stamp.clone(),
+            TEST_MD5.to_string(),
+        );
+        cache.insert(
+            PathBuf::from("/docs/b.txt"),
+            stamp.clone(),
+            TEST_MD5.to_string(),
+        );
+        cache.save().unwrap();
+
+        // Запуск по /docs, в котором b.txt уже нет: /photos он не обходил
+        let cache = HashCache::load(&repository, &docs);
+        assert_eq!(cache.lookup(Path::new("/photos/a.jpg"), &stamp), None);
+        cache.save().unwrap();
+
+        let cache = HashCache::load(&repository, &photos);
+        assert_eq!(
+            cache.lookup(Path::new("/photos/a.jpg"), &stamp),
+            Some(TEST_MD5)
+        );
+        let cache = HashCache::load(&repository, &docs);
+        assert_eq!(cache.lookup(Path::new("/docs/b.txt"), &stamp), None);
+    }
+
+    #[test]
+    fn test_corrupt_cache_is_ignored() {
+        let (temp_dir, repository) = create_repository();
+        fs::write(temp_dir.path().join(CACHE_FILE), "broken").unwrap();
+
+        let mut cache = HashCache::load(&repository, &[temp_dir.path().to_path_buf()]);
+        assert!(cache.previous.is_empty());
+        assert!(cache
+            .take_load_error()
+            .unwrap()
+            .to_string()
+            .contains("Failed to parse hash cache"));
+    }
+
+    #[test]
+    fn test_clear_cache() {
+        let (temp_dir, repository) = create_repository();
+        HashCache::empty(&repository).save().unwrap();
+
+        assert!(clear_cache(&repository).unwrap());
+        assert!(!temp_dir.path().join(CACHE_FILE).exists());
+        assert!(!clear_cache(&repository).unwrap());
+    }
+}
@@ -0,0 +1,628 @@
+use crate::chunking::ChunkingConfig;
+use crate::config::{self, Config, InvalidValue, KeyPart, Source, PROFILES_KEY};
+use crate::filter;
+use crate::prune::RetentionPolicy;
+use anyhow::{Context, Result};
+use serde::de::{self, DeserializeOwned, Deserializer, Visitor};
+use serde::Serialize;
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
+use std::ops::Range;
+use toml_edit::{ImDocument, Item, TableLike, Value};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    /// Настройка читается, но, вероятно, означает не то, что задумано
+    Warning,
+    /// Настройка не читается или игнорируется
+    Error,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Warning => write!(f, "warning"),
+            Severity::Error => write!(f, "error"),
+        }
+    }
+}
+
+/// Проблема в файле конфигурации; строка и столбец считаются с 1
+#[derive(Debug, Serialize)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub line: Option<usize>,
+    pub column: Option<usize>,
+    pub message: String,
+}
+
+/// Проверяет файл конфигурации: синтаксис, неизвестные поля, значения,
+/// которые читаются не так, как записаны, и сами общие настройки и профили.
+/// Проблемы возвращаются в порядке их положения в файле
+pub fn check_config(path: &str) -> Result<Vec<Diagnostic>> {
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read config file: {}", path))?;
+    let mut checker = Checker {
+        content: &content,
+        diagnostics: Vec::new(),
+    };
+
+    let document = match ImDocument::parse(content.as_str()) {
+        Ok(document) => document,
+        Err(e) => {
+            checker.report(Severity::Error, e.span(), e.message().trim_end());
+            return Ok(checker.diagnostics);
+        }
+    };
+    let root = document.as_table();
+    checker.check_table(root, true);
+
+    let profiles = root.get(PROFILES_KEY).and_then(Item::as_table_like);
+    let extensions_span = root.key("extensions").and_then(|key| key.span());
+    match Config::from_file(path) {
+        Ok(config) => checker.check_filters(&config, extensions_span, ""),
+        // Конфигурация только из профилей допустима, если она всегда используется с --profile
+        Err(e) if profiles.is_some_and(|profiles| !profiles.is_empty()) => {
+            let (span, message) = error_location(&e, &[root]);
+            checker.report(
+                Severity::Warning,
+                span,
+                format!("without --profile: {}", message),
+            );
+        }
+        Err(e) => {
+            let (span, message) = error_location(&e, &[root]);
+            checker.report(Severity::Error, span, message);
+        }
+    }
+
+    for (name, profile) in profiles.into_iter().flat_map(|profiles| profiles.iter()) {
+        let span = profiles
+            .and_then(|profiles| profiles.key(name))
+            .and_then(|key| key.span());
+        let prefix = format!("profile {}: ", name);
+        match parse_profile(path, name) {
+            Ok(config) => checker.check_filters(&config, span, &prefix),
+            Err(e) => {
+                // Профиль наложен на общие настройки: значение ищется сначала в профиле
+                let tables: Vec<&dyn TableLike> = profile
+                    .as_table_like()
+                    .into_iter()
+                    .chain([root as &dyn TableLike])
+                    .collect();
+                let (value_span, message) = error_location(&e, &tables);
+                checker.report(
+                    Severity::Error,
+                    value_span.or(span),
+                    format!("{}{}", prefix, message),
+                );
+            }
+        }
+    }
+
+    let mut diagnostics = checker.diagnostics;
+    diagnostics.sort_by_key(|d| (d.line.is_none(), d.line, d.column));
+    Ok(diagnostics)
+}
+
+struct Checker<'a> {
+    content: &'a str,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Checker<'_> {
+    fn report(
+        &mut self,
+        severity: Severity,
+        span: Option<Range<usize>>,
+        message: impl Into<String>,
+    ) {
+        let (line, column) = match span {
+            Some(span) => {
+                let (line, column) = position(self.content, span.start);
+                (Some(line), Some(column))
+            }
+            None => (None, None),
+        };
+        self.diagnostics.push(Diagnostic {
+            severity,
+            line,
+            column,
+            message: message.into(),
+        });
+    }
+
+    /// Проверяет поля общих настроек или профиля
+    fn check_table(&mut self, table: &dyn TableLike, top_level: bool) {
+        for (name, item) in table.iter() {
+            match name {
+                "extensions" => self.check_extensions(item),
+                "include" | "exclude" => self.check_patterns(name, item),
+                "hidden_allow" => self.check_hidden_allow(item),
+                "retention" => {
+                    if let Some(retention) = item.as_table_like() {
+                        let fields = struct_fields::<RetentionPolicy>();
+                        self.check_fields(retention, fields, "retention");
+                    }
+                }
+                "chunking" => {
+                    if let Some(chunking) = item.as_table_like() {
+                        let fields = struct_fields::<ChunkingConfig>();
+                        self.check_fields(chunking, fields, "chunking");
+                    }
+                }
+                "sources" => {
+                    for source in tables(item) {
+                        self.check_fields(source, struct_fields::<Source>(), "sources");
+                    }
+                }
+                PROFILES_KEY if top_level => {
+                    let profiles = item.as_table_like().into_iter();
+                    for (_, profile) in profiles.flat_map(|profiles| profiles.iter()) {
+                        if let Some(profile) = profile.as_table_like() {
+                            self.check_table(profile, false);
+                        }
+                    }
+                }
+                name if struct_fields::<Config>().contains(&name) => {}
+                name => {
+                    let span = table.key(name).and_then(|key| key.span());
+                    self.report(Severity::Error, span, format!("unknown field `{}`", name));
+                }
+            }
+        }
+    }
+
+    fn check_fields(&mut self, table: &dyn TableLike, fields: &[&str], section: &str) {
+        for (name, _) in table.iter() {
+            if !fields.contains(&name) {
+                let span = table.key(name).and_then(|key| key.span());
+                self.report(
+                    Severity::Error,
+                    span,
+                    format!("unknown field `{}` in {}", name, section),
+                );
+            }
+        }
+    }
+
+    /// Расширения сравниваются без точки и пробелов по краям: ".jpg" читается как "jpg"
+    fn check_extensions(&mut self, item: &Item) {
+        let mut seen = HashSet::new();
+        for value in item.as_array().into_iter().flatten() {
+            let Some(raw) = value.as_str() else {
+                continue;
+            };
+            let normalized = config::normalize_extension(raw);
+            if normalized.is_empty() {
+                self.report(
+                    Severity::Error,
+                    value.span(),
+                    format!("empty extension {:?}", raw),
+                );
+                continue;
+            }
+            if normalized != raw.to_lowercase() {
+                self.report(
+                    Severity::Warning,
+                    value.span(),
+                    format!("extension {:?} is read as {:?}", raw, normalized),
+                );
+            }
+            if !seen.insert(normalized) {
+                self.report(
+                    Severity::Warning,
+                    value.span(),
+                    format!("duplicate extension {:?}", raw),
+                );
+            }
+        }
+    }
+
+    fn check_patterns(&mut self, name: &str, item: &Item) {
+        for value in item.as_array().into_iter().flatten() {
+            let Some(pattern) = value.as_str() else {
+                continue;
+            };
+            if pattern.trim().is_empty() {
+                self.report(
+                    Severity::Warning,
+                    value.span(),
+                    format!("empty pattern in {} matches nothing", name),
+                );
+            } else if let Err(e) = filter::check_pattern(pattern) {
+                self.report(Severity::Error, value.span(), format!("{:#}", e));
+            }
+        }
+    }
+
+    fn check_hidden_allow(&mut self, item: &Item) {
+        for value in item.as_array().into_iter().flatten() {
+            if let Some(name) = value.as_str()
+                && !name.starts_with('.')
+            {
+                self.report(
+                    Severity::Warning,
+                    value.span(),
+                    format!(
+                        "{:?} in hidden_allow has no effect: only names starting with a dot are hidden",
+                        name
+                    ),
+                );
+            }
+        }
+    }
+
+    /// Без расширений и шаблонов include ни один файл не попадет в снимок
+    fn check_filters(&mut self, config: &Config, span: Option<Range<usize>>, prefix: &str) {
+        if config.extensions.is_empty() && config.include.is_empty() {
+            self.report(
+                Severity::Warning,
+                span,
+                format!(
+                    "{}extensions and include are empty: no files will be backed up",
+                    prefix
+                ),
+            );
+        }
+    }
+}
+
+/// Таблицы `[[sources]]` или встроенные таблицы массива `sources = [{ ... }]`
+fn tables(item: &Item) -> Vec<&dyn TableLike> {
+    if let Some(tables) = item.as_array_of_tables() {
+        return tables.iter().map(|table| table as &dyn TableLike).collect();
+    }
+    item.as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|value| value.as_inline_table())
+        .map(|table| table as &dyn TableLike)
+        .collect()
+}
+
+/// Имена полей структуры в том виде, в каком их читает serde: сгенерированная
+/// десериализация передает их в `deserialize_struct`, на котором она и прерывается
+fn struct_fields<T: DeserializeOwned>() -> &'static [&'static str] {
+    struct FieldNames<'a>(&'a mut &'static [&'static str]);
+
+    impl<'de> Deserializer<'de> for FieldNames<'_> {
+        type Error = de::value::Error;
+
+        fn deserialize_any<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, Self::Error> {
+            Err(de::Error::custom("expected a struct"))
+        }
+
+        fn deserialize_struct<V: Visitor<'de>>(
+            self,
+            _name: &'static str,
+            fields: &'static [&'static str],
+            _visitor: V,
+        ) -> Result<V::Value, Self::Error> {
+            *self.0 = fields;
+            Err(de::Error::custom("field names recorded"))
+        }
+
+        serde::forward_to_deserialize_any! {
+            bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+            bytes byte_buf option unit unit_struct newtype_struct seq tuple
+            tuple_struct map enum identifier ignored_any
+        }
+    }
+
+    let mut fields: &'static [&'static str] = &[];
+    let _ = T::deserialize(FieldNames(&mut fields));
+    fields
+}
+
+/// Разбирает профиль как `Config::from_profile`, но из текста объединенной таблицы:
+/// у объединенных значений нет положения в файле, поэтому ошибка разбора
+/// переводится в путь к значению, который затем ищется в самом файле
+fn parse_profile(path: &str, name: &str) -> Result<Config> {
+    let table = Config::profile_table(path, name)?;
+    let content = toml::to_string(&table)
+        .with_context(|| format!("Failed to parse profile {} in config file: {}", name, path))?;
+
+    Config::parse(&content, path).map_err(|e| {
+        let Some(toml_error) = e
+            .chain()
+            .find_map(|cause| cause.downcast_ref::<toml::de::Error>())
+        else {
+            return e;
+        };
+        let key_path = toml_error
+            .span()
+            .zip(ImDocument::parse(content.as_str()).ok())
+            .and_then(|(span, document)| path_at(document.as_table(), span.start))
+            .unwrap_or_default();
+        InvalidValue::new(key_path, toml_error.message()).into()
+    })
+}
+
+/// Путь к самому глубокому значению, которое содержит смещение `offset`
+fn path_at(table: &dyn TableLike, offset: usize) -> Option<Vec<KeyPart>> {
+    table.iter().find_map(|(name, item)| {
+        let mut path = match item {
+            Item::Value(value) => value_path_at(value, offset),
+            Item::Table(table) => path_at(table, offset),
+            Item::ArrayOfTables(array) => array.iter().enumerate().find_map(|(index, table)| {
+                let mut path = path_at(table, offset)?;
+                path.insert(0, KeyPart::Index(index));
+                Some(path)
+            }),
+            Item::None => None,
+        }?;
+        path.insert(0, KeyPart::Field(name.to_string()));
+        Some(path)
+    })
+}
+
+fn value_path_at(value: &Value, offset: usize) -> Option<Vec<KeyPart>> {
+    let nested = match value {
+        Value::InlineTable(table) => path_at(table, offset),
+        Value::Array(array) => array.iter().enumerate().find_map(|(index, value)| {
+            let mut path = value_path_at(value, offset)?;
+            path.insert(0, KeyPart::Index(index));
+            Some(path)
+        }),
+        _ => None,
+    };
+    nested.or_else(|| {
+        value
+            .span()
+            .filter(|span| span.contains(&offset))
+            .map(|_| Vec::new())
+    })
+}
+
+/// Положение значения по пути от корня таблицы: для вложенных таблиц
+/// указывается их ключ, для остальных значений - само значение
+fn value_span(table: &dyn TableLike, key_path: &[KeyPart]) -> Option<Range<usize>> {
+    let (KeyPart::Field(name), rest) = key_path.split_first()? else {
+        return None;
+    };
+    let item = table.get(name)?;
+    match rest.split_first() {
+        None => match item {
+            Item::Value(value) if !value.is_inline_table() => value.span(),
+            _ => table.key(name)?.span(),
+        },
+        Some((KeyPart::Index(index), rest)) => value_span(*tables(item).get(*index)?, rest),
+        Some((KeyPart::Field(_), _)) => value_span(item.as_table_like()?, rest),
+    }
+}
+
+/// Положение ошибки, если оно известно, и сообщение без пути к файлу. Ошибка
+/// разбора TOML несет положение сама, а недопустимое значение ищется по пути
+/// в `tables` по порядку
+fn error_location(
+    error: &anyhow::Error,
+    tables: &[&dyn TableLike],
+) -> (Option<Range<usize>>, String) {
+    for cause in error.chain() {
+        if let Some(toml_error) = cause.downcast_ref::<toml::de::Error>() {
+            return (toml_error.span(), toml_error.message().to_string());
+        }
+        if let Some(invalid) = cause.downcast_ref::<InvalidValue>() {
+            let span = tables
+                .iter()
+                .find_map(|table| value_span(*table, &invalid.path));
+            return (span, invalid.message.clone());
+        }
+    }
+    (None, error.root_cause().to_string())
+}
+
+/// Номер строки и столбца (в символах) для смещения в байтах
+fn position(content: &str, offset: usize) -> (usize, usize) {
+    let before = content.get(..offset).unwrap_or(content);
+    let line = before.matches('\n').count() + 1;
+    let line_start = before.rfind('\n').map_or(0, |index| index + 1);
+    (line, before[line_start..].chars().count() + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use tempfile::NamedTempFile;
+
+    fn check(content: &str) -> Vec<Diagnostic> {
+        let mut config_file = NamedTempFile::new().unwrap();
+        write!(config_file, "{}", content).unwrap();
+        check_config(config_file.path().to_str().unwrap()).unwrap()
+    }
+
+    fn summary(diagnostics: &[Diagnostic]) -> Vec<String> {
+        diagnostics
+            .iter()
+            .map(|d| {
+                format!(
+                    "{}:{} {}: {}",
+                    d.line.unwrap_or(0),
+                    d.column.unwrap_or(0),
+                    d.severity,
+                    d.message
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_valid_config() {
+        let diagnostics = check(
+            r#"
+extensions = ["txt", "jpg"]
+exclude = ["node_modules/"]
+hidden_allow = [".config"]
+sources = [{ name = "home", path = "/home" }]
+
+[retention]
+keep_last = 3
+
+[chunking]
+enabled = true
+avg_size = 2097152
+
+[profile.photos]
+extensions = ["png"]
+"#,
+        );
+
+        assert!(diagnostics.is_empty(), "{:?}", summary(&diagnostics));
+    }
+
+    #[test]
+    fn test_unknown_fields() {
+        let diagnostics = check(
+            r#"extensions = ["txt"]
+exlude = ["*.tmp"]
+
+[[sources]]
+name = "home"
+path = "/home"
+label = "Home"
+
+[retention]
+keep_yearly = 2
+
+[profile.photos]
+extension = ["jpg"]
+chunking = { enabled = true, chunk_size = 65536 }
+"#,
+        );
+
+        assert_eq!(
+            summary(&diagnostics),
+            vec![
+                "2:1 error: unknown field `exlude`",
+                "7:1 error: unknown field `label` in sources",
+                "10:1 error: unknown field `keep_yearly` in retention",
+                "13:1 error: unknown field `extension`",
+                "14:30 error: unknown field `chunk_size` in chunking",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_extensions() {
+        let diagnostics = check(r#"extensions = ["txt", ".jpg", " PDF ", "", "TXT"]"#);
+
+        assert_eq!(
+            summary(&diagnostics),
+            vec![
+                r#"1:22 warning: extension ".jpg" is read as "jpg""#,
+                r#"1:30 warning: extension " PDF " is read as "pdf""#,
+                r#"1:39 error: empty extension """#,
+                r#"1:43 warning: duplicate extension "TXT""#,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_empty_filters_and_patterns() {
+        let diagnostics = check(
+            r#"
+extensions = []
+exclude = ["", "{a,b"]
+hidden_allow = ["config"]
+"#,
+        );
+
+        let summary = summary(&diagnostics);
+        assert_eq!(summary.len(), 4);
+        assert_eq!(
+            summary[0],
+            "2:1 warning: extensions and include are empty: no files will be backed up"
+        );
+        assert_eq!(
+            summary[1],
+            "3:12 warning: empty pattern in exclude matches nothing"
+        );
+        assert!(summary[2].starts_with("3:16 error: Invalid pattern: {a,b"));
+        assert!(summary[3].starts_with("4:17 warning: \"config\" in hidden_allow has no effect"));
+    }
+
+    #[test]
+    fn test_syntax_and_type_errors() {
+        let diagnostics = check("extensions = [\"txt\"]\nretries = \"two\"\n");
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].severity, Severity::Error);
+        assert_eq!(diagnostics[0].line, Some(2));
+        assert!(diagnostics[0].message.contains("invalid type"));
+
+        let diagnostics = check("extensions = [\"txt\"\n");
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].line, Some(2));
+    }
+
+    #[test]
+    fn test_profiles() {
+        let diagnostics = check(
+            r#"
+[profile.photos]
+extensions = ["jpg"]
+
+[profile.docs]
+include = ["*.md"]
+hash_algorithm = "crc32"
+"#,
+        );
+
+        let summary = summary(&diagnostics);
+        assert_eq!(summary.len(), 2);
+        // Без общих расширений конфигурация работает только с --profile
+        assert!(
+            summary[0].starts_with("1:1 warning: without --profile: missing field `extensions`")
+        );
+        assert!(summary[1].starts_with("7:18 error: profile docs: unknown variant `crc32`"));
+    }
+
+    #[test]
+    fn test_invalid_values() {
+        let diagnostics = check(
+            r#"extensions = ["txt"]
+buffer_size = 0
+
+[profile.home]
+buffer_size = 4096
+sources = [{ name = "home", path = "/home" }, { name = "a/b", path = "/srv" }]
+
+[profile.large]
+buffer_size = 4096
+chunking = { enabled = true, min_size = 1 }
+
+[profile.small]
+buffer_size = 4096
+
+[profile.small.chunking]
+max_size = 65536
+"#,
+        );
+
+        assert_eq!(
+            summary(&diagnostics),
+            vec![
+                "2:15 warning: without --profile: buffer_size must be greater than zero",
+                r#"6:56 error: profile home: invalid source name: "a/b""#,
+                "10:41 error: profile large: chunking.min_size must be between 64 and 1048576, got 1",
+                "15:16 error: profile small: chunking sizes must satisfy min_size <= avg_size <= max_size",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_struct_fields() {
+        assert_eq!(struct_fields::<Source>(), ["name", "path"]);
+        assert_eq!(
+            struct_fields::<ChunkingConfig>(),
+            ["enabled", "min_size", "avg_size", "max_size"]
+        );
+        let fields = struct_fields::<Config>();
+        assert!(fields.contains(&"extensions"));
+        assert!(fields.contains(&"chunking"));
+        assert!(!fields.contains(&PROFILES_KEY));
+    }
+}
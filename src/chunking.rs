@@ -0,0 +1,528 @@
+use crate::config::{InvalidValue, KeyPart};
+use crate::directory::{self, StoredObject};
+use crate::hash::{HashAlgorithm, Hasher};
+use crate::repository::Repository;
+use anyhow::{bail, Context, Result};
+use fastcdc::v2020::{self as cdc, StreamCDC};
+use serde::Deserialize;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+/// Разбиение файлов на фрагменты по содержимому (FastCDC). Границы фрагментов
+/// определяются скользящим хешем по самим данным, поэтому вставка или дописывание
+/// в большой файл меняет только соседние фрагменты, а остальные дедуплицируются
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct ChunkingConfig {
+    /// Новые файлы сохраняются фрагментами; прочитать хранилище можно и без этого
+    pub enabled: bool,
+    /// Минимальный, средний и максимальный размер фрагмента, в байтах
+    pub min_size: u32,
+    pub avg_size: u32,
+    pub max_size: u32,
+}
+
+impl Default for ChunkingConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            min_size: 256 * 1024,
+            avg_size: 1024 * 1024,
+            max_size: 4 * 1024 * 1024,
+        }
+    }
+}
+
+impl ChunkingConfig {
+    /// Проверяет размеры по ограничениям FastCDC (иначе он паникует)
+    pub fn validate(&self) -> Result<()> {
+        for (name, size, min, max) in [
+            (
+                "min_size",
+                self.min_size,
+                cdc::MINIMUM_MIN,
+                cdc::MINIMUM_MAX,
+            ),
+            (
+                "avg_size",
+                self.avg_size,
+                cdc::AVERAGE_MIN,
+                cdc::AVERAGE_MAX,
+            ),
+            (
+                "max_size",
+                self.max_size,
+                cdc::MAXIMUM_MIN,
+                cdc::MAXIMUM_MAX,
+            ),
+        ] {
+            if !(min..=max).contains(&size) {
+                return Err(InvalidValue::new(
+                    vec![
+                        KeyPart::Field("chunking".to_string()),
+                        KeyPart::Field(name.to_string()),
+                    ],
+                    format!(
+                        "chunking.{} must be between {} and {}, got {}",
+                        name, min, max, size
+                    ),
+                )
+                .into());
+            }
+        }
+        if !(self.min_size <= self.avg_size && self.avg_size <= self.max_size) {
+            return Err(InvalidValue::new(
+                vec![KeyPart::Field("chunking".to_string())],
+                "chunking sizes must satisfy min_size <= avg_size <= max_size",
+            )
+            .into());
+        }
+        Ok(())
+    }
+}
+
+/// Читает данные, одновременно хешируя их целиком
+struct HashingReader<R> {
+    inner: R,
+    hasher: Box<dyn Hasher>,
+    size: u64,
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        self.size += read as u64;
+        Ok(read)
+    }
+}
+
+/// Разбивает содержимое `reader` на фрагменты и передает их по порядку в `on_chunk`.
+/// Возвращает хеш и размер всего содержимого
+fn split<R: Read>(
+    reader: R,
+    algorithm: HashAlgorithm,
+    chunking: &ChunkingConfig,
+    mut on_chunk: impl FnMut(&[u8]) -> Result<()>,
+) -> Result<(String, u64)> {
+    let mut reader = HashingReader {
+        inner: reader,
+        hasher: algorithm.hasher(),
+        size: 0,
+    };
+
+    let chunker = StreamCDC::new(
+        &mut reader,
+        chunking.min_size,
+        chunking.avg_size,
+        chunking.max_size,
+    );
+    for chunk in chunker {
+        let chunk = chunk.map_err(io::Error::from)?;
+        on_chunk(&chunk.data)?;
+    }
+
+    Ok((reader.hasher.finalize_hex(), reader.size))
+}
+
+fn hash_bytes(data: &[u8], algorithm: HashAlgorithm) -> String {
+    let mut hasher = algorithm.hasher();
+    hasher.update(data);
+    hasher.finalize_hex()
+}
+
+/// Сохраняет файл фрагментами: каждый фрагмент - объект в objects_dir, а файл -
+/// список хешей фрагментов в chunk_lists_dir под хешем всего содержимого.
+/// Файл читается один раз: фрагменты пишутся по мере разбиения, а хеш всего
+/// файла вычисляется попутно. Файл из одного фрагмента (или пустой) сохраняется
+/// целым объектом, потому что хеш единственного фрагмента и есть хеш файла,
+/// поэтому первый фрагмент пишется только после появления второго. Хеш файла
+/// известен только после чтения, поэтому фрагменты записываются, даже если это
+/// содержимое уже хранится целым объектом; тогда список фрагментов все равно
+/// пишется, чтобы на новые фрагменты ссылался хеш файла, а их объем учитывается
+/// как записанный
+pub fn store_chunked(
+    source_path: &Path,
+    repository: &Repository,
+    chunking: &ChunkingConfig,
+    buffer_size: usize,
+) -> Result<StoredObject> {
+    let source = File::open(source_path)
+        .with_context(|| format!("Failed to open file: {}", source_path.display()))?;
+
+    let mut first = None;
+    let mut chunks = Vec::new();
+    let (hash_hex, size) = split(source, repository.algorithm(), chunking, |data| {
+        if chunks.is_empty() && first.is_none() {
+            first = Some(data.to_vec());
+            return Ok(());
+        }
+        if let Some(first) = first.take() {
+            chunks.push(store_chunk(repository, &first, buffer_size)?);
+        }
+        chunks.push(store_chunk(repository, data, buffer_size)?);
+        Ok(())
+    })
+    .with_context(|| format!("Failed to read file: {}", source_path.display()))?;
+
+    let existing = StoredObject {
+        hash: hash_hex.clone(),
+        is_new: false,
+        size,
+        new_bytes: 0,
+    };
+
+    if chunks.is_empty() {
+        if repository.has_object(&hash_hex) {
+            return Ok(existing);
+        }
+        let data = first.unwrap_or_default();
+        let is_new = directory::write_object(repository, &hash_hex, &data, buffer_size)?;
+        return Ok(StoredObject {
+            is_new,
+            new_bytes: if is_new { size } else { 0 },
+            ..existing
+        });
+    }
+
+    let new_bytes = chunks.iter().map(|chunk| chunk.new_bytes).sum();
+    let is_new = write_chunk_list(repository, &hash_hex, &chunks)?;
+
+    Ok(StoredObject {
+        is_new: is_new || new_bytes > 0,
+        new_bytes,
+        ..existing
+    })
+}
+
+/// Сохраняет фрагмент обычным объектом
+fn store_chunk(repository: &Repository, data: &[u8], buffer_size: usize) -> Result<StoredObject> {
+    let hash_hex = hash_bytes(data, repository.algorithm());
+    let is_new = directory::write_object(repository, &hash_hex, data, buffer_size)?;
+
+    Ok(StoredObject {
+        hash: hash_hex,
+        is_new,
+        size: data.len() as u64,
+        new_bytes: if is_new { data.len() as u64 } else { 0 },
+    })
+}
+
+/// Записывает список фрагментов под хешем всего содержимого `hash_hex`. Перед
+/// переименованием записанный список перечитывается и сверяется с `chunks`;
+/// сами фрагменты уже сверены с хешами при записи и повторно не читаются
+fn write_chunk_list(
+    repository: &Repository,
+    hash_hex: &str,
+    chunks: &[StoredObject],
+) -> Result<bool> {
+    let list: String = chunks
+        .iter()
+        .map(|chunk| chunk.hash.clone() + "\n")
+        .collect();
+
+    directory::write_verified(
+        &repository.chunk_lists_dir(),
+        hash_hex,
+        list.as_bytes(),
+        |path| verify_chunk_list(repository, path, chunks),
+    )
+}
+
+/// Проверяет, что список фрагментов в `path` читается как хеши `chunks` по порядку
+fn verify_chunk_list(repository: &Repository, path: &Path, chunks: &[StoredObject]) -> Result<()> {
+    let written = repository.read_chunk_list_file(path)?;
+    if !written.iter().eq(chunks.iter().map(|chunk| &chunk.hash)) {
+        bail!(
+            "Chunk list mismatch: expected {} chunks, read back {}",
+            chunks.len(),
+            written.len()
+        );
+    }
+    Ok(())
+}
+
+/// Вычисляет хеш файла и хеши с размерами его фрагментов, ничего не записывая
+pub fn plan_chunks(
+    source_path: &Path,
+    algorithm: HashAlgorithm,
+    chunking: &ChunkingConfig,
+) -> Result<(String, Vec<(String, u64)>)> {
+    let source = File::open(source_path)
+        .with_context(|| format!("Failed to open file: {}", source_path.display()))?;
+
+    let mut chunks = Vec::new();
+    let (hash_hex, _) = split(source, algorithm, chunking, |data| {
+        chunks.push((hash_bytes(data, algorithm), data.len() as u64));
+        Ok(())
+    })
+    .with_context(|| format!("Failed to read file: {}", source_path.display()))?;
+
+    Ok((hash_hex, chunks))
+}
+
+/// Записывает в `writer` содержимое файла из фрагментов по порядку.
+/// Возвращает хеш записанных данных и их размер
+pub fn write_chunks<W: Write>(
+    repository: &Repository,
+    chunks: &[String],
+    writer: &mut W,
+) -> Result<(String, u64)> {
+    let mut hasher = repository.algorithm().hasher();
+    let mut size = 0;
+
+    for chunk_hash in chunks {
+        let path = repository.object_path(chunk_hash);
+        let data =
+            fs::read(&path).with_context(|| format!("Failed to read chunk: {}", path.display()))?;
+        writer
+            .write_all(&data)
+            .context("Failed to write chunk data")?;
+        hasher.update(&data);
+        size += data.len() as u64;
+    }
+
+    Ok((hasher.finalize_hex(), size))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash::DEFAULT_BUFFER_SIZE;
+    use crate::repository::FileObject;
+    use tempfile::TempDir;
+
+    /// Маленькие фрагменты, чтобы тестовые файлы делились на части
+    const SMALL_CHUNKS: ChunkingConfig = ChunkingConfig {
+        enabled: true,
+        min_size: 64,
+        avg_size: 256,
+        max_size: 1024,
+    };
+
+    /// Псевдослучайные данные без повторов, на которых FastCDC находит границы
+    fn random_bytes(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state = state
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                (state >> 33) as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_store_chunked_roundtrip() {
+        let temp_dir = TempDir::new().unwrap();
+        let repository = Repository::open_or_init(temp_dir.path().to_str().unwrap(), None).unwrap();
+        let source = temp_dir.path().join("large.bin");
+        let content = random_bytes(16 * 1024, 1);
+        fs::write(&source, &content).unwrap();
+
+        let stored =
+            store_chunked(&source, &repository, &SMALL_CHUNKS, DEFAULT_BUFFER_SIZE).unwrap();
+
+        assert!(stored.is_new);
+        assert_eq!(stored.size, content.len() as u64);
+        assert_eq!(stored.new_bytes, content.len() as u64);
+        assert_eq!(stored.hash, hash_bytes(&content, HashAlgorithm::Md5));
+        assert!(!repository.object_path(&stored.hash).exists());
+
+        let Some(FileObject::Chunked(chunks)) = repository.find_object(&stored.hash).unwrap()
+        else {
+            panic!("expected chunked object");
+        };
+        assert!(chunks.len() > 1);
+        let mut restored = Vec::new();
+        let (hash_hex, size) = write_chunks(&repository, &chunks, &mut restored).unwrap();
+        assert_eq!(restored, content);
+        assert_eq!((hash_hex, size), (stored.hash, content.len() as u64));
+    }
+
+    #[test]
+    fn test_appended_file_reuses_chunks() {
+        let temp_dir = TempDir::new().unwrap();
+        let repository = Repository::open_or_init(temp_dir.path().to_str().unwrap(), None).unwrap();
+        let source = temp_dir.path().join("log.bin");
+        let mut content = random_bytes(32 * 1024, 2);
+        fs::write(&source, &content).unwrap();
+        store_chunked(&source, &repository, &SMALL_CHUNKS, DEFAULT_BUFFER_SIZE).unwrap();
+
+        content.extend(random_bytes(100, 3));
+        fs::write(&source, &content).unwrap();
+        let stored =
+            store_chunked(&source, &repository, &SMALL_CHUNKS, DEFAULT_BUFFER_SIZE).unwrap();
+
+        // Новым оказывается только хвост файла
+        assert!(stored.is_new);
+        assert!(stored.new_bytes > 0);
+        assert!(stored.new_bytes <= 100 + u64::from(SMALL_CHUNKS.max_size));
+        assert_eq!(repository.list_chunk_lists().unwrap().len(), 2);
+
+        let again =
+            store_chunked(&source, &repository, &SMALL_CHUNKS, DEFAULT_BUFFER_SIZE).unwrap();
+        assert!(!again.is_new);
+        assert_eq!(again.new_bytes, 0);
+    }
+
+    #[test]
+    fn test_small_file_is_stored_whole() {
+        let temp_dir = TempDir::new().unwrap();
+        let repository = Repository::open_or_init(temp_dir.path().to_str().unwrap(), None).unwrap();
+        let source = temp_dir.path().join("small.txt");
+        fs::write(&source, "test").unwrap();
+        let empty = temp_dir.path().join("empty.txt");
+        fs::write(&empty, "").unwrap();
+
+        let stored =
+            store_chunked(&source, &repository, &SMALL_CHUNKS, DEFAULT_BUFFER_SIZE).unwrap();
+        assert_eq!(stored.hash, "098f6bcd4621d373cade4e832627b4f6");
+        assert!(stored.is_new);
+        assert_eq!(stored.new_bytes, 4);
+        assert_eq!(
+            repository.find_object(&stored.hash).unwrap(),
+            Some(FileObject::Whole(repository.object_path(&stored.hash)))
+        );
+
+        let stored =
+            store_chunked(&empty, &repository, &SMALL_CHUNKS, DEFAULT_BUFFER_SIZE).unwrap();
+        assert_eq!(stored.hash, "d41d8cd98f00b204e9800998ecf8427e");
+        assert!(repository.object_path(&stored.hash).is_file());
+        assert!(repository.list_chunk_lists().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_existing_whole_object_references_new_chunks() {
+        let temp_dir = TempDir::new().unwrap();
+        let repository = Repository::open_or_init(temp_dir.path().to_str().unwrap(), None).unwrap();
+        let source = temp_dir.path().join("large.bin");
+        let content = random_bytes(16 * 1024, 4);
+        fs::write(&source, &content).unwrap();
+        let hash_hex = hash_bytes(&content, HashAlgorithm::Md5);
+        directory::write_object(&repository, &hash_hex, &content, DEFAULT_BUFFER_SIZE).unwrap();
+
+        let stored =
+            store_chunked(&source, &repository, &SMALL_CHUNKS, DEFAULT_BUFFER_SIZE).unwrap();
+
+        // Фрагменты записаны до того, как стал известен хеш файла: они учтены
+        // как новые и перечислены в списке фрагментов, а не брошены
+        assert_eq!(stored.hash, hash_hex);
+        assert!(stored.is_new);
+        assert_eq!(stored.size, content.len() as u64);
+        assert_eq!(stored.new_bytes, content.len() as u64);
+        let chunks = repository.read_chunk_list(&hash_hex).unwrap();
+        assert!(chunks.len() > 1);
+        assert!(chunks
+            .iter()
+            .all(|chunk| repository.object_path(chunk).is_file()));
+        // Целый объект по-прежнему предпочтительнее
+        assert_eq!(
+            repository.find_object(&hash_hex).unwrap(),
+            Some(FileObject::Whole(repository.object_path(&hash_hex)))
+        );
+
+        let again =
+            store_chunked(&source, &repository, &SMALL_CHUNKS, DEFAULT_BUFFER_SIZE).unwrap();
+        assert!(!again.is_new);
+        assert_eq!(again.new_bytes, 0);
+    }
+
+    #[test]
+    fn test_existing_single_chunk_is_not_rewritten() {
+        let temp_dir = TempDir::new().unwrap();
+        let repository = Repository::open_or_init(temp_dir.path().to_str().unwrap(), None).unwrap();
+        let source = temp_dir.path().join("small.txt");
+        fs::write(&source, "test").unwrap();
+        let md5_hex = "098f6bcd4621d373cade4e832627b4f6";
+
+        // То же содержимое уже сохранено списком фрагментов
+        let chunk_list = repository.chunk_list_path(md5_hex);
+        fs::create_dir_all(chunk_list.parent().unwrap()).unwrap();
+        fs::write(&chunk_list, "").unwrap();
+
+        let stored =
+            store_chunked(&source, &repository, &SMALL_CHUNKS, DEFAULT_BUFFER_SIZE).unwrap();
+        assert!(!stored.is_new);
+        assert!(repository.list_objects().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_chunk_list_is_verified() {
+        let temp_dir = TempDir::new().unwrap();
+        let repository = Repository::open_or_init(temp_dir.path().to_str().unwrap(), None).unwrap();
+        let chunks: Vec<StoredObject> = [b"te".as_slice(), b"st"]
+            .into_iter()
+            .map(|data| {
+                let hash = hash_bytes(data, HashAlgorithm::Md5);
+                directory::write_object(&repository, &hash, data, DEFAULT_BUFFER_SIZE).unwrap();
+                StoredObject {
+                    hash,
+                    is_new: true,
+                    size: 2,
+                    new_bytes: 2,
+                }
+            })
+            .collect();
+
+        // Перечитанный список с другим порядком или составом не проходит сверку
+        let written = temp_dir.path().join("written");
+        for list in [
+            format!("{}\n{}\n", chunks[1].hash, chunks[0].hash),
+            format!("{}\n", chunks[0].hash),
+        ] {
+            fs::write(&written, list).unwrap();
+            let error = verify_chunk_list(&repository, &written, &chunks).unwrap_err();
+            assert!(error.to_string().contains("Chunk list mismatch"));
+        }
+
+        let md5_hex = "098f6bcd4621d373cade4e832627b4f6";
+        assert!(write_chunk_list(&repository, md5_hex, &chunks).unwrap());
+        assert_eq!(
+            repository.find_object(md5_hex).unwrap(),
+            Some(FileObject::Chunked(
+                chunks.into_iter().map(|chunk| chunk.hash).collect()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_plan_chunks_matches_store() {
+        let temp_dir = TempDir::new().unwrap();
+        let repository = Repository::open_or_init(temp_dir.path().to_str().unwrap(), None).unwrap();
+        let source = temp_dir.path().join("large.bin");
+        fs::write(&source, random_bytes(8 * 1024, 4)).unwrap();
+
+        let (hash_hex, planned) = plan_chunks(&source, HashAlgorithm::Md5, &SMALL_CHUNKS).unwrap();
+        let stored =
+            store_chunked(&source, &repository, &SMALL_CHUNKS, DEFAULT_BUFFER_SIZE).unwrap();
+
+        assert_eq!(hash_hex, stored.hash);
+        let planned: Vec<String> = planned.into_iter().map(|(hash, _)| hash).collect();
+        assert_eq!(repository.read_chunk_list(&hash_hex).unwrap(), planned);
+    }
+
+    #[test]
+    fn test_invalid_sizes() {
+        assert!(ChunkingConfig::default().validate().is_ok());
+        assert!(SMALL_CHUNKS.validate().is_ok());
+
+        let too_small = ChunkingConfig {
+            min_size: 16,
+            ..SMALL_CHUNKS
+        };
+        assert!(too_small
+            .validate()
+            .unwrap_err()
+            .to_string()
+            .contains("chunking.min_size"));
+
+        let unordered = ChunkingConfig {
+            min_size: 4096,
+            ..SMALL_CHUNKS
+        };
+        assert!(unordered.validate().is_err());
+    }
+}
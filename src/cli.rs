@@ -0,0 +1,483 @@
+use crate::prune::parse_duration;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use std::ffi::OsString;
+use std::num::NonZeroUsize;
+
+/// Файл конфигурации по умолчанию
+pub const DEFAULT_CONFIG: &str = "config.toml";
+
+#[derive(Debug, Parser)]
+#[command(
+    version,
+    about = "Deduplicating backup of files with selected extensions"
+)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Command,
+    #[arg(
+        long,
+        global = true,
+        value_enum,
+        default_value_t = Format::Text,
+        help = "Output format"
+    )]
+    pub format: Format,
+}
+
+/// Формат вывода результатов команд
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Format {
+    Text,
+    /// Один JSON-документ, а для списков - JSON Lines
+    Json,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    #[command(about = "Back up a source directory into a new snapshot")]
+    Backup(BackupArgs),
+    #[command(about = "Restore a snapshot into a directory")]
+    Restore(RestoreArgs),
+    #[command(about = "List snapshots or the files of one snapshot")]
+    List(ListArgs),
+    #[command(about = "Re-hash stored objects and check snapshot references")]
+    Verify(VerifyArgs),
+    #[command(about = "Remove objects no snapshot references")]
+    Gc(GcArgs),
+    #[command(about = "Remove snapshots according to retention rules")]
+    Prune(PruneArgs),
+    #[command(about = "Show files added, removed or changed between two snapshots")]
+    Diff(DiffArgs),
+    #[command(about = "Show repository size and deduplication statistics")]
+    Stats(StatsArgs),
+    #[command(about = "Work with the config file")]
+    Config(ConfigArgs),
+}
+
+#[derive(Debug, Args)]
+pub struct BackupArgs {
+    #[arg(
+        required_unless_present = "profile",
+        help = "Directory to back up; with [[sources]] in the config, the base of relative source paths"
+    )]
+    pub source_dir: Option<String>,
+    #[arg(
+        required_unless_present = "profile",
+        help = "Backup repository [default with --profile: target from the config]"
+    )]
+    pub target_base: Option<String>,
+    #[arg(help = "Config file [default: config.toml]")]
+    pub config: Option<String>,
+    #[arg(
+        long = "config",
+        id = "config_file",
+        value_name = "FILE",
+        conflicts_with = "config",
+        help = "Config file, for use without positional arguments"
+    )]
+    pub config_file: Option<String>,
+    #[arg(
+        long,
+        value_name = "NAME",
+        help = "Use the [profile.NAME] section of the config"
+    )]
+    pub profile: Option<String>,
+    #[arg(
+        long,
+        short,
+        value_name = "N",
+        help = "Number of hashing threads [default: number of CPUs]"
+    )]
+    pub jobs: Option<NonZeroUsize>,
+    #[arg(long, help = "Ignore the hash cache and re-hash every file")]
+    pub rehash: bool,
+    #[arg(long, help = "Delete the hash cache before the run")]
+    pub clear_cache: bool,
+    #[arg(
+        long,
+        conflicts_with = "clear_cache",
+        help = "Only show what would be copied; create no snapshot or objects"
+    )]
+    pub dry_run: bool,
+    #[arg(long, help = "Abort on the first file that cannot be backed up")]
+    pub fail_fast: bool,
+}
+
+impl BackupArgs {
+    /// Файл конфигурации из позиционного аргумента или `--config`
+    pub fn config_path(&self) -> &str {
+        self.config
+            .as_deref()
+            .or(self.config_file.as_deref())
+            .unwrap_or(DEFAULT_CONFIG)
+    }
+}
+
+#[derive(Debug, Args)]
+pub struct RestoreArgs {
+    #[arg(help = "Backup repository")]
+    pub target_base: String,
+    #[arg(help = "Snapshot name, e.g. 20230601_123045")]
+    pub snapshot: String,
+    #[arg(help = "Destination directory")]
+    pub dest: String,
+    #[arg(long, help = "Overwrite existing files in the destination")]
+    pub overwrite: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct ListArgs {
+    #[arg(help = "Backup repository")]
+    pub target_base: String,
+    #[arg(help = "List the files of this snapshot")]
+    pub snapshot: Option<String>,
+}
+
+#[derive(Debug, Args)]
+pub struct VerifyArgs {
+    #[arg(help = "Backup repository")]
+    pub target_base: String,
+    #[arg(
+        long,
+        value_name = "PERCENT",
+        value_parser = parse_percent,
+        help = "Re-hash only a random sample of objects"
+    )]
+    pub sample: Option<f64>,
+}
+
+#[derive(Debug, Args)]
+pub struct GcArgs {
+    #[arg(help = "Backup repository")]
+    pub target_base: String,
+    #[arg(long, help = "Only show what would be removed")]
+    pub dry_run: bool,
+    #[arg(long, help = "Move objects to target/trash instead of deleting them")]
+    pub trash: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct PruneArgs {
+    #[arg(
+        required_unless_present = "profile",
+        help = "Backup repository [default with --profile: target from the config]"
+    )]
+    pub target_base: Option<String>,
+    #[arg(
+        long,
+        value_name = "N",
+        value_parser = parse_count,
+        help = "Keep the last N snapshots"
+    )]
+    pub keep_last: Option<usize>,
+    #[arg(
+        long,
+        value_name = "N",
+        value_parser = parse_count,
+        help = "Keep the newest snapshot of each of N days"
+    )]
+    pub keep_daily: Option<usize>,
+    #[arg(
+        long,
+        value_name = "N",
+        value_parser = parse_count,
+        help = "Keep the newest snapshot of each of N weeks"
+    )]
+    pub keep_weekly: Option<usize>,
+    #[arg(
+        long,
+        value_name = "N",
+        value_parser = parse_count,
+        help = "Keep the newest snapshot of each of N months"
+    )]
+    pub keep_monthly: Option<usize>,
+    #[arg(
+        long,
+        value_name = "DURATION",
+        value_parser = parse_duration,
+        help = "Keep all snapshots younger than DURATION (12h, 30d, 4w, 6m, 1y)"
+    )]
+    pub keep_within: Option<chrono::Duration>,
+    #[arg(long, help = "Only show what would be removed")]
+    pub dry_run: bool,
+    #[arg(long, help = "Run gc after removing snapshots")]
+    pub gc: bool,
+    #[arg(long, requires = "gc", help = "Let gc move objects to target/trash")]
+    pub trash: bool,
+    #[arg(
+        long,
+        value_name = "NAME",
+        help = "Take the target and retention rules from [profile.NAME]; options override them"
+    )]
+    pub profile: Option<String>,
+    #[arg(
+        long,
+        value_name = "FILE",
+        default_value = DEFAULT_CONFIG,
+        requires = "profile",
+        help = "Config file with the profile"
+    )]
+    pub config: String,
+}
+
+#[derive(Debug, Args)]
+pub struct DiffArgs {
+    #[arg(help = "Backup repository")]
+    pub target_base: String,
+    #[arg(help = "Older snapshot")]
+    pub from: String,
+    #[arg(help = "Newer snapshot")]
+    pub to: String,
+}
+
+#[derive(Debug, Args)]
+pub struct StatsArgs {
+    #[arg(help = "Backup repository")]
+    pub target_base: String,
+}
+
+#[derive(Debug, Args)]
+pub struct ConfigArgs {
+    #[command(subcommand)]
+    pub command: ConfigCommand,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ConfigCommand {
+    #[command(about = "Report unknown fields, suspicious values and errors with their location")]
+    Check(ConfigCheckArgs),
+}
+
+#[derive(Debug, Args)]
+pub struct ConfigCheckArgs {
+    #[arg(default_value = DEFAULT_CONFIG, help = "Config file")]
+    pub config: String,
+}
+
+/// Разбирает аргументы командной строки процесса
+pub fn parse() -> Cli {
+    Cli::parse_from(normalize_args(std::env::args_os().collect()))
+}
+
+/// Старая форма `<source_dir> <target_base> <config_file>` без подкоманды
+/// (используется в cron-заданиях) считается подкомандой backup. Глобальные опции
+/// перед ней пропускаются; исходная директория с именем подкоманды запускает подкоманду
+pub fn normalize_args(mut args: Vec<OsString>) -> Vec<OsString> {
+    let mut position = 1;
+    while let Some(arg) = args.get(position).and_then(|arg| arg.to_str()) {
+        if !arg.starts_with('-') || arg == "--" {
+            break;
+        }
+        position += if takes_value(arg) { 2 } else { 1 };
+    }
+
+    let is_legacy = args
+        .get(position)
+        .and_then(|arg| arg.to_str())
+        .is_some_and(|arg| !arg.starts_with('-') && !is_subcommand(arg));
+
+    if is_legacy {
+        args.insert(position, OsString::from("backup"));
+    }
+    args
+}
+
+/// Глобальная опция вида `--name` без `=`, значение которой - следующий аргумент
+fn takes_value(flag: &str) -> bool {
+    use clap::CommandFactory;
+    let Some(name) = flag.strip_prefix("--") else {
+        return false;
+    };
+    Cli::command()
+        .get_arguments()
+        .any(|arg| arg.get_long() == Some(name) && arg.get_action().takes_values())
+}
+
+fn is_subcommand(name: &str) -> bool {
+    use clap::CommandFactory;
+    name == "help"
+        || Cli::command()
+            .get_subcommands()
+            .any(|command| command.get_name() == name)
+}
+
+fn parse_percent(value: &str) -> Result<f64, String> {
+    let percent: f64 = value
+        .trim_end_matches('%')
+        .parse()
+        .map_err(|_| format!("invalid percent: {}", value))?;
+    if !(percent > 0.0 && percent <= 100.0) {
+        return Err(format!("percent must be in (0, 100], got {}", value));
+    }
+    Ok(percent)
+}
+
+/// Число снимков для правила хранения: 0 удалил бы все снимки
+fn parse_count(value: &str) -> Result<usize, String> {
+    match value.parse() {
+        Ok(0) => Err("must be at least 1".to_string()),
+        Ok(count) => Ok(count),
+        Err(_) => Err(format!("invalid number: {}", value)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_from(args: &[&str]) -> Result<Cli, clap::Error> {
+        Cli::try_parse_from(normalize_args(args.iter().map(OsString::from).collect()))
+    }
+
+    #[test]
+    fn test_legacy_backup_form() {
+        let cli = parse_from(&["backup_md5", "./src", "./target", "my.toml"]).unwrap();
+
+        let Command::Backup(args) = cli.command else {
+            panic!("expected backup");
+        };
+        assert_eq!(args.source_dir.as_deref(), Some("./src"));
+        assert_eq!(args.target_base.as_deref(), Some("./target"));
+        assert_eq!(args.config_path(), "my.toml");
+    }
+
+    #[test]
+    fn test_legacy_backup_form_after_global_options() {
+        for prefix in [&["--format", "json"][..], &["--format=json"][..]] {
+            let mut args = vec!["backup_md5"];
+            args.extend_from_slice(prefix);
+            args.extend_from_slice(&["./src", "./target", "my.toml"]);
+            let cli = parse_from(&args).unwrap();
+
+            assert_eq!(cli.format, Format::Json);
+            let Command::Backup(args) = cli.command else {
+                panic!("expected backup");
+            };
+            assert_eq!(args.source_dir.as_deref(), Some("./src"));
+            assert_eq!(args.config_path(), "my.toml");
+        }
+
+        // Имя подкоманды и после глобальных опций остается подкомандой
+        let cli = parse_from(&["backup_md5", "--format", "json", "stats", "./target"]).unwrap();
+        assert!(matches!(cli.command, Command::Stats(_)));
+    }
+
+    #[test]
+    fn test_backup_default_config_and_options() {
+        let cli =
+            parse_from(&["backup_md5", "backup", "./src", "./target", "--jobs", "4"]).unwrap();
+
+        let Command::Backup(args) = cli.command else {
+            panic!("expected backup");
+        };
+        assert_eq!(args.config_path(), DEFAULT_CONFIG);
+        assert_eq!(args.jobs, NonZeroUsize::new(4));
+        assert!(parse_from(&["backup_md5", "backup", "./src", "./target", "--jobs", "0"]).is_err());
+        assert!(parse_from(&[
+            "backup_md5",
+            "backup",
+            "./src",
+            "./target",
+            "--dry-run",
+            "--clear-cache"
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn test_profile_option() {
+        let cli = parse_from(&[
+            "backup_md5",
+            "backup",
+            "--profile",
+            "photos",
+            "--config",
+            "my.toml",
+        ])
+        .unwrap();
+        let Command::Backup(args) = cli.command else {
+            panic!("expected backup");
+        };
+        assert_eq!(args.profile.as_deref(), Some("photos"));
+        assert_eq!(args.source_dir, None);
+        assert_eq!(args.config_path(), "my.toml");
+
+        // Без профиля исходная директория и хранилище обязательны
+        assert!(parse_from(&["backup_md5", "backup", "./src"]).is_err());
+        assert!(parse_from(&[
+            "backup_md5",
+            "backup",
+            "./src",
+            "./t",
+            "a.toml",
+            "--config",
+            "b.toml"
+        ])
+        .is_err());
+
+        let cli = parse_from(&["backup_md5", "prune", "--profile", "photos"]).unwrap();
+        let Command::Prune(args) = cli.command else {
+            panic!("expected prune");
+        };
+        assert_eq!(args.target_base, None);
+        assert_eq!(args.config, DEFAULT_CONFIG);
+        assert!(parse_from(&["backup_md5", "prune", "./target", "--config", "my.toml"]).is_err());
+    }
+
+    #[test]
+    fn test_subcommands() {
+        let cli = parse_from(&["backup_md5", "prune", "./target", "--keep-within", "7d"]).unwrap();
+        let Command::Prune(args) = cli.command else {
+            panic!("expected prune");
+        };
+        assert_eq!(args.keep_within, Some(chrono::Duration::days(7)));
+        assert!(parse_from(&["backup_md5", "prune", "./target", "--keep-last", "0"]).is_err());
+        assert!(parse_from(&["backup_md5", "prune", "./target", "--keep-daily", "0"]).is_err());
+        assert!(parse_from(&["backup_md5", "prune", "./target", "--keep-within=-5d"]).is_err());
+
+        let cli = parse_from(&["backup_md5", "verify", "./target", "--sample", "10%"]).unwrap();
+        let Command::Verify(args) = cli.command else {
+            panic!("expected verify");
+        };
+        assert_eq!(args.sample, Some(10.0));
+
+        assert!(parse_from(&["backup_md5", "verify", "./target", "--sample", "0"]).is_err());
+        assert!(parse_from(&["backup_md5", "prune", "./target", "--trash"]).is_err());
+        assert!(parse_from(&["backup_md5", "diff", "./target", "a"]).is_err());
+
+        let cli = parse_from(&["backup_md5", "config", "check"]).unwrap();
+        let Command::Config(ConfigArgs {
+            command: ConfigCommand::Check(args),
+        }) = cli.command
+        else {
+            panic!("expected config check");
+        };
+        assert_eq!(args.config, DEFAULT_CONFIG);
+    }
+
+    #[test]
+    fn test_format_option() {
+        let cli = parse_from(&["backup_md5", "list", "./target"]).unwrap();
+        assert_eq!(cli.format, Format::Text);
+
+        let cli = parse_from(&[
+            "backup_md5",
+            "./src",
+            "./target",
+            "my.toml",
+            "--format",
+            "json",
+        ])
+        .unwrap();
+        assert_eq!(cli.format, Format::Json);
+        assert!(matches!(cli.command, Command::Backup(_)));
+
+        assert!(parse_from(&["backup_md5", "list", "./target", "--format", "xml"]).is_err());
+    }
+
+    #[test]
+    fn test_help_is_not_legacy_form() {
+        let error = parse_from(&["backup_md5", "help"]).unwrap_err();
+        assert_eq!(error.kind(), clap::error::ErrorKind::DisplayHelp);
+    }
+}
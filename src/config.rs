@@ -1,24 +1,285 @@
-use anyhow::{Context, Result};
-use serde::Deserialize;
+use crate::chunking::ChunkingConfig;
+use crate::hash::{HashAlgorithm, DEFAULT_BUFFER_SIZE};
+use crate::prune::RetentionPolicy;
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
 use std::collections::HashSet;
+use std::fmt;
 use std::fs;
+use std::path::{Component, Path, PathBuf};
+use toml::{Table, Value};
 
 #[derive(Debug, Deserialize)]
 pub struct Config {
     #[serde(deserialize_with = "deserialize_lowercase_hashset")]
     pub extensions: HashSet<String>,
+    /// Размер буфера чтения при хешировании, в байтах
+    #[serde(default = "default_buffer_size")]
+    pub buffer_size: usize,
+    /// Алгоритм хеширования для нового хранилища; существующее хранилище
+    /// продолжает использовать алгоритм из своих метаданных
+    #[serde(default)]
+    pub hash_algorithm: Option<HashAlgorithm>,
+    /// Что делать с символическими ссылками в исходной директории
+    #[serde(default)]
+    pub symlinks: SymlinkPolicy,
+    /// Сколько раз повторять копирование файла, изменившегося во время чтения
+    #[serde(default = "default_retries")]
+    pub retries: u32,
+    /// Шаблоны .gitignore для файлов, сохраняемых независимо от расширения
+    #[serde(default)]
+    pub include: Vec<String>,
+    /// Шаблоны .gitignore для исключаемых файлов и директорий
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    /// Что делать с файлами и директориями, имя которых начинается с точки
+    #[serde(default)]
+    pub hidden: HiddenPolicy,
+    /// Имена (например, `.config`), которые сохраняются и при `hidden = "skip"`
+    #[serde(default)]
+    pub hidden_allow: Vec<String>,
+    /// Именованные исходные директории одного снимка; если список пуст,
+    /// сохраняется только исходная директория из командной строки
+    #[serde(default)]
+    pub sources: Vec<Source>,
+    /// Хранилище, если оно не указано в командной строке
+    #[serde(default)]
+    pub target: Option<String>,
+    /// Правила хранения для `prune --profile`
+    #[serde(default)]
+    pub retention: RetentionPolicy,
+    /// Разбиение файлов на фрагменты по содержимому для дедупликации внутри файлов
+    #[serde(default)]
+    pub chunking: ChunkingConfig,
 }
 
+/// Исходная директория, файлы которой попадают в поддерево снимка `name`
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct Source {
+    pub name: String,
+    /// Относительный путь отсчитывается от исходной директории из командной строки,
+    /// а если она не указана - от текущей директории
+    pub path: PathBuf,
+}
+
+/// Политика обработки скрытых записей. Проверяются только компоненты пути
+/// относительно исходной директории, сама она может быть скрытой
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HiddenPolicy {
+    /// Пропускать скрытые записи, кроме перечисленных в `hidden_allow`;
+    /// содержимое скрытых директорий не обходится
+    #[default]
+    Skip,
+    /// Обрабатывать скрытые записи как обычные
+    Include,
+}
+
+/// Политика обработки символических ссылок при обходе
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SymlinkPolicy {
+    /// Пропускать ссылки с сообщением в журнале
+    #[default]
+    Skip,
+    /// Сохранять ссылку в снимке как ссылку (цель записывается в манифест)
+    Record,
+    /// Переходить по ссылкам, циклы обнаруживаются и пропускаются
+    Follow,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            extensions: HashSet::new(),
+            buffer_size: default_buffer_size(),
+            hash_algorithm: None,
+            symlinks: SymlinkPolicy::default(),
+            retries: default_retries(),
+            include: Vec::new(),
+            exclude: Vec::new(),
+            hidden: HiddenPolicy::default(),
+            hidden_allow: Vec::new(),
+            sources: Vec::new(),
+            target: None,
+            retention: RetentionPolicy::default(),
+            chunking: ChunkingConfig::default(),
+        }
+    }
+}
+
+/// Таблица профилей `[profile.<name>]`; без профиля она не используется
+pub const PROFILES_KEY: &str = "profile";
+
+/// Шаг пути к значению в файле конфигурации: поле таблицы или элемент массива
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeyPart {
+    Field(String),
+    Index(usize),
+}
+
+/// Недопустимое значение, которое разбирается, но не проходит проверку;
+/// `path` ведет к нему от корня настроек (или профиля), чтобы `config check`
+/// мог указать его положение в файле
+#[derive(Debug)]
+pub struct InvalidValue {
+    pub path: Vec<KeyPart>,
+    pub message: String,
+}
+
+impl InvalidValue {
+    pub fn new(path: Vec<KeyPart>, message: impl Into<String>) -> Self {
+        Self {
+            path,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for InvalidValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for InvalidValue {}
+
 impl Config {
+    /// Настройки профиля `profile` или, без него, общие настройки
+    pub fn load(path: &str, profile: Option<&str>) -> Result<Self> {
+        match profile {
+            Some(name) => Self::from_profile(path, name),
+            None => Self::from_file(path),
+        }
+    }
+
+    /// Читает общие настройки, секции профилей пропускаются
     pub fn from_file(path: &str) -> Result<Self> {
-        let content = fs::read_to_string(path)
-            .with_context(|| format!("Failed to read config file: {}", path))?;
+        Self::parse(&read_config(path)?, path)
+    }
 
-        let config: Self = toml::from_str(&content)
+    /// Разбирает и проверяет настройки из текста TOML; `path` нужен для сообщений
+    pub fn parse(content: &str, path: &str) -> Result<Self> {
+        let config: Self = toml::from_str(content)
             .with_context(|| format!("Failed to parse config file: {}", path))?;
 
-        Ok(config)
+        config.validated(path)
+    }
+
+    /// Читает профиль `[profile.<name>]`: значения профиля заменяют общие
+    /// настройки, вложенные таблицы (например, `retention`) объединяются по ключам
+    pub fn from_profile(path: &str, name: &str) -> Result<Self> {
+        let table = Self::profile_table(path, name)?;
+        let config: Self = table.try_into().with_context(|| {
+            format!("Failed to parse profile {} in config file: {}", name, path)
+        })?;
+
+        config.validated(path)
     }
+
+    /// Общие настройки с наложенным профилем `name`, еще не разобранные в `Config`
+    pub fn profile_table(path: &str, name: &str) -> Result<Table> {
+        let content = read_config(path)?;
+        let mut table: Table = toml::from_str(&content)
+            .with_context(|| format!("Failed to parse config file: {}", path))?;
+
+        let mut profiles = match table.remove(PROFILES_KEY) {
+            Some(Value::Table(profiles)) => profiles,
+            Some(_) => bail!("Invalid config file {}: [profile] must be a table", path),
+            None => Table::new(),
+        };
+        let profile = match profiles.remove(name) {
+            Some(Value::Table(profile)) => profile,
+            Some(_) => bail!(
+                "Invalid config file {}: profile {} must be a table",
+                path,
+                name
+            ),
+            None => bail!(
+                "Unknown profile {} in config file {} (available: {})",
+                name,
+                path,
+                profiles.keys().cloned().collect::<Vec<_>>().join(", ")
+            ),
+        };
+        merge_tables(&mut table, profile);
+
+        Ok(table)
+    }
+
+    /// Проверки значений, которые нельзя выразить типами
+    fn validated(self, path: &str) -> Result<Self> {
+        if self.buffer_size == 0 {
+            return Err(InvalidValue::new(
+                vec![KeyPart::Field("buffer_size".to_string())],
+                "buffer_size must be greater than zero",
+            ))
+            .with_context(|| format!("Invalid config file {}", path));
+        }
+        validate_sources(&self.sources).with_context(|| format!("Invalid config file {}", path))?;
+        self.chunking
+            .validate()
+            .with_context(|| format!("Invalid config file {}", path))?;
+        self.retention
+            .validate()
+            .with_context(|| format!("Invalid config file {}", path))?;
+
+        Ok(self)
+    }
+}
+
+fn read_config(path: &str) -> Result<String> {
+    fs::read_to_string(path).with_context(|| format!("Failed to read config file: {}", path))
+}
+
+/// Накладывает значения `overrides` на `base`: таблицы объединяются рекурсивно,
+/// остальные значения (в том числе массивы) заменяются целиком
+fn merge_tables(base: &mut Table, overrides: Table) {
+    for (key, value) in overrides {
+        match (base.get_mut(&key), value) {
+            (Some(Value::Table(existing)), Value::Table(value)) => merge_tables(existing, value),
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+/// Имя источника становится директорией верхнего уровня в снимке, поэтому
+/// должно быть одним компонентом пути и не повторяться
+fn validate_sources(sources: &[Source]) -> Result<()> {
+    let mut names = HashSet::new();
+    for (index, source) in sources.iter().enumerate() {
+        let path = vec![
+            KeyPart::Field("sources".to_string()),
+            KeyPart::Index(index),
+            KeyPart::Field("name".to_string()),
+        ];
+        let mut components = Path::new(&source.name).components();
+        if !matches!(
+            (components.next(), components.next()),
+            (Some(Component::Normal(_)), None)
+        ) || source.name.contains(['/', '\\'])
+        {
+            return Err(
+                InvalidValue::new(path, format!("invalid source name: {:?}", source.name)).into(),
+            );
+        }
+        if !names.insert(source.name.as_str()) {
+            return Err(
+                InvalidValue::new(path, format!("duplicate source name: {}", source.name)).into(),
+            );
+        }
+    }
+    Ok(())
+}
+
+fn default_buffer_size() -> usize {
+    DEFAULT_BUFFER_SIZE
+}
+
+fn default_retries() -> u32 {
+    2
 }
 
 fn deserialize_lowercase_hashset<'de, D>(deserializer: D) -> Result<HashSet<String>, D::Error>
@@ -26,10 +287,20 @@ where
     D: serde::Deserializer<'de>,
 {
     let original: HashSet<String> = HashSet::deserialize(deserializer)?;
-    let lowercase = original.into_iter().map(|s| s.to_lowercase()).collect();
+    let lowercase = original.iter().map(|s| normalize_extension(s)).collect();
     Ok(lowercase)
 }
 
+/// Расширение в том виде, в каком его сравнивает `has_extension`:
+/// без пробелов по краям и точки в начале, в нижнем регистре
+pub fn normalize_extension(extension: &str) -> String {
+    let extension = extension.trim();
+    extension
+        .strip_prefix('.')
+        .unwrap_or(extension)
+        .to_lowercase()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -53,6 +324,330 @@ mod tests {
         assert!(config.extensions.contains("txt"));
         assert!(config.extensions.contains("jpg"));
         assert!(config.extensions.contains("pdf"));
+        assert_eq!(config.buffer_size, DEFAULT_BUFFER_SIZE);
+        assert_eq!(config.hash_algorithm, None);
+        assert_eq!(config.symlinks, SymlinkPolicy::Skip);
+        assert_eq!(config.retries, 2);
+        assert!(config.include.is_empty());
+        assert!(config.exclude.is_empty());
+        assert_eq!(config.hidden, HiddenPolicy::Skip);
+        assert!(config.hidden_allow.is_empty());
+    }
+
+    #[test]
+    fn test_hash_algorithm() {
+        let mut config_file = NamedTempFile::new().unwrap();
+        write!(
+            config_file,
+            r#"
+            extensions = ["txt"]
+            hash_algorithm = "sha256"
+        "#
+        )
+        .unwrap();
+
+        let config = Config::from_file(config_file.path().to_str().unwrap()).unwrap();
+        assert_eq!(config.hash_algorithm, Some(HashAlgorithm::Sha256));
+    }
+
+    #[test]
+    fn test_symlink_policy() {
+        let mut config_file = NamedTempFile::new().unwrap();
+        write!(
+            config_file,
+            r#"
+            extensions = ["txt"]
+            symlinks = "follow"
+        "#
+        )
+        .unwrap();
+
+        let config = Config::from_file(config_file.path().to_str().unwrap()).unwrap();
+        assert_eq!(config.symlinks, SymlinkPolicy::Follow);
+    }
+
+    #[test]
+    fn test_retries() {
+        let mut config_file = NamedTempFile::new().unwrap();
+        write!(
+            config_file,
+            r#"
+            extensions = ["txt"]
+            retries = 5
+        "#
+        )
+        .unwrap();
+
+        let config = Config::from_file(config_file.path().to_str().unwrap()).unwrap();
+        assert_eq!(config.retries, 5);
+    }
+
+    #[test]
+    fn test_include_exclude() {
+        let mut config_file = NamedTempFile::new().unwrap();
+        write!(
+            config_file,
+            r#"
+            extensions = ["txt"]
+            include = ["Makefile"]
+            exclude = ["node_modules/", "*.tmp", "!keep.tmp"]
+        "#
+        )
+        .unwrap();
+
+        let config = Config::from_file(config_file.path().to_str().unwrap()).unwrap();
+        assert_eq!(config.include, vec!["Makefile"]);
+        assert_eq!(config.exclude, vec!["node_modules/", "*.tmp", "!keep.tmp"]);
+    }
+
+    #[test]
+    fn test_hidden_policy() {
+        let mut config_file = NamedTempFile::new().unwrap();
+        write!(
+            config_file,
+            r#"
+            extensions = ["txt"]
+            hidden = "include"
+            hidden_allow = [".config"]
+        "#
+        )
+        .unwrap();
+
+        let config = Config::from_file(config_file.path().to_str().unwrap()).unwrap();
+        assert_eq!(config.hidden, HiddenPolicy::Include);
+        assert_eq!(config.hidden_allow, vec![".config"]);
+    }
+
+    #[test]
+    fn test_sources() {
+        let mut config_file = NamedTempFile::new().unwrap();
+        write!(
+            config_file,
+            r#"
+            extensions = ["txt"]
+
+            [[sources]]
+            name = "home"
+            path = "/home"
+
+            [[sources]]
+            name = "photos"
+            path = "srv/photos"
+        "#
+        )
+        .unwrap();
+
+        let config = Config::from_file(config_file.path().to_str().unwrap()).unwrap();
+        assert_eq!(
+            config.sources,
+            vec![
+                Source {
+                    name: "home".to_string(),
+                    path: PathBuf::from("/home"),
+                },
+                Source {
+                    name: "photos".to_string(),
+                    path: PathBuf::from("srv/photos"),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_invalid_sources() {
+        let source = |name: &str| Source {
+            name: name.to_string(),
+            path: PathBuf::from("/srv"),
+        };
+
+        assert!(validate_sources(&[source("srv"), source("etc")]).is_ok());
+        for name in ["", ".", "..", "a/b", "/srv"] {
+            let error = validate_sources(&[source(name)]).unwrap_err();
+            assert!(
+                error.to_string().contains("invalid source name"),
+                "{}",
+                name
+            );
+        }
+        let error = validate_sources(&[source("srv"), source("srv")]).unwrap_err();
+        assert!(error.to_string().contains("duplicate source name: srv"));
+    }
+
+    #[test]
+    fn test_chunking() {
+        let mut config_file = NamedTempFile::new().unwrap();
+        write!(
+            config_file,
+            r#"
+            extensions = ["img"]
+
+            [chunking]
+            enabled = true
+            avg_size = 2097152
+        "#
+        )
+        .unwrap();
+
+        let config = Config::from_file(config_file.path().to_str().unwrap()).unwrap();
+        assert_eq!(
+            config.chunking,
+            ChunkingConfig {
+                enabled: true,
+                avg_size: 2097152,
+                ..Default::default()
+            }
+        );
+        assert!(!Config::default().chunking.enabled);
+
+        let mut config_file = NamedTempFile::new().unwrap();
+        write!(
+            config_file,
+            r#"
+            extensions = ["img"]
+            chunking = {{ enabled = true, max_size = 1048576, avg_size = 2097152 }}
+        "#
+        )
+        .unwrap();
+
+        let error = Config::from_file(config_file.path().to_str().unwrap()).unwrap_err();
+        assert!(format!("{:#}", error).contains("min_size <= avg_size <= max_size"));
+    }
+
+    #[test]
+    fn test_profiles() {
+        let mut config_file = NamedTempFile::new().unwrap();
+        write!(
+            config_file,
+            r#"
+            extensions = ["txt"]
+            exclude = ["*.tmp"]
+            hash_algorithm = "sha256"
+
+            [retention]
+            keep_last = 10
+
+            [profile.photos]
+            extensions = ["jpg", "PNG"]
+            target = "/mnt/backup/photos"
+            sources = [{{ name = "photos", path = "/srv/photos" }}]
+
+            [profile.photos.retention]
+            keep_within = "30d"
+
+            [profile.docs]
+            hash_algorithm = "blake3"
+        "#
+        )
+        .unwrap();
+        let path = config_file.path().to_str().unwrap();
+
+        let photos = Config::from_profile(path, "photos").unwrap();
+        assert_eq!(photos.extensions.len(), 2);
+        assert!(photos.extensions.contains("png"));
+        // Не заданное в профиле наследуется из общих настроек
+        assert_eq!(photos.exclude, vec!["*.tmp"]);
+        assert_eq!(photos.hash_algorithm, Some(HashAlgorithm::Sha256));
+        assert_eq!(photos.target.as_deref(), Some("/mnt/backup/photos"));
+        assert_eq!(photos.sources[0].path, PathBuf::from("/srv/photos"));
+        assert_eq!(photos.retention.keep_last, Some(10));
+        assert_eq!(
+            photos.retention.keep_within,
+            Some(chrono::Duration::days(30))
+        );
+
+        let docs = Config::from_profile(path, "docs").unwrap();
+        assert_eq!(docs.hash_algorithm, Some(HashAlgorithm::Blake3));
+        assert!(docs.extensions.contains("txt"));
+
+        // Без профиля секции профилей не используются
+        let global = Config::from_file(path).unwrap();
+        assert_eq!(global.target, None);
+        assert_eq!(global.retention.keep_within, None);
+
+        let error = Config::from_profile(path, "music").unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("Unknown profile music in config file"));
+        assert!(error.to_string().contains("(available: docs, photos)"));
+    }
+
+    #[test]
+    fn test_invalid_profile_retention() {
+        let mut config_file = NamedTempFile::new().unwrap();
+        write!(
+            config_file,
+            r#"
+            extensions = ["txt"]
+
+            [profile.docs.retention]
+            keep_within = "30 days"
+
+            [profile.all.retention]
+            keep_last = 0
+
+            [profile.future.retention]
+            keep_within = "-5d"
+        "#
+        )
+        .unwrap();
+
+        let path = config_file.path().to_str().unwrap();
+        assert!(Config::from_profile(path, "docs").is_err());
+        let error = Config::from_profile(path, "all").unwrap_err();
+        assert!(format!("{:#}", error).contains("keep_last must be at least 1"));
+        let error = Config::from_profile(path, "future").unwrap_err();
+        assert!(format!("{:#}", error).contains("Duration must be positive"));
+    }
+
+    #[test]
+    fn test_unknown_hash_algorithm() {
+        let mut config_file = NamedTempFile::new().unwrap();
+        write!(
+            config_file,
+            r#"
+            extensions = ["txt"]
+            hash_algorithm = "crc32"
+        "#
+        )
+        .unwrap();
+
+        let result = Config::from_file(config_file.path().to_str().unwrap());
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_buffer_size() {
+        let mut config_file = NamedTempFile::new().unwrap();
+        write!(
+            config_file,
+            r#"
+            extensions = ["txt"]
+            buffer_size = 1048576
+        "#
+        )
+        .unwrap();
+
+        let config = Config::from_file(config_file.path().to_str().unwrap()).unwrap();
+        assert_eq!(config.buffer_size, 1048576);
+    }
+
+    #[test]
+    fn test_zero_buffer_size() {
+        let mut config_file = NamedTempFile::new().unwrap();
+        write!(
+            config_file,
+            r#"
+            extensions = ["txt"]
+            buffer_size = 0
+        "#
+        )
+        .unwrap();
+
+        let result = Config::from_file(config_file.path().to_str().unwrap());
+        assert!(result.is_err());
+        assert!(
+            format!("{:#}", result.unwrap_err()).contains("buffer_size must be greater than zero")
+        );
     }
 
     #[test]
@@ -143,7 +738,6 @@ mod tests {
         assert!(result.is_err());
     }
 
-    #[ignore]
     #[test]
     fn test_whitespace_in_extensions() {
         let mut config_file = NamedTempFile::new().unwrap();
@@ -161,6 +755,23 @@ mod tests {
         assert!(config.extensions.contains("pdf"));
     }
 
+    #[test]
+    fn test_dotted_extensions() {
+        let mut config_file = NamedTempFile::new().unwrap();
+        write!(
+            config_file,
+            r#"
+            extensions = [".JPG", "txt", " .pdf"]
+        "#
+        )
+        .unwrap();
+
+        let config = Config::from_file(config_file.path().to_str().unwrap()).unwrap();
+        assert_eq!(config.extensions.len(), 3);
+        assert!(config.extensions.contains("jpg"));
+        assert!(config.extensions.contains("pdf"));
+    }
+
     #[test]
     fn test_commented_extensions() {
         let mut config_file = NamedTempFile::new().unwrap();
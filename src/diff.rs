@@ -0,0 +1,154 @@
+use crate::repository::Repository;
+use crate::snapshot::ManifestEntry;
+use anyhow::Result;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::PathBuf;
+
+/// Вид изменения файла между двумя снимками
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Change {
+    Added,
+    Removed,
+    /// Изменилось содержимое или цель ссылки
+    Modified,
+    /// Содержимое то же, изменились метаданные (mtime, права, владелец, xattr)
+    Metadata,
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let marker = match self {
+            Change::Added => "+",
+            Change::Removed => "-",
+            Change::Modified => "M",
+            Change::Metadata => "m",
+        };
+        f.write_str(marker)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DiffEntry {
+    pub path: PathBuf,
+    pub change: Change,
+}
+
+/// Сравнивает два снимка хранилища; изменения упорядочены по пути
+pub fn diff_snapshots(target_base: &str, from: &str, to: &str) -> Result<Vec<DiffEntry>> {
+    let repository = Repository::open(target_base)?;
+    let before = by_path(repository.read_snapshot(from)?);
+    let mut after = by_path(repository.read_snapshot(to)?);
+
+    let mut changes = Vec::new();
+    for (path, old) in before {
+        let change = match after.remove(&path) {
+            None => Change::Removed,
+            Some(new) if new.hash != old.hash || new.link_target != old.link_target => {
+                Change::Modified
+            }
+            Some(new) if new != old => Change::Metadata,
+            Some(_) => continue,
+        };
+        changes.push(DiffEntry { path, change });
+    }
+    changes.extend(after.into_keys().map(|path| DiffEntry {
+        path,
+        change: Change::Added,
+    }));
+
+    changes.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(changes)
+}
+
+fn by_path(entries: Vec<ManifestEntry>) -> BTreeMap<PathBuf, ManifestEntry> {
+    entries.into_iter().map(|e| (e.path.clone(), e)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::snapshot::ManifestWriter;
+    use std::fs;
+    use tempfile::TempDir;
+
+    const TEST_MD5: &str = "098f6bcd4621d373cade4e832627b4f6";
+    const CONTENT_MD5: &str = "9a0364b9e99bb480dd25e1f0284c8555";
+
+    fn write_snapshot(repository: &Repository, name: &str, entries: &[ManifestEntry]) {
+        let snapshot_dir = repository.snapshot_dir(name);
+        fs::create_dir(&snapshot_dir).unwrap();
+        let mut manifest = ManifestWriter::create(&snapshot_dir).unwrap();
+        for entry in entries {
+            manifest.write(entry).unwrap();
+        }
+        manifest.finish().unwrap();
+    }
+
+    fn entry(path: &str, hash: &str, mode: u32) -> ManifestEntry {
+        ManifestEntry {
+            mode: Some(mode),
+            ..ManifestEntry::from_hash(PathBuf::from(path), hash.to_string())
+        }
+    }
+
+    #[test]
+    fn test_diff_snapshots() {
+        let temp_dir = TempDir::new().unwrap();
+        let target = temp_dir.path().to_str().unwrap();
+        let repository = Repository::open_or_init(target, None).unwrap();
+
+        write_snapshot(
+            &repository,
+            "20240101_000000",
+            &[
+                entry("same.txt", TEST_MD5, 0o644),
+                entry("modified.txt", TEST_MD5, 0o644),
+                entry("chmod.txt", TEST_MD5, 0o644),
+                entry("removed.txt", TEST_MD5, 0o644),
+            ],
+        );
+        write_snapshot(
+            &repository,
+            "20240102_000000",
+            &[
+                entry("same.txt", TEST_MD5, 0o644),
+                entry("modified.txt", CONTENT_MD5, 0o644),
+                entry("chmod.txt", TEST_MD5, 0o600),
+                entry("added.txt", TEST_MD5, 0o644),
+            ],
+        );
+
+        let changes = diff_snapshots(target, "20240101_000000", "20240102_000000").unwrap();
+
+        let summary: Vec<String> = changes
+            .iter()
+            .map(|c| format!("{} {}", c.change, c.path.display()))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                "+ added.txt",
+                "m chmod.txt",
+                "M modified.txt",
+                "- removed.txt"
+            ]
+        );
+        assert_eq!(
+            serde_json::to_string(&changes[0]).unwrap(),
+            r#"{"path":"added.txt","change":"added"}"#
+        );
+    }
+
+    #[test]
+    fn test_diff_missing_snapshot() {
+        let temp_dir = TempDir::new().unwrap();
+        let target = temp_dir.path().to_str().unwrap();
+        let repository = Repository::open_or_init(target, None).unwrap();
+        write_snapshot(&repository, "20240101_000000", &[]);
+
+        assert!(diff_snapshots(target, "20240101_000000", "20240102_000000").is_err());
+    }
+}
@@ -1,10 +1,18 @@
-use anyhow::{Context, Result};
-use chrono::Local;
+use crate::hash::{self, HashAlgorithm};
+use crate::repository::Repository;
+use anyhow::{bail, Context, Result};
+use chrono::{Local, NaiveDateTime};
 use std::collections::HashSet;
 use std::ffi::OsStr;
 use std::fs::{self, create_dir_all, File};
-use std::io::Write;
+use std::io::{ErrorKind, Write};
 use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+use tempfile::NamedTempFile;
+
+/// Префикс временных файлов, в которые пишутся объекты до переименования
+pub const TEMP_OBJECT_PREFIX: &str = ".tmp-";
 
 pub fn create_directories(dirs: &[&Path]) -> Result<()> {
     for dir in dirs {
@@ -14,10 +22,32 @@ pub fn create_directories(dirs: &[&Path]) -> Result<()> {
     Ok(())
 }
 
+/// Формат имени директории снимка, например 20230601_123045
+pub const TIMESTAMP_FORMAT: &str = "%Y%m%d_%H%M%S";
+
+/// Создает директорию снимка с текущим временем в имени. Если директория с таким
+/// именем уже есть (предыдущий запуск в ту же секунду), ждет следующей секунды:
+/// существующий снимок никогда не переиспользуется
 pub fn create_timestamp_dir(base: &str) -> Result<PathBuf> {
-    let timestamp = Local::now().format("%Y%m%d_%H%M%S").to_string();
-    let path = Path::new(base).join(timestamp);
-    Ok(path)
+    loop {
+        let timestamp = Local::now().format(TIMESTAMP_FORMAT).to_string();
+        let path = Path::new(base).join(timestamp);
+        match fs::create_dir(&path) {
+            Ok(()) => return Ok(path),
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                thread::sleep(Duration::from_millis(100));
+            }
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("Failed to create directory: {}", path.display()));
+            }
+        }
+    }
+}
+
+/// Разбирает имя директории снимка; для посторонних имен возвращает None
+pub fn parse_timestamp(name: &str) -> Option<NaiveDateTime> {
+    NaiveDateTime::parse_from_str(name, TIMESTAMP_FORMAT).ok()
 }
 
 pub fn has_extension(path: &Path, extensions: &HashSet<String>) -> bool {
@@ -27,76 +57,224 @@ pub fn has_extension(path: &Path, extensions: &HashSet<String>) -> bool {
         .unwrap_or(false)
 }
 
-pub fn handle_md5_copy(source_path: &Path, md5_dir: &Path, md5_hex: &str) -> Result<String> {
-    // Получаем первые два символа MD5 для поддиректории
-    let prefix = &md5_hex[..2];
-
-    // Создаем путь: md5_dir/{prefix}/{md5_hex}
-    let sub_dir = md5_dir.join(prefix);
-    create_dir_all(&sub_dir)
-        .with_context(|| format!("Failed to create subdirectory: {}", sub_dir.display()))?;
+/// Возвращает путь объекта в хранилище: objects_dir/{prefix}/{hash_hex}
+pub fn object_path(objects_dir: &Path, hash_hex: &str) -> PathBuf {
+    // Первые два символа хеша используются как поддиректория
+    objects_dir.join(&hash_hex[..2]).join(hash_hex)
+}
 
-    let md5_target = sub_dir.join(md5_hex);
-    let full_md5_path = fs::canonicalize(&md5_target)
-        .unwrap_or_else(|_| md5_target.clone())
-        .to_string_lossy()
-        .to_string();
+/// Объект, сохраненный в хранилище
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoredObject {
+    pub hash: String,
+    /// Объект записан этим вызовом; false - объект с таким хешем уже был
+    pub is_new: bool,
+    /// Число прочитанных из исходного файла байт
+    pub size: u64,
+    /// Сколько байт содержимого записано в хранилище впервые: для целого объекта
+    /// весь размер или 0, для файла из фрагментов - только новые фрагменты
+    pub new_bytes: u64,
+}
 
-    // Копируем только если файл не существует
-    if !md5_target.exists() {
-        fs::copy(source_path, &md5_target).with_context(|| {
+/// Сохраняет файл в хранилище за один проход чтения: содержимое пишется во временный
+/// файл в каталоге объектов с одновременным хешированием записанных байт. Если
+/// объект с таким хешем уже есть (целиком или списком фрагментов), временный файл
+/// удаляется; иначе он синхронизируется с диском, перечитывается и сверяется с хешем
+/// (исходный файл второй раз не читается), и только затем переименовывается на место
+/// без перезаписи существующего объекта. Прерванное или искаженное при записи
+/// копирование не оставляет объект с чужим содержимым под валидным именем
+pub fn store_object(
+    source_path: &Path,
+    repository: &Repository,
+    buffer_size: usize,
+) -> Result<StoredObject> {
+    let objects_dir = &repository.objects_dir();
+    let algorithm = repository.algorithm();
+    let mut source = File::open(source_path)
+        .with_context(|| format!("Failed to open file: {}", source_path.display()))?;
+    let permissions = source
+        .metadata()
+        .with_context(|| format!("Failed to read metadata: {}", source_path.display()))?
+        .permissions();
+
+    create_dir_all(objects_dir)
+        .with_context(|| format!("Failed to create directory: {}", objects_dir.display()))?;
+    // Имя временного файла не является хешем, поэтому он не виден как объект
+    let mut temp = tempfile::Builder::new()
+        .prefix(TEMP_OBJECT_PREFIX)
+        .tempfile_in(objects_dir)
+        .with_context(|| {
             format!(
-                "Failed to copy {} to {}",
-                source_path.display(),
-                md5_target.display()
+                "Failed to create temporary file in {}",
+                objects_dir.display()
             )
         })?;
+
+    let (hash_hex, size) =
+        hash::copy_with_hash(&mut source, temp.as_file_mut(), algorithm, buffer_size)
+            .with_context(|| {
+                format!(
+                    "Failed to copy {} to {}",
+                    source_path.display(),
+                    temp.path().display()
+                )
+            })?;
+
+    let target = object_path(objects_dir, &hash_hex);
+    // Дубликат (в том числе сохраненный фрагментами): временный файл удаляется
+    // при выходе из функции
+    if repository.has_object(&hash_hex) {
+        return Ok(StoredObject {
+            hash: hash_hex,
+            is_new: false,
+            size,
+            new_bytes: 0,
+        });
     }
 
-    Ok(full_md5_path)
+    sync_file(&temp)?;
+    verify_written(temp.path(), &hash_hex, algorithm, buffer_size)
+        .with_context(|| format!("Failed to store {}", source_path.display()))?;
+    fs::set_permissions(temp.path(), permissions)
+        .with_context(|| format!("Failed to set permissions: {}", temp.path().display()))?;
+    let is_new = persist_object(temp, &target)?;
+
+    Ok(StoredObject {
+        hash: hash_hex,
+        is_new,
+        size,
+        new_bytes: if is_new { size } else { 0 },
+    })
 }
 
-/// Создает файл с записью во временной директории, сохраняя структуру каталогов
-pub fn create_timestamp_record(
-    source_path: &Path,
-    source_base: &Path,
-    timestamp_dir: &Path,
-    md5_hex: &str,
+/// Записывает объект из памяти (фрагмент файла) так же атомарно, как store_object:
+/// синхронизированный временный файл перечитывается и сверяется с `hash_hex`.
+/// Возвращает false, если объект с таким хешем уже есть: он не перезаписывается
+pub fn write_object(
+    repository: &Repository,
+    hash_hex: &str,
+    data: &[u8],
+    buffer_size: usize,
+) -> Result<bool> {
+    let algorithm = repository.algorithm();
+    write_verified(&repository.objects_dir(), hash_hex, data, |path| {
+        verify_written(path, hash_hex, algorithm, buffer_size)
+    })
+}
+
+/// Записывает данные из памяти в файл `dir/{prefix}/{name}`: временный файл
+/// синхронизируется с диском, проверяется `verify` и только затем переименовывается.
+/// Возвращает false, если файл с таким именем уже есть: он не перезаписывается
+pub fn write_verified(
+    dir: &Path,
+    name: &str,
+    data: &[u8],
+    verify: impl FnOnce(&Path) -> Result<()>,
+) -> Result<bool> {
+    let target = object_path(dir, name);
+    if target.exists() {
+        return Ok(false);
+    }
+
+    create_dir_all(dir)
+        .with_context(|| format!("Failed to create directory: {}", dir.display()))?;
+    let mut temp = tempfile::Builder::new()
+        .prefix(TEMP_OBJECT_PREFIX)
+        .tempfile_in(dir)
+        .with_context(|| format!("Failed to create temporary file in {}", dir.display()))?;
+    temp.write_all(data)
+        .with_context(|| format!("Failed to write file: {}", temp.path().display()))?;
+    sync_file(&temp)?;
+    verify(temp.path()).with_context(|| format!("Failed to store {}", target.display()))?;
+
+    persist_object(temp, &target)
+}
+
+fn sync_file(temp: &NamedTempFile) -> Result<()> {
+    temp.as_file()
+        .sync_all()
+        .with_context(|| format!("Failed to sync file: {}", temp.path().display()))
+}
+
+/// Перечитывает записанный временный файл и сверяет его хеш с ожидаемым
+fn verify_written(
+    path: &Path,
+    expected: &str,
+    algorithm: HashAlgorithm,
+    buffer_size: usize,
 ) -> Result<()> {
-    // Получаем относительный путь от базовой директории
-    let relative_path = source_path.strip_prefix(source_base).with_context(|| {
-        format!(
-            "Failed to get relative path for {} from base {}",
-            source_path.display(),
-            source_base.display()
-        )
-    })?;
+    let actual = hash::calculate_hash(path, algorithm, buffer_size)?;
+    if actual != expected {
+        bail!(
+            "Hash mismatch: expected {}, stored copy has {}",
+            expected,
+            actual
+        );
+    }
+    Ok(())
+}
 
-    // Создаем полный путь во временной директории
-    let record_path = timestamp_dir.join(relative_path);
+/// Переименовывает синхронизированный временный файл в `target` без перезаписи.
+/// Возвращает false, если тот же объект успели записать параллельно
+fn persist_object(temp: NamedTempFile, target: &Path) -> Result<bool> {
+    let sub_dir = target
+        .parent()
+        .with_context(|| format!("Invalid object path: {}", target.display()))?;
+    create_dir_all(sub_dir)
+        .with_context(|| format!("Failed to create subdirectory: {}", sub_dir.display()))?;
 
-    // Создаем все необходимые поддиректории
-    if let Some(parent) = record_path.parent() {
-        create_dir_all(parent)
-            .with_context(|| format!("Failed to create directory: {}", parent.display()))?;
+    match temp.persist_noclobber(target) {
+        Ok(_) => {}
+        Err(e) if e.error.kind() == ErrorKind::AlreadyExists => return Ok(false),
+        Err(e) => {
+            return Err(e.error)
+                .with_context(|| format!("Failed to rename object to {}", target.display()));
+        }
     }
+    sync_dir(sub_dir)?;
 
-    // Создаем файл с записью
-    let mut output = File::create(&record_path)
-        .with_context(|| format!("Failed to create file: {}", record_path.display()))?;
+    Ok(true)
+}
 
-    write!(output, "{}", md5_hex)
-        .with_context(|| format!("Failed to write to file: {}", record_path.display()))?;
+/// Синхронизирует директорию, чтобы переименование пережило сбой питания
+#[cfg(unix)]
+pub fn sync_dir(dir: &Path) -> Result<()> {
+    File::open(dir)
+        .and_then(|d| d.sync_all())
+        .with_context(|| format!("Failed to sync directory: {}", dir.display()))
+}
 
+#[cfg(not(unix))]
+pub fn sync_dir(_dir: &Path) -> Result<()> {
     Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::fs;
+    use crate::hash::DEFAULT_BUFFER_SIZE;
     use tempfile::{NamedTempFile, TempDir};
 
+    // Сохраняет файл в хранилище target; объекты лежат в target/files_by_md5
+    fn store(source: &Path, target: &Path) -> Result<StoredObject> {
+        let repository = Repository::open_or_init(target.to_str().unwrap(), None)?;
+        store_object(source, &repository, DEFAULT_BUFFER_SIZE)
+    }
+
+    #[test]
+    fn test_create_timestamp_dir_is_unique() {
+        let temp_dir = TempDir::new().unwrap();
+        let base = temp_dir.path().to_str().unwrap();
+
+        let first = create_timestamp_dir(base).unwrap();
+        let second = create_timestamp_dir(base).unwrap();
+
+        assert_ne!(first, second);
+        assert!(first.is_dir() && second.is_dir());
+        let name = second.file_name().unwrap().to_str().unwrap();
+        assert!(parse_timestamp(name).is_some());
+    }
+
     #[test]
     fn test_create_directories_success() {
         let temp_dir = TempDir::new().unwrap();
@@ -125,12 +303,21 @@ mod tests {
 
         assert!(result.is_ok());
         let timestamp_dir = result.unwrap();
-        // assert!(timestamp_dir.exists()); create_timestamp_dir - не создает файлов/диреторий
+        assert!(timestamp_dir.is_dir());
         assert!(timestamp_dir
             .to_string_lossy()
             .contains(Local::now().format("%Y%m%d").to_string().as_str()));
     }
 
+    #[test]
+    fn test_parse_timestamp() {
+        let parsed = parse_timestamp("20230601_123045").unwrap();
+        assert_eq!(parsed.to_string(), "2023-06-01 12:30:45");
+
+        assert!(parse_timestamp("files_by_md5").is_none());
+        assert!(parse_timestamp("20231301_000000").is_none());
+    }
+
     #[test]
     fn test_has_extension() {
         let temp_file = NamedTempFile::new().unwrap();
@@ -143,101 +330,145 @@ mod tests {
     }
 
     #[test]
-    fn test_handle_md5_copy_new_file() {
+    fn test_object_path() {
+        let md5_dir = Path::new("/backup/files_by_md5");
+        let md5_hex = "098f6bcd4621d373cade4e832627b4f6";
+
+        assert_eq!(
+            object_path(md5_dir, md5_hex),
+            md5_dir.join("09").join(md5_hex)
+        );
+    }
+
+    #[test]
+    fn test_store_object_new_file() {
         let temp_dir = TempDir::new().unwrap();
         let source_file = temp_dir.path().join("source.txt");
-        fs::write(&source_file, "test content").unwrap();
+        fs::write(&source_file, "test").unwrap();
 
         let md5_hex = "098f6bcd4621d373cade4e832627b4f6"; // MD5 для "test"
-        let md5_dir = temp_dir.path().join("md5");
+        let target = temp_dir.path().join("target");
+        let md5_dir = target.join("files_by_md5");
 
-        let result = handle_md5_copy(&source_file, &md5_dir, md5_hex);
-        assert!(result.is_ok());
+        let stored = store(&source_file, &target).unwrap();
+        assert_eq!(stored.hash, md5_hex);
+        assert!(stored.is_new);
+        assert_eq!(stored.size, 4);
+        assert_eq!(stored.new_bytes, 4);
 
         let expected_path = md5_dir.join("09").join(md5_hex);
-        assert!(expected_path.exists());
+        assert_eq!(fs::read_to_string(&expected_path).unwrap(), "test");
+        // Временный файл не остается в хранилище
+        assert_eq!(fs::read_dir(&md5_dir).unwrap().count(), 1);
     }
 
     #[test]
-    fn test_handle_md5_copy_existing_file() {
+    fn test_verify_written_hash_mismatch() {
         let temp_dir = TempDir::new().unwrap();
-        let source_file = temp_dir.path().join("source.txt");
-        fs::write(&source_file, "test content").unwrap();
-
+        let written = temp_dir.path().join("written");
+        fs::write(&written, "test").unwrap();
         let md5_hex = "098f6bcd4621d373cade4e832627b4f6";
-        let md5_dir = temp_dir.path().join("md5");
 
-        // Первое копирование
-        handle_md5_copy(&source_file, &md5_dir, md5_hex).unwrap();
-        // Второе копирование (не должно вызывать ошибку)
-        let result = handle_md5_copy(&source_file, &md5_dir, md5_hex);
+        assert!(verify_written(&written, md5_hex, HashAlgorithm::Md5, DEFAULT_BUFFER_SIZE).is_ok());
 
-        assert!(result.is_ok());
+        // Содержимое, искаженное после копирования, не проходит сверку
+        fs::write(&written, "tesT").unwrap();
+        let error =
+            verify_written(&written, md5_hex, HashAlgorithm::Md5, DEFAULT_BUFFER_SIZE).unwrap_err();
+        assert!(error.to_string().contains("Hash mismatch"));
     }
 
-    #[ignore]
     #[test]
-    fn test_handle_md5_copy_invalid_md5() {
+    fn test_store_object_existing_file() {
         let temp_dir = TempDir::new().unwrap();
         let source_file = temp_dir.path().join("source.txt");
         fs::write(&source_file, "test").unwrap();
+        let target = temp_dir.path().join("target");
+        let md5_dir = target.join("files_by_md5");
 
-        let result = handle_md5_copy(&source_file, temp_dir.path(), "short");
-        assert!(result.is_err());
+        // Первое копирование
+        store(&source_file, &target).unwrap();
+        // Второе копирование (не должно вызывать ошибку и перезаписывать объект)
+        let result = store(&source_file, &target);
+
+        assert!(result.is_ok());
+        assert!(!result.unwrap().is_new);
+        assert_eq!(fs::read_dir(&md5_dir).unwrap().count(), 1);
     }
 
     #[test]
-    fn test_create_timestamp_record_simple() {
+    fn test_store_object_keeps_existing_object() {
         let temp_dir = TempDir::new().unwrap();
-        let source_base = temp_dir.path().join("source");
-        fs::create_dir(&source_base).unwrap();
+        let source_file = temp_dir.path().join("source.txt");
+        fs::write(&source_file, "test").unwrap();
+        let target = temp_dir.path().join("target");
+        let md5_dir = target.join("files_by_md5");
 
-        let source_file = source_base.join("test.txt");
-        fs::write(&source_file, "content").unwrap();
+        let existing = object_path(&md5_dir, "098f6bcd4621d373cade4e832627b4f6");
+        fs::create_dir_all(existing.parent().unwrap()).unwrap();
+        fs::write(&existing, "test").unwrap();
 
-        let timestamp_dir = temp_dir.path().join("timestamp");
-        let md5_path = "/path/to/md5/file";
+        assert!(!store(&source_file, &target).unwrap().is_new);
+        assert_eq!(fs::read_dir(&md5_dir).unwrap().count(), 1);
+    }
 
-        let result = create_timestamp_record(&source_file, &source_base, &timestamp_dir, md5_path);
-        assert!(result.is_ok());
+    #[test]
+    fn test_store_object_keeps_chunked_object() {
+        let temp_dir = TempDir::new().unwrap();
+        let source_file = temp_dir.path().join("source.txt");
+        fs::write(&source_file, "test").unwrap();
+        let target = temp_dir.path().join("target");
+        let repository = Repository::open_or_init(target.to_str().unwrap(), None).unwrap();
 
-        let expected_record = timestamp_dir.join("test.txt");
-        assert!(expected_record.exists());
-        assert_eq!(fs::read_to_string(expected_record).unwrap(), md5_path);
+        // То же содержимое уже сохранено списком фрагментов
+        let md5_hex = "098f6bcd4621d373cade4e832627b4f6";
+        let chunk_list = repository.chunk_list_path(md5_hex);
+        fs::create_dir_all(chunk_list.parent().unwrap()).unwrap();
+        fs::write(&chunk_list, "").unwrap();
+
+        let stored = store(&source_file, &target).unwrap();
+        assert!(!stored.is_new);
+        assert_eq!(stored.new_bytes, 0);
+        assert!(!repository.object_path(md5_hex).exists());
     }
 
     #[test]
-    fn test_create_timestamp_record_nested() {
+    fn test_write_object() {
         let temp_dir = TempDir::new().unwrap();
-        let source_base = temp_dir.path().join("source");
-
-        let nested_dir = source_base.join("nested/dir");
-        fs::create_dir_all(&nested_dir).unwrap();
+        let repository = Repository::open_or_init(temp_dir.path().to_str().unwrap(), None).unwrap();
+        let md5_dir = repository.objects_dir();
+        let md5_hex = "098f6bcd4621d373cade4e832627b4f6";
 
-        let source_file = nested_dir.join("file.txt");
-        fs::write(&source_file, "content").unwrap();
+        assert!(write_object(&repository, md5_hex, b"test", DEFAULT_BUFFER_SIZE).unwrap());
+        assert!(!write_object(&repository, md5_hex, b"other", DEFAULT_BUFFER_SIZE).unwrap());
 
-        let timestamp_dir = temp_dir.path().join("timestamp");
-        let md5_path = "/path/to/md5/file";
+        // Существующий объект не перезаписывается, временных файлов не остается
+        assert_eq!(
+            fs::read_to_string(object_path(&md5_dir, md5_hex)).unwrap(),
+            "test"
+        );
+        assert_eq!(fs::read_dir(md5_dir.join("09")).unwrap().count(), 1);
+        assert_eq!(fs::read_dir(&md5_dir).unwrap().count(), 1);
+    }
 
-        let result = create_timestamp_record(&source_file, &source_base, &timestamp_dir, md5_path);
-        assert!(result.is_ok());
+    #[test]
+    fn test_write_object_hash_mismatch() {
+        let temp_dir = TempDir::new().unwrap();
+        let repository = Repository::open_or_init(temp_dir.path().to_str().unwrap(), None).unwrap();
+        let md5_hex = "098f6bcd4621d373cade4e832627b4f6";
 
-        let expected_record = timestamp_dir.join("nested/dir/file.txt");
-        assert!(expected_record.exists());
-        assert_eq!(fs::read_to_string(expected_record).unwrap(), md5_path);
+        // Данные не соответствуют имени: объект не появляется под чужим хешем
+        let error = write_object(&repository, md5_hex, b"tesT", DEFAULT_BUFFER_SIZE).unwrap_err();
+        assert!(format!("{:#}", error).contains("Hash mismatch"));
+        assert!(!repository.object_path(md5_hex).exists());
+        assert_eq!(fs::read_dir(repository.objects_dir()).unwrap().count(), 0);
     }
 
     #[test]
-    fn test_create_timestamp_record_invalid_base() {
+    fn test_store_object_missing_source() {
         let temp_dir = TempDir::new().unwrap();
-        let source_file = temp_dir.path().join("file.txt");
-        fs::write(&source_file, "content").unwrap();
-
-        let invalid_base = Path::new("/invalid/base");
-        let timestamp_dir = temp_dir.path().join("timestamp");
-        let result = create_timestamp_record(&source_file, invalid_base, &timestamp_dir, "md5");
 
+        let result = store(&temp_dir.path().join("missing.txt"), temp_dir.path());
         assert!(result.is_err());
     }
 }
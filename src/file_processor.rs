@@ -1,78 +1,831 @@
-use crate::{directory, hash};
-use anyhow::Result;
-use std::collections::HashSet;
-use std::path::Path;
+use crate::cache::{self, FileStamp, HashCache};
+use crate::config::{Config, Source, SymlinkPolicy};
+use crate::filter::PathFilter;
+use crate::repository::Repository;
+use crate::snapshot::{ManifestEntry, ManifestWriter};
+use crate::{chunking, directory, hash, metadata, output, summary};
+use anyhow::{bail, Context, Result};
+use crossbeam_channel::{self as channel, Receiver, Sender};
+use serde::Serialize;
+use std::cell::Cell;
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
 use walkdir::{DirEntry, WalkDir};
 
+/// Итоги одного запуска
+#[derive(Debug, Default, Serialize)]
+pub struct BackupStats {
+    /// Имя созданного снимка; None при dry-run
+    pub snapshot: Option<String>,
+    /// Все найденные обходом записи, кроме директорий
+    pub scanned: usize,
+    /// Записи, подходящие по расширению или шаблону include
+    pub matched: usize,
+    /// Скрытые файлы и директории; содержимое скрытых директорий не обходится
+    pub hidden_skipped: usize,
+    /// Файлы и директории, совпавшие с шаблонами exclude
+    pub excluded_skipped: usize,
+    /// Файлы и ссылки на файлы (при symlinks = "record") с неподходящим
+    /// расширением, не совпавшие с include
+    pub extension_skipped: usize,
+    /// Ошибки обхода и обработки файлов
+    pub errors: Vec<FileError>,
+    pub symlinks_recorded: usize,
+    pub symlinks_skipped: usize,
+    /// Пропущенные символические ссылки (при symlinks = "skip")
+    pub skipped_symlinks: Vec<PathBuf>,
+    /// FIFO, сокеты и файлы устройств
+    pub special_files_skipped: usize,
+    /// Файлы, которые менялись во время копирования при всех попытках
+    pub inconsistent: Vec<PathBuf>,
+    /// Файлы, которые менялись во время копирования и читались повторно
+    pub retried: Vec<PathBuf>,
+    /// Кеш хешей удален перед запуском (--clear-cache)
+    pub cache_cleared: bool,
+    /// Ошибка чтения кеша хешей: кеш не использован, все файлы хешируются заново
+    pub cache_error: Option<FileError>,
+    /// Файлы, хеш которых взят из кеша без чтения содержимого
+    pub cached: usize,
+    /// Файлы (без символических ссылок), попавшие в снимок
+    pub files: usize,
+    /// Записанные объекты и их объем; при dry-run - объекты, которые были бы записаны
+    pub new_objects: usize,
+    pub new_bytes: u64,
+    /// Файлы, содержимое которых уже есть в хранилище или встретилось раньше, и их объем
+    pub duplicates: usize,
+    pub deduplicated_bytes: u64,
+    /// Прочитано из исходных файлов, включая повторные попытки
+    pub bytes_read: u64,
+    #[serde(rename = "elapsed_secs", serialize_with = "output::serialize_secs")]
+    pub elapsed: Duration,
+}
+
+/// Ошибка обхода или обработки одного файла
+#[derive(Debug, Serialize)]
+pub struct FileError {
+    pub path: PathBuf,
+    /// Сообщения от внешнего контекста к исходной причине
+    pub messages: Vec<String>,
+}
+
+impl FileError {
+    fn new(path: PathBuf, error: &anyhow::Error) -> Self {
+        Self {
+            path,
+            messages: output::error_chain(error),
+        }
+    }
+}
+
+impl BackupStats {
+    fn merge(&mut self, other: BackupStats) {
+        self.scanned += other.scanned;
+        self.matched += other.matched;
+        self.hidden_skipped += other.hidden_skipped;
+        self.excluded_skipped += other.excluded_skipped;
+        self.extension_skipped += other.extension_skipped;
+        self.errors.extend(other.errors);
+        self.symlinks_recorded += other.symlinks_recorded;
+        self.symlinks_skipped += other.symlinks_skipped;
+        self.skipped_symlinks.extend(other.skipped_symlinks);
+        self.special_files_skipped += other.special_files_skipped;
+        self.inconsistent.extend(other.inconsistent);
+        self.retried.extend(other.retried);
+        self.cached += other.cached;
+        self.files += other.files;
+        self.new_objects += other.new_objects;
+        self.new_bytes += other.new_bytes;
+        self.duplicates += other.duplicates;
+        self.deduplicated_bytes += other.deduplicated_bytes;
+        self.bytes_read += other.bytes_read;
+    }
+
+    /// Учитывает файл, попавший в снимок; `is_new` - для него записан новый объект,
+    /// `new_bytes` - сколько байт его содержимого записано впервые. Остальное
+    /// содержимое (при сохранении фрагментами - и часть нового файла) дедуплицировано
+    fn add_file(&mut self, size: u64, is_new: bool, new_bytes: u64) {
+        self.files += 1;
+        if !is_new {
+            self.duplicates += 1;
+        }
+        self.deduplicated_bytes += size.saturating_sub(new_bytes);
+    }
+
+    fn add_new_object(&mut self, size: u64) {
+        self.new_objects += 1;
+        self.new_bytes += size;
+    }
+
+    /// Скорость чтения в байтах в секунду; None, если время не измерено
+    pub fn throughput(&self) -> Option<f64> {
+        let seconds = self.elapsed.as_secs_f64();
+        (seconds > 0.0).then(|| self.bytes_read as f64 / seconds)
+    }
+}
+
+/// Параметры запуска, задаваемые в командной строке
+#[derive(Debug, Default, Clone)]
+pub struct BackupOptions {
+    /// Не использовать кеш хешей: все файлы хешируются заново
+    pub rehash: bool,
+    /// Удалить кеш хешей (после блокировки хранилища) перед запуском
+    pub clear_cache: bool,
+    /// Число потоков хеширования и копирования; None - по числу ядер
+    pub jobs: Option<usize>,
+    /// Только подсчитать, что будет скопировано: ни снимок, ни объекты не создаются
+    pub dry_run: bool,
+    /// Прервать запуск при первой ошибке обработки файла; снимок не создается
+    pub fail_fast: bool,
+    /// Не выводить в stderr сообщения об отдельных файлах и кеше: они остаются
+    /// только в статистике запуска (для --format json)
+    pub quiet: bool,
+}
+
+/// Результат обработки одной записи: порядковый номер в обходе, путь и запись манифеста
+type Outcome = (usize, PathBuf, Result<ManifestEntry>);
+
+/// Исходная директория запуска и поддерево снимка, в которое попадают ее файлы
+#[derive(Debug)]
+struct SourceRoot {
+    /// Имя источника из конфигурации; пустой путь - корень снимка
+    prefix: PathBuf,
+    filter: PathFilter,
+}
+
+impl SourceRoot {
+    fn new(prefix: PathBuf, path: &Path, config: &Config) -> Result<Self> {
+        if !fs::metadata(path)
+            .with_context(|| format!("Failed to read source directory: {}", path.display()))?
+            .is_dir()
+        {
+            bail!("Source is not a directory: {}", path.display());
+        }
+        Ok(Self {
+            prefix,
+            filter: PathFilter::new(path, config)?,
+        })
+    }
+
+    fn path(&self) -> &Path {
+        self.filter.root()
+    }
+
+    /// Переносит запись манифеста в поддерево источника
+    fn place(&self, result: Result<ManifestEntry>) -> Result<ManifestEntry> {
+        result.map(|mut record| {
+            record.path = self.prefix.join(&record.path);
+            record
+        })
+    }
+}
+
+/// Исходные директории запуска: именованные источники из конфигурации
+/// (относительные пути отсчитываются от `source_dir`) или сама `source_dir`
+fn source_roots(source_dir: &str, config: &Config) -> Result<Vec<SourceRoot>> {
+    if config.sources.is_empty() {
+        return Ok(vec![SourceRoot::new(
+            PathBuf::new(),
+            Path::new(source_dir),
+            config,
+        )?]);
+    }
+
+    config
+        .sources
+        .iter()
+        .map(|Source { name, path }| {
+            SourceRoot::new(
+                PathBuf::from(name),
+                &Path::new(source_dir).join(path),
+                config,
+            )
+            .with_context(|| format!("Invalid source: {}", name))
+        })
+        .collect()
+}
+
+/// Резервное копирование конвейером: поток обхода отправляет файлы в пул потоков,
+/// которые хешируют и копируют их, а текущий поток записывает манифест.
+/// Записи манифеста идут в порядке обхода независимо от числа потоков.
+/// Именованные источники из конфигурации попадают в один снимок, каждый в свое
+/// поддерево. При `options.dry_run` файлы только хешируются, а на диск ничего не пишется
 pub fn process_files_with_extensions(
     source_dir: &str,
     target_base: &str,
-    extensions: &HashSet<String>,
-) -> Result<()> {
-    let source_path = Path::new(source_dir);
-    let md5_dir = Path::new(target_base).join("files_by_md5");
-    let timestamp_dir = directory::create_timestamp_dir(target_base)?;
-
-    directory::create_directories(&[&md5_dir, &timestamp_dir])?;
-
-    for entry in WalkDir::new(source_dir)
-        .into_iter()
-        .filter_entry(|e| !is_hidden(e)) // Фильтрация скрытых директорий
-        .filter_map(|e| e.ok())
-        .filter(|e| e.file_type().is_file())
-    {
-        if should_process_file(&entry, extensions) {
-            if let Err(e) = process_file(&entry, source_path, &md5_dir, &timestamp_dir) {
-                eprintln!("Error processing {}: {}", entry.path().display(), e);
+    config: &Config,
+    options: &BackupOptions,
+) -> Result<BackupStats> {
+    let started = Instant::now();
+    let roots = source_roots(source_dir, config)?;
+
+    let repository = if options.dry_run {
+        Repository::open_or_new(target_base, config.hash_algorithm)?
+    } else {
+        Repository::open_or_init(target_base, config.hash_algorithm)?
+    };
+    // Блокировка держится до конца запуска: параллельные запуски, gc и prune
+    // в это время завершаются с ошибкой блокировки
+    let _lock = if options.dry_run {
+        None
+    } else {
+        Some(repository.lock()?)
+    };
+    let cache_cleared = options.clear_cache && !options.dry_run && cache::clear_cache(&repository)?;
+    if cache_cleared && !options.quiet {
+        eprintln!("Hash cache cleared");
+    }
+    // Ключи кеша - абсолютные пути файлов, поэтому и директории сравниваются абсолютными
+    let walked = roots
+        .iter()
+        .map(|root| std::path::absolute(root.path()))
+        .collect::<Result<Vec<_>, _>>()
+        .context("Failed to get absolute path of source directory")?;
+    let mut cache = HashCache::load(&repository, &walked);
+    let cache_error = cache.take_load_error().map(|e| {
+        if !options.quiet {
+            eprintln!("Ignoring hash cache: {:#}", e);
+        }
+        FileError::new(cache.path().to_path_buf(), &e)
+    });
+    if options.rehash {
+        cache.forget_previous();
+    }
+    let cache = Mutex::new(cache);
+    // Хеши, которые уже встретились в этом dry-run: их объекты были бы скопированы один раз
+    let planned = Mutex::new(HashSet::new());
+
+    let timestamp_dir = if options.dry_run {
+        None
+    } else {
+        Some(directory::create_timestamp_dir(target_base)?)
+    };
+    let mut manifest = timestamp_dir
+        .as_deref()
+        .map(ManifestWriter::create)
+        .transpose()?;
+
+    let jobs = options.jobs.unwrap_or_else(default_jobs).max(1);
+    let (job_sender, job_receiver) = channel::bounded::<(usize, &SourceRoot, DirEntry)>(jobs * 4);
+    let (result_sender, result_receiver) = channel::bounded::<Outcome>(jobs * 4);
+
+    let result = thread::scope(|scope| -> Result<BackupStats> {
+        let repository = &repository;
+        let cache = &cache;
+        let planned = &planned;
+
+        let roots = &roots;
+
+        let walker = {
+            let result_sender = result_sender.clone();
+            scope.spawn(move || walk_source(roots, config, options, job_sender, result_sender))
+        };
+
+        let workers: Vec<_> = (0..jobs)
+            .map(|_| {
+                let job_receiver = job_receiver.clone();
+                let result_sender = result_sender.clone();
+                scope.spawn(move || {
+                    let mut stats = BackupStats::default();
+                    for (index, root, entry) in job_receiver {
+                        let result = if options.dry_run {
+                            plan_file(
+                                &entry,
+                                root.path(),
+                                repository,
+                                config,
+                                cache,
+                                planned,
+                                &mut stats,
+                            )
+                        } else {
+                            backup_file(
+                                &entry,
+                                &root.filter,
+                                repository,
+                                config,
+                                options,
+                                cache,
+                                &mut stats,
+                            )
+                        };
+                        if result_sender
+                            .send((index, entry.into_path(), root.place(result)))
+                            .is_err()
+                        {
+                            break;
+                        }
+                    }
+                    stats
+                })
+            })
+            .collect();
+        drop(job_receiver);
+        drop(result_sender);
+
+        // При ошибке записи получатель закрывается, и остальные потоки завершаются
+        let written = write_results(result_receiver, manifest.as_mut(), options);
+
+        let mut stats = walker.join().expect("walker thread panicked");
+        for worker in workers {
+            stats.merge(worker.join().expect("worker thread panicked"));
+        }
+        stats.errors.extend(written?);
+        if options.fail_fast
+            && let Some(error) = stats.errors.first()
+        {
+            bail!(
+                "Stopped after error in {} (--fail-fast): {}",
+                error.path.display(),
+                error.messages.join(": ")
+            );
+        }
+        Ok(stats)
+    });
+
+    let mut stats = match result {
+        Ok(stats) => stats,
+        Err(e) => {
+            // Незавершенный снимок удаляется, чтобы его не приняли за полный
+            if let Some(timestamp_dir) = &timestamp_dir {
+                let _ = fs::remove_dir_all(timestamp_dir);
             }
+            return Err(e);
         }
+    };
+    if let Some(manifest) = manifest {
+        manifest.finish()?;
+        cache
+            .into_inner()
+            .unwrap_or_else(PoisonError::into_inner)
+            .save()?;
+    }
+
+    stats.cache_cleared = cache_cleared;
+    stats.cache_error = cache_error;
+    stats.elapsed = started.elapsed();
+    if let Some(timestamp_dir) = &timestamp_dir {
+        stats.snapshot = timestamp_dir
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned());
+        summary::write_summary(timestamp_dir, &stats)?;
     }
+    Ok(stats)
+}
+
+fn lock(cache: &Mutex<HashCache>) -> MutexGuard<'_, HashCache> {
+    cache.lock().unwrap_or_else(PoisonError::into_inner)
+}
 
-    Ok(())
+fn default_jobs() -> usize {
+    thread::available_parallelism().map_or(1, |n| n.get())
 }
 
-/// Проверяет, является ли директория или файл скрытым
-fn is_hidden(entry: &DirEntry) -> bool {
-    entry
-        .file_name()
-        .to_str()
-        .map(|s| s.starts_with('.')) // Unix-скрытые файлы
-        .unwrap_or(false)
-        || entry.path().components().any(|c| {
-            if let std::path::Component::Normal(os_str) = c {
-                os_str.to_str().map(|s| s.starts_with('.')).unwrap_or(false)
+/// Обходит исходные директории по очереди: файлы отправляются в пул потоков,
+/// символические ссылки обрабатываются сразу. Возвращает счетчики пропущенных записей
+fn walk_source<'a>(
+    roots: &'a [SourceRoot],
+    config: &Config,
+    options: &BackupOptions,
+    jobs: Sender<(usize, &'a SourceRoot, DirEntry)>,
+    results: Sender<Outcome>,
+) -> BackupStats {
+    let mut stats = BackupStats::default();
+    let mut index = 0;
+    let hidden = Cell::new(0);
+    let excluded = Cell::new(0);
+
+    'roots: for root in roots {
+        let filter = &root.filter;
+
+        // При follow_links walkdir сам обнаруживает циклы и возвращает для них ошибку
+        for entry in WalkDir::new(root.path())
+            .follow_links(config.symlinks == SymlinkPolicy::Follow)
+            .into_iter()
+            .filter_entry(|e| {
+                // Скрытые и исключенные директории не обходятся
+                if filter.is_hidden(e.path()) {
+                    hidden.set(hidden.get() + 1);
+                    return false;
+                }
+                if e.file_type().is_dir() && filter.is_excluded(e.path(), true) {
+                    excluded.set(excluded.get() + 1);
+                    return false;
+                }
+                true
+            })
+        {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) => {
+                    match (e.path(), e.loop_ancestor()) {
+                        _ if options.quiet => {}
+                        (Some(path), Some(ancestor)) => eprintln!(
+                            "Skipping symlink loop: {} -> {}",
+                            path.display(),
+                            ancestor.display()
+                        ),
+                        _ => eprintln!("Error walking {}: {}", root.path().display(), e),
+                    }
+                    let path = e.path().unwrap_or(root.path()).to_path_buf();
+                    stats.errors.push(FileError::new(path, &e.into()));
+                    if options.fail_fast {
+                        break 'roots;
+                    }
+                    continue;
+                }
+            };
+
+            let file_type = entry.file_type();
+            if file_type.is_dir() {
+                continue;
+            }
+            stats.scanned += 1;
+            if !file_type.is_file() && !file_type.is_symlink() {
+                stats.special_files_skipped += 1;
+                continue;
+            }
+            // Ссылка на директорию, как и сама директория, не фильтруется по расширению
+            let link_to_dir = file_type.is_symlink() && entry.path().is_dir();
+            if filter.is_excluded(entry.path(), link_to_dir) {
+                stats.excluded_skipped += 1;
+                continue;
+            }
+            // Политика ссылок применяется до фильтра по расширению: о пропуске
+            // сообщается для любой ссылки, в том числе на директорию
+            if file_type.is_symlink() && config.symlinks != SymlinkPolicy::Record {
+                if !options.quiet {
+                    eprintln!("Skipping symlink: {}", entry.path().display());
+                }
+                stats.symlinks_skipped += 1;
+                stats.skipped_symlinks.push(entry.into_path());
+                continue;
+            }
+            if !link_to_dir && !should_process_file(&entry, filter) {
+                stats.extension_skipped += 1;
+                continue;
+            }
+            stats.matched += 1;
+
+            let sent = if file_type.is_symlink() {
+                let result =
+                    process_symlink(&entry, root.path()).inspect(|_| stats.symlinks_recorded += 1);
+                results
+                    .send((index, entry.into_path(), root.place(result)))
+                    .is_ok()
             } else {
-                false
+                jobs.send((index, root, entry)).is_ok()
+            };
+
+            // Получатель закрыт: запись манифеста завершилась ошибкой
+            if !sent {
+                break 'roots;
+            }
+            index += 1;
+        }
+    }
+
+    stats.hidden_skipped = hidden.get();
+    stats.excluded_skipped += excluded.get();
+    stats
+}
+
+/// Записывает результаты в манифест в порядке обхода: результаты из пула потоков
+/// приходят вразнобой и ждут в буфере, пока не будут записаны все предыдущие.
+/// Возвращает ошибки обработки отдельных файлов, а при `fail_fast` - первую из них
+fn write_results(
+    results: Receiver<Outcome>,
+    mut manifest: Option<&mut ManifestWriter>,
+    options: &BackupOptions,
+) -> Result<Vec<FileError>> {
+    let mut pending = BTreeMap::new();
+    let mut next = 0;
+    let mut errors = Vec::new();
+
+    for (index, path, result) in results {
+        pending.insert(index, (path, result));
+        while let Some((path, result)) = pending.remove(&next) {
+            next += 1;
+            match result {
+                Ok(record) => {
+                    if let Some(manifest) = manifest.as_mut() {
+                        manifest.write(&record)?;
+                    }
+                }
+                Err(e) if options.fail_fast => {
+                    return Err(e.context(format!("Failed to process {}", path.display())));
+                }
+                Err(e) => {
+                    if !options.quiet {
+                        eprintln!("Error processing {}: {:#}", path.display(), e);
+                    }
+                    errors.push(FileError::new(path, &e));
+                }
             }
-        }) // Проверка всех компонентов пути
+        }
+    }
+
+    Ok(errors)
+}
+
+fn should_process_file(entry: &DirEntry, filter: &PathFilter) -> bool {
+    filter.matches_file(entry.path())
+}
+
+/// Файл изменился (размер или mtime) за время копирования. Объект сохранен
+/// и соответствует своему хешу, но может не совпадать ни с одним состоянием файла
+#[derive(Debug)]
+struct FileChanged {
+    file: StoredFile,
+}
+
+impl fmt::Display for FileChanged {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "File changed during backup: {}",
+            self.file.record.path.display()
+        )
+    }
 }
 
-fn should_process_file(entry: &DirEntry, extensions: &HashSet<String>) -> bool {
-    directory::has_extension(entry.path(), extensions)
+impl std::error::Error for FileChanged {}
+
+/// Запись манифеста сохраненного файла
+#[derive(Debug)]
+struct StoredFile {
+    record: ManifestEntry,
+    /// Для файла записан новый объект
+    is_new: bool,
+    /// Байты содержимого, записанные в хранилище впервые
+    new_bytes: u64,
+}
+
+/// Сохраняет файл, повторяя попытку, если файл менялся во время копирования.
+/// Если все попытки неудачны, файл отмечается в статистике как несогласованный,
+/// а в снимок попадает последняя сохраненная копия
+fn backup_file(
+    entry: &DirEntry,
+    filter: &PathFilter,
+    repository: &Repository,
+    config: &Config,
+    options: &BackupOptions,
+    cache: &Mutex<HashCache>,
+    stats: &mut BackupStats,
+) -> Result<ManifestEntry> {
+    let path = entry.path();
+    let cache_key = std::path::absolute(path)
+        .with_context(|| format!("Failed to get absolute path for {}", path.display()))?;
+    let file_metadata = fs::metadata(path)
+        .with_context(|| format!("Failed to read metadata: {}", path.display()))?;
+    let stamp = FileStamp::new(&file_metadata);
+
+    // Файл не менялся с прошлого запуска и его объект на месте: читать его не нужно
+    let cached_hash = lock(cache).lookup(&cache_key, &stamp).map(str::to_owned);
+    if let Some(hash_hex) = cached_hash
+        && repository.has_object(&hash_hex)
+    {
+        let relative_path = relative_path(path, filter.root())?;
+        let mut record = ManifestEntry::new(relative_path.to_path_buf(), hash_hex, &file_metadata);
+        record.xattrs = metadata::read_xattrs(path)?;
+        lock(cache).insert(cache_key, stamp, record.hash.clone());
+        stats.cached += 1;
+        stats.add_file(file_metadata.len(), false, 0);
+        return Ok(record);
+    }
+
+    let mut attempt = 0;
+    loop {
+        let changed = match process_file(entry, filter, repository, config, stats) {
+            Ok(StoredFile {
+                record,
+                is_new,
+                new_bytes,
+            }) => {
+                // Хеш кешируется, только если файл не менялся после снятия stamp
+                if record.size == Some(stamp.size)
+                    && record.mtime == stamp.mtime
+                    && record.mtime_nsec == stamp.mtime_nsec
+                {
+                    lock(cache).insert(cache_key, stamp, record.hash.clone());
+                }
+                stats.add_file(record.size.unwrap_or(0), is_new, new_bytes);
+                return Ok(record);
+            }
+            Err(e) => e.downcast::<FileChanged>()?,
+        };
+
+        if attempt >= config.retries {
+            if !options.quiet {
+                eprintln!("Inconsistent {}: {}", entry.path().display(), changed);
+            }
+            stats.inconsistent.push(entry.path().to_path_buf());
+            let StoredFile {
+                record,
+                is_new,
+                new_bytes,
+            } = changed.file;
+            stats.add_file(record.size.unwrap_or(0), is_new, new_bytes);
+            return Ok(record);
+        }
+
+        attempt += 1;
+        if attempt == 1 {
+            stats.retried.push(entry.path().to_path_buf());
+        }
+        if !options.quiet {
+            eprintln!(
+                "File changed during backup, retrying ({}/{}): {}",
+                attempt,
+                config.retries,
+                entry.path().display()
+            );
+        }
+    }
 }
 
+/// Сохраняет файл в хранилище за один проход чтения и возвращает запись для
+/// манифеста снимка. Размер и mtime сравниваются до и после копирования; если они
+/// изменились, возвращается ошибка FileChanged. Прочитанные байты и записанный
+/// объект учитываются в `stats` при каждой попытке. Скрытые файлы не сохраняются.
+/// При `chunking.enabled` файл сохраняется фрагментами
 fn process_file(
+    entry: &DirEntry,
+    filter: &PathFilter,
+    repository: &Repository,
+    config: &Config,
+    stats: &mut BackupStats,
+) -> Result<StoredFile> {
+    let path = entry.path();
+    let relative_path = relative_path(path, filter.root())?;
+    if filter.is_hidden(path) {
+        bail!("Hidden file is not backed up: {}", path.display());
+    }
+    let before = fs::metadata(path)
+        .with_context(|| format!("Failed to read metadata: {}", path.display()))?;
+
+    let stored = if config.chunking.enabled {
+        chunking::store_chunked(path, repository, &config.chunking, config.buffer_size)?
+    } else {
+        directory::store_object(path, repository, config.buffer_size)?
+    };
+    stats.bytes_read += stored.size;
+    if stored.is_new {
+        stats.add_new_object(stored.new_bytes);
+    }
+
+    let after = fs::metadata(path)
+        .with_context(|| format!("Failed to read metadata: {}", path.display()))?;
+
+    // Размер - сколько байт попало в объект, остальные метаданные - после копирования:
+    // у файла, измененного во время копирования, они ближе к сохраненному содержимому
+    let mut record = ManifestEntry::new(relative_path.to_path_buf(), stored.hash, &after);
+    record.size = Some(stored.size);
+    record.xattrs = metadata::read_xattrs(path)?;
+    let file = StoredFile {
+        record,
+        is_new: stored.is_new,
+        new_bytes: stored.new_bytes,
+    };
+
+    if !same_state(&before, &after) {
+        return Err(FileChanged { file }.into());
+    }
+
+    Ok(file)
+}
+
+/// Совпадают ли размер и время модификации файла
+fn same_state(before: &fs::Metadata, after: &fs::Metadata) -> bool {
+    before.len() == after.len() && before.modified().ok() == after.modified().ok()
+}
+
+/// Вычисляет хеш файла (или берет его из кеша) и определяет, нужен ли новый
+/// объект. Ничего не записывает ни в хранилище, ни в кеш
+fn plan_file(
     entry: &DirEntry,
     source_base: &Path,
-    md5_dir: &Path,
-    timestamp_dir: &Path,
-) -> Result<()> {
+    repository: &Repository,
+    config: &Config,
+    cache: &Mutex<HashCache>,
+    planned: &Mutex<HashSet<String>>,
+    stats: &mut BackupStats,
+) -> Result<ManifestEntry> {
+    let path = entry.path();
+    let relative_path = relative_path(path, source_base)?;
+    let cache_key = std::path::absolute(path)
+        .with_context(|| format!("Failed to get absolute path for {}", path.display()))?;
+    let file_metadata = fs::metadata(path)
+        .with_context(|| format!("Failed to read metadata: {}", path.display()))?;
+    let stamp = FileStamp::new(&file_metadata);
+
+    // Фрагменты файла известны, только если он прочитан при сохранении фрагментами
+    let cached_hash = lock(cache).lookup(&cache_key, &stamp).map(str::to_owned);
+    let (hash_hex, chunks) = match cached_hash {
+        Some(hash_hex) => {
+            stats.cached += 1;
+            (hash_hex, Vec::new())
+        }
+        None if config.chunking.enabled => {
+            let planned = chunking::plan_chunks(path, repository.algorithm(), &config.chunking)?;
+            stats.bytes_read += file_metadata.len();
+            planned
+        }
+        None => {
+            let hash_hex = hash::calculate_hash(path, repository.algorithm(), config.buffer_size)?;
+            stats.bytes_read += file_metadata.len();
+            (hash_hex, Vec::new())
+        }
+    };
+
+    let mut planned = planned.lock().unwrap_or_else(PoisonError::into_inner);
+    let is_new = !repository.has_object(&hash_hex) && planned.insert(hash_hex.clone());
+    let new_bytes = match (is_new, chunks.len()) {
+        (false, _) => 0,
+        // Файл из одного фрагмента сохраняется целым объектом
+        (true, 0 | 1) => file_metadata.len(),
+        (true, _) => chunks
+            .into_iter()
+            .filter(|(chunk_hash, _)| {
+                !repository.object_path(chunk_hash).exists() && planned.insert(chunk_hash.clone())
+            })
+            .map(|(_, size)| size)
+            .sum(),
+    };
+    drop(planned);
+
+    if is_new {
+        stats.add_new_object(new_bytes);
+    }
+    stats.add_file(file_metadata.len(), is_new, new_bytes);
+
+    Ok(ManifestEntry::new(
+        relative_path.to_path_buf(),
+        hash_hex,
+        &file_metadata,
+    ))
+}
+
+/// Возвращает запись манифеста о символической ссылке без перехода по ней
+fn process_symlink(entry: &DirEntry, source_base: &Path) -> Result<ManifestEntry> {
     let path = entry.path();
-    let md5_hex = hash::calculate_md5(path)?;
-    let _full_md5_path = directory::handle_md5_copy(path, md5_dir, &md5_hex)?;
-    directory::create_timestamp_record(path, source_base, timestamp_dir, &md5_hex)?;
-    Ok(())
+    let relative_path = relative_path(path, source_base)?;
+    let link_target =
+        fs::read_link(path).with_context(|| format!("Failed to read link: {}", path.display()))?;
+    let link_metadata = fs::symlink_metadata(path)
+        .with_context(|| format!("Failed to read metadata: {}", path.display()))?;
+
+    Ok(ManifestEntry::symlink(
+        relative_path.to_path_buf(),
+        link_target,
+        &link_metadata,
+    ))
+}
+
+fn relative_path<'a>(path: &'a Path, source_base: &Path) -> Result<&'a Path> {
+    path.strip_prefix(source_base).with_context(|| {
+        format!(
+            "Failed to get relative path for {} from base {}",
+            path.display(),
+            source_base.display()
+        )
+    })
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::collections::HashSet;
     use std::fs::{self};
+    use std::io::Write;
     use std::path::PathBuf;
-    use tempfile::{NamedTempFile, TempDir};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use tempfile::TempDir;
     use walkdir::WalkDir;
 
+    // Пути из манифеста единственного снимка в target_dir
+    fn snapshot_paths(target_dir: &Path) -> Vec<PathBuf> {
+        let repository = Repository::open(target_dir.to_str().unwrap()).unwrap();
+        let snapshot_dir = fs::read_dir(target_dir)
+            .unwrap()
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .find(|p| p.join(crate::snapshot::MANIFEST_FILE).exists())
+            .unwrap();
+
+        let mut paths: Vec<PathBuf> =
+            crate::snapshot::read_snapshot(&snapshot_dir, repository.algorithm())
+                .unwrap()
+                .into_iter()
+                .map(|e| e.path)
+                .collect();
+        paths.sort();
+        paths
+    }
+
     // Вспомогательная функция для создания тестовой DirEntry
     fn create_dir_entry(path: &Path) -> walkdir::DirEntry {
         WalkDir::new(path.parent().unwrap())
@@ -82,15 +835,28 @@ mod tests {
             .unwrap_or_else(|| panic!("Failed to create DirEntry for {}", path.display()))
     }
 
+    // Вспомогательная функция для создания конфигурации с заданными расширениями
+    fn test_config(extensions: &[&str]) -> Config {
+        Config {
+            extensions: extensions.iter().map(|&s| s.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    // Фильтр исходной директории с настройками по умолчанию
+    fn source_filter(source_dir: &Path) -> PathFilter {
+        PathFilter::new(source_dir, &Config::default()).unwrap()
+    }
+
     // Вспомогательная функция для создания тестовой структуры
-    fn create_test_environment() -> (TempDir, PathBuf, HashSet<String>) {
+    fn create_test_environment() -> (TempDir, PathBuf, Config) {
         let temp_dir = TempDir::new().unwrap();
         let source_dir = temp_dir.path().join("source");
         fs::create_dir(&source_dir).unwrap();
 
-        let extensions: HashSet<String> = ["txt", "jpg"].iter().map(|&s| s.to_string()).collect();
+        let config = test_config(&["txt", "jpg"]);
 
-        (temp_dir, source_dir, extensions)
+        (temp_dir, source_dir, config)
     }
 
     #[test]
@@ -104,20 +870,404 @@ mod tests {
         fs::write(source_dir.join("file2.jpg"), "content").unwrap();
         fs::write(source_dir.join("ignore.pdf"), "content").unwrap();
 
-        let extensions: HashSet<String> = ["txt", "jpg"].iter().map(|&s| s.to_string()).collect();
+        let config = test_config(&["txt", "jpg"]);
         let target_dir = temp_dir.path().join("target");
 
         let result = process_files_with_extensions(
             source_dir.to_str().unwrap(),
             target_dir.to_str().unwrap(),
-            &extensions,
+            &config,
+            &BackupOptions::default(),
         );
 
         assert!(result.is_ok());
         assert!(target_dir.join("files_by_md5").exists());
     }
 
-    #[ignore]
+    #[test]
+    fn test_hash_cache_skips_unchanged_files() {
+        let temp_dir = TempDir::new().unwrap();
+        let source_dir = temp_dir.path().join("source");
+        let target_dir = temp_dir.path().join("target");
+        fs::create_dir(&source_dir).unwrap();
+        fs::write(source_dir.join("a.txt"), "content").unwrap();
+        fs::write(source_dir.join("b.txt"), "other").unwrap();
+
+        let config = test_config(&["txt"]);
+        let run = |options: &BackupOptions| {
+            process_files_with_extensions(
+                source_dir.to_str().unwrap(),
+                target_dir.to_str().unwrap(),
+                &config,
+                options,
+            )
+            .unwrap()
+        };
+
+        assert_eq!(run(&BackupOptions::default()).cached, 0);
+        assert_eq!(run(&BackupOptions::default()).cached, 2);
+
+        // Измененный файл и файл с удаленным объектом читаются заново
+        fs::write(source_dir.join("a.txt"), "changed content").unwrap();
+        fs::remove_file(
+            target_dir
+                .join("files_by_md5/79")
+                .join("795f3202b17cb6bc3d4b771d8c6c9eaf"),
+        )
+        .unwrap();
+        assert_eq!(run(&BackupOptions::default()).cached, 0);
+        assert!(target_dir
+            .join("files_by_md5/79")
+            .join("795f3202b17cb6bc3d4b771d8c6c9eaf")
+            .exists());
+
+        let rehash = BackupOptions {
+            rehash: true,
+            ..Default::default()
+        };
+        assert_eq!(run(&rehash).cached, 0);
+        assert_eq!(run(&BackupOptions::default()).cached, 2);
+
+        // Поврежденный кеш и его удаление попадают в статистику запуска
+        fs::write(target_dir.join(cache::CACHE_FILE), "broken").unwrap();
+        let quiet = BackupOptions {
+            quiet: true,
+            ..Default::default()
+        };
+        let stats = run(&quiet);
+        assert_eq!(stats.cached, 0);
+        assert!(!stats.cache_cleared);
+        let cache_error = stats.cache_error.unwrap();
+        assert_eq!(cache_error.path, target_dir.join(cache::CACHE_FILE));
+        assert!(cache_error.messages[0].contains("Failed to parse hash cache"));
+
+        let stats = run(&BackupOptions {
+            clear_cache: true,
+            ..quiet
+        });
+        assert!(stats.cache_cleared);
+        assert!(stats.cache_error.is_none());
+    }
+
+    #[test]
+    fn test_backup_summary() {
+        let temp_dir = TempDir::new().unwrap();
+        let source_dir = temp_dir.path().join("source");
+        let target_dir = temp_dir.path().join("target");
+        fs::create_dir_all(source_dir.join(".git")).unwrap();
+        fs::write(source_dir.join(".git/config.txt"), "hidden").unwrap();
+        fs::write(source_dir.join("a.txt"), "same").unwrap();
+        fs::write(source_dir.join("b.txt"), "same").unwrap();
+        fs::write(source_dir.join("c.txt"), "other").unwrap();
+        fs::write(source_dir.join("ignore.pdf"), "content").unwrap();
+
+        let stats = process_files_with_extensions(
+            source_dir.to_str().unwrap(),
+            target_dir.to_str().unwrap(),
+            &test_config(&["txt"]),
+            &BackupOptions::default(),
+        )
+        .unwrap();
+
+        assert_eq!(stats.scanned, 4);
+        assert_eq!(stats.matched, 3);
+        assert_eq!(stats.hidden_skipped, 1);
+        assert_eq!(stats.extension_skipped, 1);
+        assert!(stats.errors.is_empty());
+        assert_eq!(stats.files, 3);
+        assert_eq!(stats.new_objects, 2);
+        assert_eq!(stats.new_bytes, 9);
+        assert_eq!(stats.duplicates, 1);
+        assert_eq!(stats.deduplicated_bytes, 4);
+        assert_eq!(stats.bytes_read, 13);
+
+        // Итоги сохраняются в директорию снимка
+        let snapshot = stats.snapshot.as_deref().unwrap();
+        let summary =
+            fs::read_to_string(target_dir.join(snapshot).join(crate::summary::SUMMARY_FILE))
+                .unwrap();
+        assert!(summary.starts_with(&format!("Snapshot: {}\n", snapshot)));
+        assert!(summary.contains("New objects: 2 (9 bytes stored)\n"));
+
+        let json = serde_json::to_value(&stats).unwrap();
+        assert_eq!(json["new_objects"], 2);
+        assert_eq!(json["errors"], serde_json::json!([]));
+        assert!(json["elapsed_secs"].is_f64());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_file_errors_and_fail_fast() {
+        let temp_dir = TempDir::new().unwrap();
+        let source_dir = temp_dir.path().join("source");
+        let target_dir = temp_dir.path().join("target");
+        fs::create_dir(&source_dir).unwrap();
+        fs::write(source_dir.join("a.txt"), "content").unwrap();
+        // Цикл ссылок при follow дает ошибку обхода
+        std::os::unix::fs::symlink(".", source_dir.join("loop")).unwrap();
+
+        let config = Config {
+            symlinks: SymlinkPolicy::Follow,
+            ..test_config(&["txt"])
+        };
+        let run = |fail_fast: bool| {
+            process_files_with_extensions(
+                source_dir.to_str().unwrap(),
+                target_dir.to_str().unwrap(),
+                &config,
+                &BackupOptions {
+                    fail_fast,
+                    ..Default::default()
+                },
+            )
+        };
+
+        // Без --fail-fast ошибка учитывается, остальные файлы сохраняются
+        let stats = run(false).unwrap();
+        assert_eq!(stats.errors.len(), 1);
+        assert_eq!(stats.errors[0].path, source_dir.join("loop"));
+        assert!(!stats.errors[0].messages.is_empty());
+        assert_eq!(stats.files, 1);
+
+        // С --fail-fast запуск прерывается, незавершенный снимок удаляется
+        assert!(run(true).is_err());
+        let repository = Repository::open(target_dir.to_str().unwrap()).unwrap();
+        assert_eq!(repository.list_snapshots().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_locked_repository() {
+        let temp_dir = TempDir::new().unwrap();
+        let source_dir = temp_dir.path().join("source");
+        let target_dir = temp_dir.path().join("target");
+        fs::create_dir(&source_dir).unwrap();
+        fs::write(source_dir.join("a.txt"), "content").unwrap();
+
+        let repository = Repository::open_or_init(target_dir.to_str().unwrap(), None).unwrap();
+        HashCache::empty(&repository).save().unwrap();
+        let _lock = repository.lock().unwrap();
+
+        let error = process_files_with_extensions(
+            source_dir.to_str().unwrap(),
+            target_dir.to_str().unwrap(),
+            &test_config(&["txt"]),
+            &BackupOptions {
+                clear_cache: true,
+                ..Default::default()
+            },
+        )
+        .unwrap_err();
+
+        assert!(error.is::<crate::repository::RepositoryLocked>());
+        assert!(repository.list_snapshots().unwrap().is_empty());
+        // Кеш идущего запуска не удаляется
+        assert!(target_dir.join(cache::CACHE_FILE).exists());
+    }
+
+    #[test]
+    fn test_include_exclude_patterns() {
+        let temp_dir = TempDir::new().unwrap();
+        let source_dir = temp_dir.path().join("source");
+        let target_dir = temp_dir.path().join("target");
+        fs::create_dir_all(source_dir.join("web/node_modules/pkg")).unwrap();
+        fs::write(source_dir.join("web/node_modules/a.txt"), "a").unwrap();
+        fs::write(source_dir.join("web/node_modules/pkg/b.txt"), "b").unwrap();
+        fs::write(source_dir.join("web/index.txt"), "index").unwrap();
+        fs::write(source_dir.join("Makefile"), "all:").unwrap();
+        fs::write(source_dir.join("notes.txt"), "notes").unwrap();
+        fs::write(source_dir.join("scratch.txt"), "scratch").unwrap();
+
+        let config = Config {
+            include: vec!["Makefile".to_string()],
+            exclude: vec!["node_modules/".to_string(), "/scratch.txt".to_string()],
+            ..test_config(&["txt"])
+        };
+        let stats = process_files_with_extensions(
+            source_dir.to_str().unwrap(),
+            target_dir.to_str().unwrap(),
+            &config,
+            &BackupOptions::default(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            snapshot_paths(&target_dir),
+            vec![
+                PathBuf::from("Makefile"),
+                PathBuf::from("notes.txt"),
+                PathBuf::from("web/index.txt"),
+            ]
+        );
+        // Исключенная директория не обходится: ее файлы не попадают даже в scanned
+        assert_eq!(stats.excluded_skipped, 2);
+        assert_eq!(stats.scanned, 4);
+    }
+
+    #[test]
+    fn test_dry_run_writes_nothing() {
+        let temp_dir = TempDir::new().unwrap();
+        let source_dir = temp_dir.path().join("source");
+        let target_dir = temp_dir.path().join("target");
+        fs::create_dir(&source_dir).unwrap();
+        fs::write(source_dir.join("a.txt"), "same").unwrap();
+        fs::write(source_dir.join("b.txt"), "same").unwrap();
+        fs::write(source_dir.join("c.txt"), "other").unwrap();
+        fs::write(source_dir.join("ignore.pdf"), "content").unwrap();
+
+        let config = test_config(&["txt"]);
+        let dry_run = BackupOptions {
+            dry_run: true,
+            ..Default::default()
+        };
+        let run = |options: &BackupOptions| {
+            process_files_with_extensions(
+                source_dir.to_str().unwrap(),
+                target_dir.to_str().unwrap(),
+                &config,
+                options,
+            )
+            .unwrap()
+        };
+
+        // Пустое хранилище: одинаковые файлы дают один объект
+        let stats = run(&dry_run);
+        assert_eq!(stats.files, 3);
+        assert_eq!(stats.new_objects, 2);
+        assert_eq!(stats.new_bytes, 9);
+        assert_eq!(stats.deduplicated_bytes, 4);
+        assert!(!target_dir.exists());
+
+        // После настоящего запуска новым остается только добавленный файл
+        run(&BackupOptions::default());
+        fs::write(source_dir.join("d.txt"), "new!").unwrap();
+        let stats = run(&dry_run);
+        assert_eq!(stats.files, 4);
+        assert_eq!(stats.new_objects, 1);
+        assert_eq!(stats.new_bytes, 4);
+        assert_eq!(stats.deduplicated_bytes, 13);
+
+        let repository = Repository::open(target_dir.to_str().unwrap()).unwrap();
+        assert_eq!(repository.list_snapshots().unwrap().len(), 1);
+        assert_eq!(repository.list_objects().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_chunked_backup_of_appended_file() {
+        let temp_dir = TempDir::new().unwrap();
+        let source_dir = temp_dir.path().join("source");
+        let target_dir = temp_dir.path().join("target");
+        fs::create_dir(&source_dir).unwrap();
+        let log = source_dir.join("app.log");
+        let mut content: String = (0..2000).map(|i| format!("line {}\n", i)).collect();
+        fs::write(&log, &content).unwrap();
+
+        let config = Config {
+            chunking: chunking::ChunkingConfig {
+                enabled: true,
+                min_size: 64,
+                avg_size: 256,
+                max_size: 1024,
+            },
+            ..test_config(&["log"])
+        };
+        let run = |options: &BackupOptions| {
+            process_files_with_extensions(
+                source_dir.to_str().unwrap(),
+                target_dir.to_str().unwrap(),
+                &config,
+                options,
+            )
+            .unwrap()
+        };
+
+        let stats = run(&BackupOptions::default());
+        assert_eq!(stats.new_objects, 1);
+        assert_eq!(stats.new_bytes, content.len() as u64);
+
+        // Дописанная строка добавляет только последние фрагменты, и dry-run
+        // оценивает их так же, как настоящий запуск
+        content.push_str("appended\n");
+        fs::write(&log, &content).unwrap();
+        let planned = run(&BackupOptions {
+            dry_run: true,
+            ..Default::default()
+        });
+        let stats = run(&BackupOptions::default());
+
+        assert_eq!(stats.new_objects, 1);
+        assert!(stats.new_bytes < 2 * 1024);
+        assert_eq!(
+            stats.deduplicated_bytes,
+            content.len() as u64 - stats.new_bytes
+        );
+        assert_eq!(planned.new_bytes, stats.new_bytes);
+        assert_eq!(planned.deduplicated_bytes, stats.deduplicated_bytes);
+
+        let repository = Repository::open(target_dir.to_str().unwrap()).unwrap();
+        assert_eq!(repository.list_chunk_lists().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_parallel_backup_with_duplicate_content() {
+        let temp_dir = TempDir::new().unwrap();
+        let source_dir = temp_dir.path().join("source");
+        let target_dir = temp_dir.path().join("target");
+        for dir in 0..4 {
+            fs::create_dir_all(source_dir.join(format!("dir{}", dir))).unwrap();
+            for file in 0..25 {
+                // Половина файлов с одинаковым содержимым: потоки пишут один объект
+                let content = if file % 2 == 0 {
+                    "same".to_string()
+                } else {
+                    format!("file {} {}", dir, file)
+                };
+                fs::write(source_dir.join(format!("dir{}/{}.txt", dir, file)), content).unwrap();
+            }
+        }
+
+        let run = |jobs: usize| {
+            process_files_with_extensions(
+                source_dir.to_str().unwrap(),
+                target_dir.to_str().unwrap(),
+                &test_config(&["txt"]),
+                &BackupOptions {
+                    rehash: true,
+                    jobs: Some(jobs),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+
+            // Последний снимок в порядке записей манифеста
+            let repository = Repository::open(target_dir.to_str().unwrap()).unwrap();
+            let snapshot = repository.list_snapshots().unwrap().pop().unwrap();
+            crate::snapshot::read_snapshot(
+                &repository.snapshot_dir(&snapshot),
+                repository.algorithm(),
+            )
+            .unwrap()
+            .into_iter()
+            .map(|e| e.path)
+            .collect::<Vec<_>>()
+        };
+
+        let parallel = run(8);
+        assert_eq!(parallel.len(), 100);
+        // Порядок манифеста не зависит от числа потоков
+        assert_eq!(run(1), parallel);
+
+        let repository = Repository::open(target_dir.to_str().unwrap()).unwrap();
+        assert_eq!(repository.list_objects().unwrap().len(), 49);
+        // Временные файлы не остаются в хранилище
+        assert!(!WalkDir::new(repository.objects_dir())
+            .into_iter()
+            .filter_map(|e| e.ok())
+            .any(|e| e
+                .file_name()
+                .to_string_lossy()
+                .starts_with(directory::TEMP_OBJECT_PREFIX)));
+    }
+
     #[test]
     fn test_process_files_with_extensions_hidden() {
         let temp_dir = TempDir::new().unwrap();
@@ -128,13 +1278,14 @@ mod tests {
         fs::write(source_dir.join(".hidden.txt"), "content").unwrap();
         fs::write(source_dir.join("visible.txt"), "content").unwrap();
 
-        let extensions: HashSet<String> = ["txt"].iter().map(|&s| s.to_string()).collect();
+        let config = test_config(&["txt"]);
         let target_dir = temp_dir.path().join("target");
 
         let result = process_files_with_extensions(
             source_dir.to_str().unwrap(),
             target_dir.to_str().unwrap(),
-            &extensions,
+            &config,
+            &BackupOptions::default(),
         );
 
         assert!(result.is_ok());
@@ -155,23 +1306,117 @@ mod tests {
         assert_eq!(processed_files, 1);
     }
 
-    #[ignore]
+    #[test]
+    fn test_multiple_sources() {
+        let temp_dir = TempDir::new().unwrap();
+        let base_dir = temp_dir.path().join("root");
+        let photos_dir = temp_dir.path().join("photos");
+        let target_dir = temp_dir.path().join("target");
+        fs::create_dir_all(base_dir.join("home/user")).unwrap();
+        fs::create_dir_all(&photos_dir).unwrap();
+        fs::write(base_dir.join("home/user/notes.txt"), "same").unwrap();
+        fs::write(photos_dir.join("copy.txt"), "same").unwrap();
+
+        let source = |name: &str, path: &Path| Source {
+            name: name.to_string(),
+            path: path.to_path_buf(),
+        };
+        let config = Config {
+            // Относительный путь отсчитывается от source_dir, абсолютный берется как есть
+            sources: vec![
+                source("home", Path::new("home")),
+                source("pics", &photos_dir),
+            ],
+            ..test_config(&["txt"])
+        };
+
+        let stats = process_files_with_extensions(
+            base_dir.to_str().unwrap(),
+            target_dir.to_str().unwrap(),
+            &config,
+            &BackupOptions::default(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            snapshot_paths(&target_dir),
+            vec![
+                PathBuf::from("home/user/notes.txt"),
+                PathBuf::from("pics/copy.txt")
+            ]
+        );
+        // Источники делят одно хранилище объектов
+        assert_eq!(stats.new_objects, 1);
+        assert_eq!(stats.duplicates, 1);
+
+        let config = Config {
+            sources: vec![source("missing", Path::new("missing"))],
+            ..config
+        };
+        let error = process_files_with_extensions(
+            base_dir.to_str().unwrap(),
+            target_dir.to_str().unwrap(),
+            &config,
+            &BackupOptions::default(),
+        )
+        .unwrap_err();
+        assert!(format!("{:#}", error).contains("Invalid source: missing"));
+    }
+
+    #[test]
+    fn test_hidden_policy() {
+        let temp_dir = TempDir::new().unwrap();
+        let source_dir = temp_dir.path().join(".local/share");
+        fs::create_dir_all(source_dir.join(".config")).unwrap();
+        fs::create_dir_all(source_dir.join(".cache")).unwrap();
+        fs::write(source_dir.join(".config/app.txt"), "settings").unwrap();
+        fs::write(source_dir.join(".cache/data.txt"), "cache").unwrap();
+        fs::write(source_dir.join("photo.txt"), "photo").unwrap();
+
+        let run = |config: &Config, target: &str| {
+            let target_dir = temp_dir.path().join(target);
+            process_files_with_extensions(
+                source_dir.to_str().unwrap(),
+                target_dir.to_str().unwrap(),
+                config,
+                &BackupOptions::default(),
+            )
+            .unwrap();
+            snapshot_paths(&target_dir)
+        };
+
+        let config = Config {
+            hidden_allow: vec![".config".to_string()],
+            ..test_config(&["txt"])
+        };
+        assert_eq!(
+            run(&config, "allow"),
+            vec![PathBuf::from(".config/app.txt"), PathBuf::from("photo.txt")]
+        );
+
+        let config = Config {
+            hidden: crate::config::HiddenPolicy::Include,
+            ..test_config(&["txt"])
+        };
+        assert_eq!(run(&config, "include").len(), 3);
+    }
+
     #[test]
     fn test_should_process_file() {
-        let temp_file = NamedTempFile::new().unwrap();
-        let path = temp_file.path().with_extension("txt");
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("file.txt");
         fs::write(&path, "content").unwrap();
 
-        let extensions: HashSet<String> = ["txt", "jpg"].iter().map(|&s| s.to_string()).collect();
+        let filter = PathFilter::new(temp_dir.path(), &test_config(&["txt", "jpg"])).unwrap();
         let entry = create_dir_entry(&path);
 
-        assert!(should_process_file(&entry, &extensions));
+        assert!(should_process_file(&entry, &filter));
 
-        let hidden_path = temp_file.path().with_file_name(".hidden.txt");
+        let hidden_path = temp_dir.path().join(".hidden.txt");
         fs::write(&hidden_path, "content").unwrap();
         let hidden_entry = create_dir_entry(&hidden_path);
 
-        assert!(!should_process_file(&hidden_entry, &extensions));
+        assert!(!should_process_file(&hidden_entry, &filter));
     }
 
     #[test]
@@ -183,35 +1428,55 @@ mod tests {
         let test_file = source_dir.join("test.txt");
         fs::write(&test_file, "content").unwrap();
 
-        let md5_dir = temp_dir.path().join("md5");
-        let timestamp_dir = temp_dir.path().join("timestamp");
-        fs::create_dir_all(&md5_dir).unwrap();
-        fs::create_dir_all(&timestamp_dir).unwrap();
+        let repository =
+            Repository::open_or_init(temp_dir.path().join("target").to_str().unwrap(), None)
+                .unwrap();
+        let md5_dir = repository.objects_dir();
 
         let entry = create_dir_entry(&test_file);
-        let result = process_file(&entry, &source_dir, &md5_dir, &timestamp_dir);
+        let mut stats = BackupStats::default();
+        let result = process_file(
+            &entry,
+            &source_filter(&source_dir),
+            &repository,
+            &Config::default(),
+            &mut stats,
+        );
 
         assert!(result.is_ok());
+        let file = result.unwrap();
+        assert!(file.is_new);
+        assert_eq!(stats.new_objects, 1);
+        assert_eq!(stats.bytes_read, 7);
+        let record = file.record;
+        assert_eq!(record.path, PathBuf::from("test.txt"));
+        assert_eq!(record.hash, "9a0364b9e99bb480dd25e1f0284c8555");
+        assert_eq!(record.size, Some(7));
         assert!(md5_dir
             .join("9a")
             .join("9a0364b9e99bb480dd25e1f0284c8555")
             .exists());
     }
 
-    #[ignore]
     #[test]
     fn test_process_file_hidden() {
         let (temp_dir, source_dir, _) = create_test_environment();
         let hidden_file = source_dir.join(".hidden.txt");
         fs::write(&hidden_file, "content").unwrap();
 
+        let repository = Repository::open_or_init(temp_dir.path().to_str().unwrap(), None).unwrap();
         let entry = create_dir_entry(&hidden_file);
-        let result = process_file(&entry, &source_dir, temp_dir.path(), temp_dir.path());
+        let result = process_file(
+            &entry,
+            &source_filter(&source_dir),
+            &repository,
+            &Config::default(),
+            &mut BackupStats::default(),
+        );
 
         assert!(result.is_err()); // Должно вернуть ошибку для скрытых файлов
     }
 
-    #[ignore]
     #[test]
     fn test_is_hidden_detection() {
         let temp_dir = TempDir::new().unwrap();
@@ -236,31 +1501,263 @@ mod tests {
             .find(|e| e.path() == normal_file)
             .unwrap();
 
-        assert!(is_hidden(&hidden_entry));
-        assert!(!is_hidden(&normal_entry));
+        // Имя временной директории начинается с точки, но проверяются только
+        // компоненты пути внутри исходной директории
+        let filter = source_filter(temp_dir.path());
+        assert!(filter.is_hidden(hidden_entry.path()));
+        assert!(!filter.is_hidden(normal_entry.path()));
+    }
+
+    #[test]
+    fn test_same_state_detects_changes() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("growing.log");
+        fs::write(&path, "first").unwrap();
+        let before = fs::metadata(&path).unwrap();
+
+        assert!(same_state(&before, &fs::metadata(&path).unwrap()));
+
+        fs::write(&path, "first second").unwrap();
+        assert!(!same_state(&before, &fs::metadata(&path).unwrap()));
+    }
+
+    #[test]
+    fn test_file_growing_during_copy() {
+        let temp_dir = TempDir::new().unwrap();
+        let source_dir = temp_dir.path().join("source");
+        fs::create_dir(&source_dir).unwrap();
+        let path = source_dir.join("growing.log");
+        fs::write(&path, vec![b'a'; 8 * 1024 * 1024]).unwrap();
+        let repository =
+            Repository::open_or_init(temp_dir.path().join("target").to_str().unwrap(), None)
+                .unwrap();
+        let entry = create_dir_entry(&path);
+
+        // Файл дописывается, пока он копируется в хранилище
+        let done = AtomicBool::new(false);
+        let result = thread::scope(|scope| {
+            scope.spawn(|| {
+                let mut file = fs::OpenOptions::new().append(true).open(&path).unwrap();
+                while !done.load(Ordering::Relaxed) {
+                    file.write_all(&[b'b'; 1024]).unwrap();
+                    thread::sleep(Duration::from_millis(1));
+                }
+            });
+            let result = process_file(
+                &entry,
+                &source_filter(&source_dir),
+                &repository,
+                &Config::default(),
+                &mut BackupStats::default(),
+            );
+            done.store(true, Ordering::Relaxed);
+            result
+        });
+
+        let changed = result.unwrap_err().downcast::<FileChanged>().unwrap();
+        let record = changed.file.record;
+        // Размер в манифесте совпадает с содержимым объекта, а не с файлом до копирования
+        let object_size = fs::metadata(repository.object_path(&record.hash))
+            .unwrap()
+            .len();
+        assert_eq!(record.size, Some(object_size));
+        assert!(object_size > 8 * 1024 * 1024);
+    }
+
+    #[test]
+    fn test_process_file_sha256_repository() {
+        let temp_dir = TempDir::new().unwrap();
+        let source_dir = temp_dir.path().join("source");
+        fs::create_dir(&source_dir).unwrap();
+
+        let test_file = source_dir.join("test.txt");
+        fs::write(&test_file, "content").unwrap();
+
+        let repository = Repository::open_or_init(
+            temp_dir.path().join("target").to_str().unwrap(),
+            Some(hash::HashAlgorithm::Sha256),
+        )
+        .unwrap();
+
+        let entry = create_dir_entry(&test_file);
+        let record = process_file(
+            &entry,
+            &source_filter(&source_dir),
+            &repository,
+            &Config::default(),
+            &mut BackupStats::default(),
+        )
+        .unwrap()
+        .record;
+
+        let sha256_hex = "ed7002b439e9ac845f22357d822bac1444730fbdb6016d3ec9432297b9ec9f73";
+        assert!(temp_dir
+            .path()
+            .join("target/files_by_sha256/ed")
+            .join(sha256_hex)
+            .exists());
+        assert_eq!(record.hash, sha256_hex);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_symlinks_skipped_by_default() {
+        let temp_dir = TempDir::new().unwrap();
+        let source_dir = temp_dir.path().join("source");
+        fs::create_dir(&source_dir).unwrap();
+        fs::write(source_dir.join("a.txt"), "content").unwrap();
+        std::os::unix::fs::symlink("a.txt", source_dir.join("link.txt")).unwrap();
+
+        let target_dir = temp_dir.path().join("target");
+        let stats = process_files_with_extensions(
+            source_dir.to_str().unwrap(),
+            target_dir.to_str().unwrap(),
+            &test_config(&["txt"]),
+            &BackupOptions::default(),
+        )
+        .unwrap();
+
+        assert_eq!(stats.symlinks_skipped, 1);
+        assert_eq!(stats.skipped_symlinks, vec![source_dir.join("link.txt")]);
+        assert_eq!(stats.symlinks_recorded, 0);
+        assert_eq!(snapshot_paths(&target_dir), vec![PathBuf::from("a.txt")]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_directory_symlinks_follow_policy() {
+        let temp_dir = TempDir::new().unwrap();
+        let source_dir = temp_dir.path().join("source");
+        fs::create_dir_all(source_dir.join("docs")).unwrap();
+        fs::write(source_dir.join("docs/a.txt"), "content").unwrap();
+        std::os::unix::fs::symlink("docs", source_dir.join("docs_link")).unwrap();
+        std::os::unix::fs::symlink("docs/a.txt", source_dir.join("a.bin")).unwrap();
+
+        // skip: о ссылке на директорию сообщается, а не считается пропуском по расширению
+        let skip_target = temp_dir.path().join("skip");
+        let stats = process_files_with_extensions(
+            source_dir.to_str().unwrap(),
+            skip_target.to_str().unwrap(),
+            &test_config(&["txt"]),
+            &BackupOptions::default(),
+        )
+        .unwrap();
+
+        let mut skipped = stats.skipped_symlinks;
+        skipped.sort();
+        assert_eq!(stats.symlinks_skipped, 2);
+        assert_eq!(
+            skipped,
+            vec![source_dir.join("a.bin"), source_dir.join("docs_link")]
+        );
+        assert_eq!(stats.extension_skipped, 0);
+        assert_eq!(
+            snapshot_paths(&skip_target),
+            vec![PathBuf::from("docs/a.txt")]
+        );
+
+        // record: ссылка на директорию сохраняется независимо от расширений,
+        // ссылка на файл по-прежнему проходит фильтр по расширению
+        let record_target = temp_dir.path().join("record");
+        let config = Config {
+            symlinks: SymlinkPolicy::Record,
+            ..test_config(&["txt"])
+        };
+        let stats = process_files_with_extensions(
+            source_dir.to_str().unwrap(),
+            record_target.to_str().unwrap(),
+            &config,
+            &BackupOptions::default(),
+        )
+        .unwrap();
+
+        assert_eq!(stats.symlinks_recorded, 1);
+        assert_eq!(stats.extension_skipped, 1);
+        assert_eq!(
+            snapshot_paths(&record_target),
+            vec![PathBuf::from("docs/a.txt"), PathBuf::from("docs_link")]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_symlinks_followed_with_loop_detection() {
+        let temp_dir = TempDir::new().unwrap();
+        let source_dir = temp_dir.path().join("source");
+        fs::create_dir(&source_dir).unwrap();
+        fs::write(source_dir.join("a.txt"), "content").unwrap();
+        std::os::unix::fs::symlink("a.txt", source_dir.join("alias.txt")).unwrap();
+        // Ссылка на саму исходную директорию образует цикл
+        std::os::unix::fs::symlink(".", source_dir.join("loop")).unwrap();
+
+        let target_dir = temp_dir.path().join("target");
+        let config = Config {
+            symlinks: SymlinkPolicy::Follow,
+            ..test_config(&["txt"])
+        };
+        let stats = process_files_with_extensions(
+            source_dir.to_str().unwrap(),
+            target_dir.to_str().unwrap(),
+            &config,
+            &BackupOptions::default(),
+        )
+        .unwrap();
+
+        assert_eq!(stats.symlinks_skipped, 0);
+        assert_eq!(
+            snapshot_paths(&target_dir),
+            vec![PathBuf::from("a.txt"), PathBuf::from("alias.txt")]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_special_files_skipped_and_counted() {
+        let temp_dir = TempDir::new().unwrap();
+        let source_dir = temp_dir.path().join("source");
+        fs::create_dir(&source_dir).unwrap();
+        fs::write(source_dir.join("a.txt"), "content").unwrap();
+        let _socket =
+            std::os::unix::net::UnixListener::bind(source_dir.join("socket.txt")).unwrap();
+
+        let target_dir = temp_dir.path().join("target");
+        let stats = process_files_with_extensions(
+            source_dir.to_str().unwrap(),
+            target_dir.to_str().unwrap(),
+            &test_config(&["txt"]),
+            &BackupOptions::default(),
+        )
+        .unwrap();
+
+        assert_eq!(stats.special_files_skipped, 1);
+        assert_eq!(snapshot_paths(&target_dir), vec![PathBuf::from("a.txt")]);
     }
 
     #[test]
     fn test_empty_source_directory() {
-        let (temp_dir, source_dir, extensions) = create_test_environment();
+        let (temp_dir, source_dir, config) = create_test_environment();
         let target_dir = temp_dir.path().join("target");
 
         let result = process_files_with_extensions(
             source_dir.to_str().unwrap(),
             target_dir.to_str().unwrap(),
-            &extensions,
+            &config,
+            &BackupOptions::default(),
         );
 
         assert!(result.is_ok());
         assert!(target_dir.exists());
     }
 
-    #[ignore]
     #[test]
     fn test_invalid_source_directory() {
-        let extensions: HashSet<String> = ["txt"].iter().map(|&s| s.to_string()).collect();
-        let result =
-            process_files_with_extensions("/nonexistent/directory", "/tmp/target", &extensions);
+        let config = test_config(&["txt"]);
+        let result = process_files_with_extensions(
+            "/nonexistent/directory",
+            "/tmp/target",
+            &config,
+            &BackupOptions::default(),
+        );
 
         assert!(result.is_err());
     }
@@ -0,0 +1,194 @@
+use crate::config::{Config, HiddenPolicy};
+use crate::directory;
+use anyhow::{Context, Result};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use std::collections::HashSet;
+use std::path::{Component, Path, PathBuf};
+
+/// Отбор записей исходной директории: скрытые записи, расширения и шаблоны include/exclude.
+/// Шаблоны задаются относительно исходной директории в синтаксисе .gitignore:
+/// `/` в начале привязывает шаблон к корню, `/` в конце - только к директориям,
+/// `**` совпадает с любым числом директорий, `!` отменяет предыдущие совпадения
+/// (при совпадении нескольких шаблонов действует последний)
+#[derive(Debug)]
+pub struct PathFilter {
+    root: PathBuf,
+    extensions: HashSet<String>,
+    include: Gitignore,
+    exclude: Gitignore,
+    hidden: HiddenPolicy,
+    hidden_allow: HashSet<String>,
+}
+
+impl PathFilter {
+    pub fn new(source_dir: &Path, config: &Config) -> Result<Self> {
+        Ok(Self {
+            root: source_dir.to_path_buf(),
+            extensions: config.extensions.clone(),
+            include: build_patterns(source_dir, &config.include)
+                .context("Invalid include pattern")?,
+            exclude: build_patterns(source_dir, &config.exclude)
+                .context("Invalid exclude pattern")?,
+            hidden: config.hidden,
+            hidden_allow: config.hidden_allow.iter().cloned().collect(),
+        })
+    }
+
+    /// Исходная директория, относительно которой проверяются пути
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Путь пропускается как скрытый: один из его компонентов относительно
+    /// исходной директории начинается с точки и не перечислен в `hidden_allow`
+    pub fn is_hidden(&self, path: &Path) -> bool {
+        if self.hidden == HiddenPolicy::Include {
+            return false;
+        }
+        let Some(relative) = self.relative(path) else {
+            return false;
+        };
+
+        relative.components().any(|component| match component {
+            Component::Normal(name) => {
+                let name = name.to_string_lossy();
+                name.starts_with('.') && !self.hidden_allow.contains(name.as_ref())
+            }
+            _ => false,
+        })
+    }
+
+    /// Запись совпадает с шаблонами exclude. Исключенные директории не обходятся
+    pub fn is_excluded(&self, path: &Path, is_dir: bool) -> bool {
+        match self.relative(path) {
+            Some(relative) => self.exclude.matched(relative, is_dir).is_ignore(),
+            None => false,
+        }
+    }
+
+    /// Файл подходит по расширению или по шаблону include (в том числе если
+    /// шаблону соответствует одна из его директорий), не скрыт и не исключен
+    pub fn matches_file(&self, path: &Path) -> bool {
+        let Some(relative) = self.relative(path) else {
+            return false;
+        };
+        if self.is_hidden(path) || self.exclude.matched(relative, false).is_ignore() {
+            return false;
+        }
+
+        directory::has_extension(path, &self.extensions)
+            || self
+                .include
+                .matched_path_or_any_parents(relative, false)
+                .is_ignore()
+    }
+
+    /// Путь относительно исходной директории; для самой директории - None
+    fn relative<'a>(&self, path: &'a Path) -> Option<&'a Path> {
+        path.strip_prefix(&self.root)
+            .ok()
+            .filter(|relative| !relative.as_os_str().is_empty())
+    }
+}
+
+/// Проверяет синтаксис одного шаблона include/exclude
+pub fn check_pattern(pattern: &str) -> Result<()> {
+    GitignoreBuilder::new("/")
+        .add_line(None, pattern)
+        .with_context(|| format!("Invalid pattern: {}", pattern))?;
+    Ok(())
+}
+
+fn build_patterns(root: &Path, patterns: &[String]) -> Result<Gitignore> {
+    let mut builder = GitignoreBuilder::new(root);
+    for pattern in patterns {
+        builder
+            .add_line(None, pattern)
+            .with_context(|| format!("Invalid pattern: {}", pattern))?;
+    }
+    builder.build().context("Failed to compile patterns")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(include: &[&str], exclude: &[&str]) -> PathFilter {
+        let config = Config {
+            extensions: ["txt".to_string()].into_iter().collect(),
+            include: include.iter().map(|s| s.to_string()).collect(),
+            exclude: exclude.iter().map(|s| s.to_string()).collect(),
+            ..Default::default()
+        };
+        PathFilter::new(Path::new("/src"), &config).unwrap()
+    }
+
+    #[test]
+    fn test_extensions_and_include() {
+        let filter = filter(&["Makefile", "/scripts/"], &[]);
+
+        assert!(filter.matches_file(Path::new("/src/notes.txt")));
+        assert!(filter.matches_file(Path::new("/src/Makefile")));
+        assert!(filter.matches_file(Path::new("/src/lib/Makefile")));
+        // Шаблон директории включает все файлы в ней
+        assert!(filter.matches_file(Path::new("/src/scripts/deploy.sh")));
+        assert!(!filter.matches_file(Path::new("/src/lib/scripts/deploy.sh")));
+        assert!(!filter.matches_file(Path::new("/src/image.png")));
+    }
+
+    #[test]
+    fn test_exclude() {
+        let filter = filter(
+            &[],
+            &["node_modules/", "/target/", "*.tmp.txt", "!keep.tmp.txt"],
+        );
+
+        assert!(filter.is_excluded(Path::new("/src/node_modules"), true));
+        assert!(filter.is_excluded(Path::new("/src/web/node_modules"), true));
+        // Шаблон с `/` в конце не совпадает с файлами
+        assert!(!filter.is_excluded(Path::new("/src/node_modules"), false));
+        assert!(filter.is_excluded(Path::new("/src/target"), true));
+        assert!(!filter.is_excluded(Path::new("/src/app/target"), true));
+
+        assert!(!filter.matches_file(Path::new("/src/build.tmp.txt")));
+        assert!(filter.matches_file(Path::new("/src/keep.tmp.txt")));
+        assert!(!filter.is_excluded(Path::new("/src"), true));
+    }
+
+    #[test]
+    fn test_hidden() {
+        let config = Config {
+            extensions: ["txt".to_string()].into_iter().collect(),
+            hidden_allow: vec![".config".to_string()],
+            ..Default::default()
+        };
+        // Скрытая исходная директория сама по себе не делает файлы скрытыми
+        let filter = PathFilter::new(Path::new("/home/user/.local/share"), &config).unwrap();
+
+        assert!(filter.matches_file(Path::new("/home/user/.local/share/photos/a.txt")));
+        assert!(filter.is_hidden(Path::new("/home/user/.local/share/.cache")));
+        assert!(filter.is_hidden(Path::new("/home/user/.local/share/.cache/a.txt")));
+        assert!(!filter.matches_file(Path::new("/home/user/.local/share/.notes.txt")));
+        assert!(!filter.is_hidden(Path::new("/home/user/.local/share/.config/app.txt")));
+        assert!(filter.is_hidden(Path::new("/home/user/.local/share/.config/.state")));
+
+        let config = Config {
+            hidden: HiddenPolicy::Include,
+            ..config
+        };
+        let filter = PathFilter::new(Path::new("/src"), &config).unwrap();
+        assert!(!filter.is_hidden(Path::new("/src/.cache/a.txt")));
+        assert!(filter.matches_file(Path::new("/src/.notes.txt")));
+    }
+
+    #[test]
+    fn test_invalid_pattern() {
+        let config = Config {
+            exclude: vec!["{a,b".to_string()],
+            ..Default::default()
+        };
+        let error = PathFilter::new(Path::new("/src"), &config).unwrap_err();
+
+        assert!(format!("{:#}", error).contains("Invalid exclude pattern"));
+    }
+}
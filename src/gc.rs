@@ -0,0 +1,400 @@
+use crate::directory::TEMP_OBJECT_PREFIX;
+use crate::repository::{self, Repository};
+use crate::snapshot;
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::collections::HashSet;
+use std::fs::{self, create_dir_all};
+use std::path::PathBuf;
+
+/// Директория для объектов, перемещенных сборщиком мусора вместо удаления
+pub const TRASH_DIR: &str = "trash";
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct GcOptions {
+    /// Только показать, что будет удалено
+    pub dry_run: bool,
+    /// Перемещать объекты в trash вместо удаления
+    pub trash: bool,
+}
+
+/// Итоги сборки мусора
+#[derive(Debug, Default, Serialize)]
+pub struct GcStats {
+    pub snapshots: usize,
+    pub referenced: usize,
+    /// Объекты, на которые не ссылается ни один снимок: (путь, размер)
+    pub unreferenced: Vec<(PathBuf, u64)>,
+    /// Временные файлы объектов, оставшиеся после сбоя: (путь, размер).
+    /// Удаляются и при `trash`: это не объекты, восстанавливать из них нечего
+    pub temp_files: Vec<(PathBuf, u64)>,
+    pub freed_bytes: u64,
+}
+
+/// Удаляет (или перемещает в trash) объекты и списки фрагментов, на которые
+/// не ссылается ни один снимок, и временные файлы прерванных запусков
+pub fn collect_garbage(target_base: &str, options: GcOptions) -> Result<GcStats> {
+    collect_garbage_without(target_base, options, &HashSet::new())
+}
+
+/// Как `collect_garbage`, но снимки из `removed` считаются уже удаленными.
+/// Так `prune --dry-run --gc` показывает, что освободит удаление этих снимков
+pub fn collect_garbage_without(
+    target_base: &str,
+    options: GcOptions,
+    removed: &HashSet<String>,
+) -> Result<GcStats> {
+    let repository = Repository::open(target_base)?;
+    let _lock = repository.lock()?;
+    let mut stats = GcStats::default();
+
+    let mut snapshots = repository.list_snapshots()?;
+    snapshots.retain(|name| !removed.contains(name));
+    let referenced = referenced_hashes(&repository, &snapshots)?;
+    stats.snapshots = snapshots.len();
+    stats.referenced = referenced.len();
+
+    let trash_dir = repository.root().join(TRASH_DIR);
+
+    let objects = repository.list_objects()?;
+    for (hash_hex, path) in objects.into_iter().chain(repository.list_chunk_lists()?) {
+        if referenced.contains(&hash_hex) {
+            continue;
+        }
+
+        let size = fs::metadata(&path)
+            .with_context(|| format!("Failed to read metadata: {}", path.display()))?
+            .len();
+
+        if !options.dry_run {
+            if options.trash {
+                let relative = path.strip_prefix(repository.root()).with_context(|| {
+                    format!("Failed to get relative path for {}", path.display())
+                })?;
+                let trash_path = trash_dir.join(relative);
+                if let Some(parent) = trash_path.parent() {
+                    create_dir_all(parent).with_context(|| {
+                        format!("Failed to create directory: {}", parent.display())
+                    })?;
+                }
+                fs::rename(&path, &trash_path).with_context(|| {
+                    format!(
+                        "Failed to move {} to {}",
+                        path.display(),
+                        trash_path.display()
+                    )
+                })?;
+            } else {
+                fs::remove_file(&path)
+                    .with_context(|| format!("Failed to remove file: {}", path.display()))?;
+            }
+
+            // Пустая директория-префикс больше не нужна; ошибку (не пуста) игнорируем
+            if let Some(parent) = path.parent() {
+                let _ = fs::remove_dir(parent);
+            }
+        }
+
+        stats.freed_bytes += size;
+        stats.unreferenced.push((path, size));
+    }
+
+    // Под блокировкой хранилища никто не пишет объекты: все временные файлы брошены
+    for (path, size) in stale_temp_files(&repository)? {
+        if !options.dry_run {
+            fs::remove_file(&path)
+                .with_context(|| format!("Failed to remove file: {}", path.display()))?;
+        }
+        stats.freed_bytes += size;
+        stats.temp_files.push((path, size));
+    }
+
+    Ok(stats)
+}
+
+/// Временные файлы с префиксом TEMP_OBJECT_PREFIX в директориях объектов
+/// и списков фрагментов
+fn stale_temp_files(repository: &Repository) -> Result<Vec<(PathBuf, u64)>> {
+    let mut temp_files = Vec::new();
+
+    for dir in [repository.objects_dir(), repository.chunk_lists_dir()] {
+        if !dir.is_dir() {
+            continue;
+        }
+        for entry in fs::read_dir(&dir)
+            .with_context(|| format!("Failed to read directory: {}", dir.display()))?
+        {
+            let entry =
+                entry.with_context(|| format!("Failed to read directory: {}", dir.display()))?;
+            let metadata = entry
+                .metadata()
+                .with_context(|| format!("Failed to read metadata: {}", entry.path().display()))?;
+            if metadata.is_file()
+                && entry
+                    .file_name()
+                    .to_string_lossy()
+                    .starts_with(TEMP_OBJECT_PREFIX)
+            {
+                temp_files.push((entry.path(), metadata.len()));
+            }
+        }
+    }
+
+    temp_files.sort();
+    Ok(temp_files)
+}
+
+/// Собирает хеши всех объектов, на которые ссылаются снимки `snapshots`, вместе
+/// с фрагментами файлов, сохраненных фрагментами. Ошибка чтения любого снимка или
+/// списка фрагментов прерывает сборку, чтобы не удалить нужные объекты
+fn referenced_hashes(repository: &Repository, snapshots: &[String]) -> Result<HashSet<String>> {
+    let mut referenced = HashSet::new();
+
+    for name in snapshots {
+        let entries =
+            snapshot::read_snapshot(&repository.snapshot_dir(name), repository.algorithm())
+                .with_context(|| format!("Failed to read snapshot {}", name))?;
+        referenced.extend(
+            entries
+                .into_iter()
+                .filter(|e| e.link_target.is_none())
+                .map(|e| e.hash),
+        );
+    }
+
+    let chunk_lists = repository.list_chunk_lists()?;
+    repository::add_referenced_chunks(
+        &mut referenced,
+        chunk_lists.iter().map(|(hash_hex, _)| hash_hex.as_str()),
+        |hash_hex| repository.read_chunk_list(hash_hex),
+    )?;
+
+    Ok(referenced)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunking::{self, ChunkingConfig};
+    use crate::hash::DEFAULT_BUFFER_SIZE;
+    use crate::snapshot::{ManifestEntry, ManifestWriter};
+    use tempfile::TempDir;
+
+    const KEPT: &str = "098f6bcd4621d373cade4e832627b4f6";
+    const ORPHAN: &str = "9a0364b9e99bb480dd25e1f0284c8555";
+
+    // Хранилище с одним снимком, который ссылается только на KEPT
+    fn create_repository() -> (TempDir, Repository) {
+        let temp_dir = TempDir::new().unwrap();
+        let repository = Repository::open_or_init(temp_dir.path().to_str().unwrap(), None).unwrap();
+
+        for (hash_hex, content) in [(KEPT, "test"), (ORPHAN, "content")] {
+            let object = repository.object_path(hash_hex);
+            fs::create_dir_all(object.parent().unwrap()).unwrap();
+            fs::write(object, content).unwrap();
+        }
+
+        let snapshot_dir = repository.snapshot_dir("20240101_000000");
+        fs::create_dir(&snapshot_dir).unwrap();
+        let mut manifest = ManifestWriter::create(&snapshot_dir).unwrap();
+        manifest
+            .write(&ManifestEntry::from_hash(
+                PathBuf::from("a.txt"),
+                KEPT.to_string(),
+            ))
+            .unwrap();
+        manifest.finish().unwrap();
+
+        (temp_dir, repository)
+    }
+
+    #[test]
+    fn test_gc_removes_unreferenced() {
+        let (temp_dir, repository) = create_repository();
+
+        let stats =
+            collect_garbage(temp_dir.path().to_str().unwrap(), GcOptions::default()).unwrap();
+
+        assert_eq!(stats.snapshots, 1);
+        assert_eq!(stats.referenced, 1);
+        assert_eq!(stats.unreferenced.len(), 1);
+        assert_eq!(stats.freed_bytes, 7);
+        assert!(repository.object_path(KEPT).exists());
+        assert!(!repository.object_path(ORPHAN).exists());
+    }
+
+    #[test]
+    fn test_gc_dry_run() {
+        let (temp_dir, repository) = create_repository();
+
+        let options = GcOptions {
+            dry_run: true,
+            ..Default::default()
+        };
+        let stats = collect_garbage(temp_dir.path().to_str().unwrap(), options).unwrap();
+
+        assert_eq!(
+            stats.unreferenced,
+            vec![(repository.object_path(ORPHAN), 7)]
+        );
+        assert!(repository.object_path(ORPHAN).exists());
+    }
+
+    #[test]
+    fn test_gc_without_removed_snapshots() {
+        let (temp_dir, repository) = create_repository();
+        let target = temp_dir.path().to_str().unwrap();
+        let options = GcOptions {
+            dry_run: true,
+            ..Default::default()
+        };
+
+        // Без единственного снимка не нужен и объект KEPT; на диске ничего не меняется
+        let removed = HashSet::from(["20240101_000000".to_string()]);
+        let stats = collect_garbage_without(target, options, &removed).unwrap();
+
+        assert_eq!(stats.snapshots, 0);
+        assert_eq!(stats.referenced, 0);
+        assert_eq!(stats.unreferenced.len(), 2);
+        assert_eq!(stats.freed_bytes, 11);
+        assert!(repository.object_path(KEPT).exists());
+        assert!(repository.snapshot_dir("20240101_000000").exists());
+    }
+
+    #[test]
+    fn test_gc_moves_to_trash() {
+        let (temp_dir, repository) = create_repository();
+
+        let options = GcOptions {
+            trash: true,
+            ..Default::default()
+        };
+        collect_garbage(temp_dir.path().to_str().unwrap(), options).unwrap();
+
+        assert!(!repository.object_path(ORPHAN).exists());
+        assert!(temp_dir
+            .path()
+            .join(TRASH_DIR)
+            .join("files_by_md5/9a")
+            .join(ORPHAN)
+            .exists());
+    }
+
+    #[test]
+    fn test_gc_keeps_chunks_of_referenced_files() {
+        let (temp_dir, repository) = create_repository();
+        let chunking = ChunkingConfig {
+            enabled: true,
+            min_size: 64,
+            avg_size: 256,
+            max_size: 1024,
+        };
+        let source = temp_dir.path().join("large.txt");
+        let content: String = (0..2000).map(|i| format!("line {}\n", i)).collect();
+        fs::write(&source, &content).unwrap();
+        let kept =
+            chunking::store_chunked(&source, &repository, &chunking, DEFAULT_BUFFER_SIZE).unwrap();
+        fs::write(&source, content.replace("line 1999", "edited")).unwrap();
+        let orphan =
+            chunking::store_chunked(&source, &repository, &chunking, DEFAULT_BUFFER_SIZE).unwrap();
+
+        let snapshot_dir = repository.snapshot_dir("20240102_000000");
+        fs::create_dir(&snapshot_dir).unwrap();
+        let mut manifest = ManifestWriter::create(&snapshot_dir).unwrap();
+        manifest
+            .write(&ManifestEntry::from_hash(
+                PathBuf::from("large.txt"),
+                kept.hash.clone(),
+            ))
+            .unwrap();
+        manifest.finish().unwrap();
+
+        collect_garbage(temp_dir.path().to_str().unwrap(), GcOptions::default()).unwrap();
+
+        // Общие с удаленным файлом фрагменты остаются, уникальные - удаляются
+        let chunks = repository.read_chunk_list(&kept.hash).unwrap();
+        assert!(chunks.iter().all(|c| repository.object_path(c).exists()));
+        assert!(!repository.chunk_list_path(&orphan.hash).exists());
+        assert_eq!(
+            repository.list_objects().unwrap().len(),
+            chunks.len() + 1 // и объект KEPT
+        );
+    }
+
+    #[test]
+    fn test_gc_removes_stale_temp_files() {
+        let (temp_dir, repository) = create_repository();
+        let temp_file = repository
+            .objects_dir()
+            .join(format!("{}abc123", TEMP_OBJECT_PREFIX));
+        fs::write(&temp_file, "partial").unwrap();
+        let target = temp_dir.path().to_str().unwrap();
+
+        let dry_run = GcOptions {
+            dry_run: true,
+            ..Default::default()
+        };
+        let stats = collect_garbage(target, dry_run).unwrap();
+        assert_eq!(stats.temp_files, vec![(temp_file.clone(), 7)]);
+        assert!(temp_file.exists());
+
+        let stats = collect_garbage(target, GcOptions::default()).unwrap();
+        assert_eq!(stats.temp_files, vec![(temp_file.clone(), 7)]);
+        assert_eq!(stats.freed_bytes, 14);
+        assert!(!temp_file.exists());
+        assert!(repository.object_path(KEPT).exists());
+    }
+
+    #[test]
+    fn test_gc_legacy_snapshot_references() {
+        let (temp_dir, repository) = create_repository();
+
+        // Снимок старого формата ссылается на ORPHAN
+        let legacy_dir = repository.snapshot_dir("20230101_000000");
+        fs::create_dir(&legacy_dir).unwrap();
+        fs::write(legacy_dir.join("b.txt"), ORPHAN).unwrap();
+
+        let stats =
+            collect_garbage(temp_dir.path().to_str().unwrap(), GcOptions::default()).unwrap();
+
+        assert!(stats.unreferenced.is_empty());
+        assert!(repository.object_path(ORPHAN).exists());
+    }
+
+    #[test]
+    fn test_gc_ignores_incomplete_snapshot() {
+        let (temp_dir, repository) = create_repository();
+
+        // Прерванный запуск оставил только временный файл манифеста
+        let interrupted_dir = repository.snapshot_dir("20240102_000000");
+        fs::create_dir(&interrupted_dir).unwrap();
+        let mut manifest = ManifestWriter::create(&interrupted_dir).unwrap();
+        manifest
+            .write(&ManifestEntry::from_hash(
+                PathBuf::from("b.txt"),
+                ORPHAN.to_string(),
+            ))
+            .unwrap();
+        std::mem::forget(manifest);
+
+        let stats =
+            collect_garbage(temp_dir.path().to_str().unwrap(), GcOptions::default()).unwrap();
+
+        assert_eq!(stats.snapshots, 1);
+        assert!(!repository.object_path(ORPHAN).exists());
+    }
+
+    #[test]
+    fn test_gc_aborts_on_broken_snapshot() {
+        let (temp_dir, repository) = create_repository();
+
+        let broken_dir = repository.snapshot_dir("20230101_000000");
+        fs::create_dir(&broken_dir).unwrap();
+        fs::write(broken_dir.join(snapshot::MANIFEST_FILE), "broken").unwrap();
+
+        let result = collect_garbage(temp_dir.path().to_str().unwrap(), GcOptions::default());
+
+        assert!(result.is_err());
+        assert!(repository.object_path(ORPHAN).exists());
+    }
+}
@@ -1,27 +1,152 @@
 use anyhow::{Context, Result};
-use md5;
+use serde::{Deserialize, Serialize};
+use sha2::Digest;
+use std::fmt;
 use std::fs::File;
-use std::io::Read;
+use std::io::{self, ErrorKind, Read, Write};
 use std::path::Path;
 
-pub fn calculate_md5(path: &Path) -> Result<String> {
+/// Размер буфера чтения по умолчанию (64 KiB)
+pub const DEFAULT_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Алгоритм хеширования, которым адресуются объекты хранилища
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HashAlgorithm {
+    #[default]
+    Md5,
+    Sha256,
+    Blake3,
+}
+
+impl HashAlgorithm {
+    pub fn name(self) -> &'static str {
+        match self {
+            HashAlgorithm::Md5 => "md5",
+            HashAlgorithm::Sha256 => "sha256",
+            HashAlgorithm::Blake3 => "blake3",
+        }
+    }
+
+    /// Длина хеша в hex-виде
+    pub fn hex_len(self) -> usize {
+        match self {
+            HashAlgorithm::Md5 => 32,
+            HashAlgorithm::Sha256 | HashAlgorithm::Blake3 => 64,
+        }
+    }
+
+    /// Проверяет, что строка похожа на хеш этого алгоритма
+    pub fn is_valid_hex(self, hex: &str) -> bool {
+        hex.len() == self.hex_len() && hex.chars().all(|c| c.is_ascii_hexdigit())
+    }
+
+    pub fn hasher(self) -> Box<dyn Hasher> {
+        match self {
+            HashAlgorithm::Md5 => Box::new(Md5Hasher(md5::Context::new())),
+            HashAlgorithm::Sha256 => Box::new(Sha256Hasher(sha2::Sha256::new())),
+            HashAlgorithm::Blake3 => Box::new(Blake3Hasher(blake3::Hasher::new())),
+        }
+    }
+}
+
+impl fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Инкрементальный вычислитель хеша
+pub trait Hasher {
+    fn update(&mut self, data: &[u8]);
+    /// Завершает вычисление и возвращает хеш в hex-виде
+    fn finalize_hex(self: Box<Self>) -> String;
+}
+
+struct Md5Hasher(md5::Context);
+
+impl Hasher for Md5Hasher {
+    fn update(&mut self, data: &[u8]) {
+        self.0.consume(data);
+    }
+
+    fn finalize_hex(self: Box<Self>) -> String {
+        format!("{:x}", self.0.compute())
+    }
+}
+
+struct Sha256Hasher(sha2::Sha256);
+
+impl Hasher for Sha256Hasher {
+    fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+
+    fn finalize_hex(self: Box<Self>) -> String {
+        format!("{:x}", self.0.finalize())
+    }
+}
+
+struct Blake3Hasher(blake3::Hasher);
+
+impl Hasher for Blake3Hasher {
+    fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+
+    fn finalize_hex(self: Box<Self>) -> String {
+        self.0.finalize().to_hex().to_string()
+    }
+}
+
+/// Вычисляет хеш файла потоково, блоками по `buffer_size` байт,
+/// поэтому расход памяти не зависит от размера файла
+pub fn calculate_hash(path: &Path, algorithm: HashAlgorithm, buffer_size: usize) -> Result<String> {
     let mut file =
         File::open(path).with_context(|| format!("Failed to open file: {}", path.display()))?;
 
-    let mut buffer = Vec::new();
-    file.read_to_end(&mut buffer)
+    let (hash_hex, _) = copy_with_hash(&mut file, &mut io::sink(), algorithm, buffer_size)
         .with_context(|| format!("Failed to read file: {}", path.display()))?;
 
-    let digest = md5::compute(&buffer);
-    Ok(format!("{:x}", digest))
+    Ok(hash_hex)
+}
+
+/// Копирует данные из reader в writer, вычисляя хеш именно записанных байт.
+/// Возвращает хеш и число скопированных байт
+pub fn copy_with_hash<R: Read, W: Write>(
+    reader: &mut R,
+    writer: &mut W,
+    algorithm: HashAlgorithm,
+    buffer_size: usize,
+) -> io::Result<(String, u64)> {
+    let mut hasher = algorithm.hasher();
+    let mut buffer = vec![0u8; buffer_size.max(1)];
+    let mut copied = 0u64;
+
+    loop {
+        let read = match reader.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        writer.write_all(&buffer[..read])?;
+        hasher.update(&buffer[..read]);
+        copied += read as u64;
+    }
+
+    Ok((hasher.finalize_hex(), copied))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::io::Write;
     use tempfile::NamedTempFile;
 
+    fn calculate_md5(path: &Path) -> Result<String> {
+        calculate_hash(path, HashAlgorithm::Md5, DEFAULT_BUFFER_SIZE)
+    }
+
     #[test]
     fn test_calculate_md5_empty_file() {
         // Создаем временный файл
@@ -83,6 +208,45 @@ mod tests {
         assert_eq!(md5_1, md5_2);
     }
 
+    #[test]
+    fn test_calculate_md5_larger_than_buffer() {
+        let mut file = NamedTempFile::new().unwrap();
+        let content: Vec<u8> = (0..DEFAULT_BUFFER_SIZE * 3 + 17)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        file.write_all(&content).unwrap();
+
+        let expected_md5 = format!("{:x}", md5::compute(&content));
+        let actual_md5 = calculate_md5(file.path()).unwrap();
+
+        assert_eq!(actual_md5, expected_md5);
+    }
+
+    #[test]
+    fn test_calculate_md5_buffer_size_does_not_change_result() {
+        let mut file = NamedTempFile::new().unwrap();
+        write!(file, "hello world").unwrap();
+
+        // Буфер меньше файла, равный файлу и больше файла
+        for buffer_size in [1, 3, 11, 4096] {
+            let actual_md5 = calculate_hash(file.path(), HashAlgorithm::Md5, buffer_size).unwrap();
+            assert_eq!(actual_md5, "5eb63bbbe01eeed093cb22bb8f5acdc3");
+        }
+    }
+
+    #[test]
+    fn test_copy_with_hash() {
+        let content = b"hello world";
+        let mut copy = Vec::new();
+
+        let (hash_hex, copied) =
+            copy_with_hash(&mut &content[..], &mut copy, HashAlgorithm::Md5, 3).unwrap();
+
+        assert_eq!(hash_hex, "5eb63bbbe01eeed093cb22bb8f5acdc3");
+        assert_eq!(copied, 11);
+        assert_eq!(copy, content);
+    }
+
     #[test]
     fn test_md5_different_for_different_content() {
         let mut file1 = NamedTempFile::new().unwrap();
@@ -95,4 +259,56 @@ mod tests {
 
         assert_ne!(md5_1, md5_2);
     }
+
+    #[test]
+    fn test_calculate_sha256() {
+        let mut file = NamedTempFile::new().unwrap();
+        write!(file, "hello world").unwrap();
+
+        let actual =
+            calculate_hash(file.path(), HashAlgorithm::Sha256, DEFAULT_BUFFER_SIZE).unwrap();
+        assert_eq!(
+            actual,
+            "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9"
+        );
+    }
+
+    #[test]
+    fn test_calculate_blake3() {
+        let mut file = NamedTempFile::new().unwrap();
+        write!(file, "hello world").unwrap();
+
+        let actual =
+            calculate_hash(file.path(), HashAlgorithm::Blake3, DEFAULT_BUFFER_SIZE).unwrap();
+        assert_eq!(
+            actual,
+            "d74981efa70a0c880b8d8c1985d075dbcbf679b99a5f9914e5aaf96b831a9e24"
+        );
+    }
+
+    #[test]
+    fn test_hash_algorithm_names() {
+        // Алгоритм читается только через serde: имена те же, что выводит name()
+        for algorithm in [
+            HashAlgorithm::Md5,
+            HashAlgorithm::Sha256,
+            HashAlgorithm::Blake3,
+        ] {
+            let quoted = format!("\"{}\"", algorithm.name());
+            assert_eq!(serde_json::to_string(&algorithm).unwrap(), quoted);
+            assert_eq!(
+                serde_json::from_str::<HashAlgorithm>(&quoted).unwrap(),
+                algorithm
+            );
+        }
+        assert!(serde_json::from_str::<HashAlgorithm>("\"SHA256\"").is_err());
+        assert!(serde_json::from_str::<HashAlgorithm>("\"crc32\"").is_err());
+    }
+
+    #[test]
+    fn test_is_valid_hex() {
+        assert!(HashAlgorithm::Md5.is_valid_hex("d41d8cd98f00b204e9800998ecf8427e"));
+        assert!(!HashAlgorithm::Md5.is_valid_hex("short"));
+        assert!(!HashAlgorithm::Sha256.is_valid_hex("d41d8cd98f00b204e9800998ecf8427e"));
+    }
 }
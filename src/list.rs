@@ -0,0 +1,138 @@
+use crate::repository::Repository;
+use crate::snapshot::ManifestEntry;
+use anyhow::Result;
+use serde::Serialize;
+
+/// Сводка по одному снимку
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SnapshotSummary {
+    pub name: String,
+    pub files: usize,
+    pub symlinks: usize,
+    /// Суммарный размер файлов снимка до дедупликации
+    pub bytes: u64,
+    /// Запись снимка прервана; он не читается, а счетчики нулевые
+    pub incomplete: bool,
+}
+
+/// Сводки по всем снимкам хранилища, включая незавершенные, от старых к новым
+pub fn list_snapshots(target_base: &str) -> Result<Vec<SnapshotSummary>> {
+    let repository = Repository::open(target_base)?;
+
+    let mut summaries = repository
+        .list_snapshots()?
+        .into_iter()
+        .map(|name| {
+            let entries = repository.read_snapshot(&name)?;
+            let symlinks = entries.iter().filter(|e| e.link_target.is_some()).count();
+
+            Ok(SnapshotSummary {
+                files: entries.len() - symlinks,
+                symlinks,
+                bytes: entries.iter().map(|e| repository.entry_size(e)).sum(),
+                name,
+                incomplete: false,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    summaries.extend(
+        repository
+            .list_incomplete_snapshots()?
+            .into_iter()
+            .map(|name| SnapshotSummary {
+                name,
+                files: 0,
+                symlinks: 0,
+                bytes: 0,
+                incomplete: true,
+            }),
+    );
+    summaries.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(summaries)
+}
+
+/// Записи одного снимка в порядке манифеста
+pub fn list_files(target_base: &str, snapshot: &str) -> Result<Vec<ManifestEntry>> {
+    Repository::open(target_base)?.read_snapshot(snapshot)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::snapshot::ManifestWriter;
+    use std::fs;
+    use std::path::PathBuf;
+    use tempfile::TempDir;
+
+    const TEST_MD5: &str = "098f6bcd4621d373cade4e832627b4f6";
+
+    #[test]
+    fn test_list_snapshots() {
+        let temp_dir = TempDir::new().unwrap();
+        let target = temp_dir.path().to_str().unwrap();
+        let repository = Repository::open_or_init(target, None).unwrap();
+
+        // Снимок старого формата: размер берется по объекту
+        let object = repository.object_path(TEST_MD5);
+        fs::create_dir_all(object.parent().unwrap()).unwrap();
+        fs::write(&object, "test").unwrap();
+        let legacy_dir = repository.snapshot_dir("20240101_000000");
+        fs::create_dir(&legacy_dir).unwrap();
+        fs::write(legacy_dir.join("a.txt"), TEST_MD5).unwrap();
+
+        let snapshot_dir = repository.snapshot_dir("20240102_000000");
+        fs::create_dir(&snapshot_dir).unwrap();
+        let mut manifest = ManifestWriter::create(&snapshot_dir).unwrap();
+        manifest
+            .write(&ManifestEntry {
+                size: Some(10),
+                ..ManifestEntry::from_hash(PathBuf::from("a.txt"), TEST_MD5.to_string())
+            })
+            .unwrap();
+        manifest
+            .write(&ManifestEntry {
+                link_target: Some(PathBuf::from("a.txt")),
+                ..ManifestEntry::from_hash(PathBuf::from("link.txt"), String::new())
+            })
+            .unwrap();
+        manifest.finish().unwrap();
+
+        // Прерванный запуск: манифест не дописан
+        let interrupted_dir = repository.snapshot_dir("20240103_000000");
+        fs::create_dir(&interrupted_dir).unwrap();
+        std::mem::forget(ManifestWriter::create(&interrupted_dir).unwrap());
+
+        let summaries = list_snapshots(target).unwrap();
+
+        assert_eq!(
+            summaries,
+            vec![
+                SnapshotSummary {
+                    name: "20240101_000000".to_string(),
+                    files: 1,
+                    symlinks: 0,
+                    bytes: 4,
+                    incomplete: false,
+                },
+                SnapshotSummary {
+                    name: "20240102_000000".to_string(),
+                    files: 1,
+                    symlinks: 1,
+                    bytes: 10,
+                    incomplete: false,
+                },
+                SnapshotSummary {
+                    name: "20240103_000000".to_string(),
+                    files: 0,
+                    symlinks: 0,
+                    bytes: 0,
+                    incomplete: true,
+                },
+            ]
+        );
+        assert!(list_files(target, "20240103_000000").is_err());
+        assert_eq!(list_files(target, "20240102_000000").unwrap().len(), 2);
+        assert!(list_files(target, "20990101_000000").is_err());
+    }
+}
@@ -1,31 +1,450 @@
-use anyhow::Result;
-use std::env;
+use anyhow::{bail, Context, Result};
+use chrono::Local;
+use std::process::ExitCode;
 
+mod cache;
+mod check;
+mod chunking;
+mod cli;
 mod config;
+mod diff;
 mod directory;
 mod file_processor;
+mod filter;
+mod gc;
 mod hash;
+mod list;
+mod metadata;
+mod output;
+mod prune;
+mod repository;
+mod restore;
+mod snapshot;
+mod stats;
+mod summary;
+mod verify;
 
+use cli::{
+    BackupArgs, Command, ConfigArgs, ConfigCommand, DiffArgs, Format, GcArgs, ListArgs, PruneArgs,
+    RestoreArgs, StatsArgs, VerifyArgs,
+};
 use config::Config;
-use file_processor::process_files_with_extensions;
+use file_processor::{process_files_with_extensions, BackupOptions};
+use gc::{collect_garbage, collect_garbage_without, GcOptions};
+use output::{print_json, print_json_lines, ErrorReport};
+use prune::{prune_snapshots, RetentionPolicy};
+use repository::RepositoryLocked;
+use restore::restore_snapshot;
+use verify::{verify_repository, VerifyOptions};
 
-fn main() -> Result<()> {
-    let args: Vec<String> = env::args().collect();
-    if args.len() < 4 {
+/// Код возврата: команда выполнена, но часть файлов не обработана
+/// (для verify - найдены поврежденные или отсутствующие объекты,
+/// для config check - ошибки в конфигурации)
+const EXIT_PARTIAL: u8 = 3;
+/// Код возврата: хранилище занято другим процессом
+const EXIT_LOCKED: u8 = 4;
+
+const NO_TARGET: &str = "No backup repository: pass TARGET_BASE or set target in the profile";
+
+fn main() -> ExitCode {
+    let cli = cli::parse();
+    let format = cli.format;
+
+    let result = match cli.command {
+        Command::Backup(args) => run_backup(args, format),
+        Command::Restore(args) => run_restore(args, format),
+        Command::List(args) => run_list(args, format).map(|()| ExitCode::SUCCESS),
+        Command::Verify(args) => run_verify(args, format),
+        Command::Gc(args) => run_gc(args, format).map(|()| ExitCode::SUCCESS),
+        Command::Prune(args) => run_prune(args, format).map(|()| ExitCode::SUCCESS),
+        Command::Diff(args) => run_diff(args, format).map(|()| ExitCode::SUCCESS),
+        Command::Stats(args) => run_stats(args, format).map(|()| ExitCode::SUCCESS),
+        Command::Config(args) => run_config(args, format),
+    };
+
+    match result {
+        Ok(code) => code,
+        Err(e) => {
+            // В JSON-режиме ошибка выводится цепочкой сообщений там же, где и результаты
+            if format == Format::Json {
+                let _ = print_json(&ErrorReport::new(&e));
+            } else {
+                eprintln!("Error: {:?}", e);
+            }
+
+            if e.chain().any(|cause| cause.is::<RepositoryLocked>()) {
+                ExitCode::from(EXIT_LOCKED)
+            } else {
+                ExitCode::FAILURE
+            }
+        }
+    }
+}
+
+/// Код возврата команды, выполненной до конца
+fn exit_code(complete: bool) -> ExitCode {
+    if complete {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::from(EXIT_PARTIAL)
+    }
+}
+
+fn run_backup(args: BackupArgs, format: Format) -> Result<ExitCode> {
+    let options = BackupOptions {
+        rehash: args.rehash,
+        clear_cache: args.clear_cache,
+        jobs: args.jobs.map(|n| n.get()),
+        dry_run: args.dry_run,
+        fail_fast: args.fail_fast,
+        // В JSON-режиме сообщения о файлах есть только в выводе статистики
+        quiet: format == Format::Json,
+    };
+
+    let config = Config::load(args.config_path(), args.profile.as_deref())?;
+    let target_base = args
+        .target_base
+        .as_deref()
+        .or(config.target.as_deref())
+        .context(NO_TARGET)?;
+    // Без SOURCE_DIR относительные пути источников отсчитываются от текущей директории
+    let source_dir = match args.source_dir.as_deref() {
+        Some(source_dir) => source_dir,
+        None if !config.sources.is_empty() => ".",
+        None => bail!("No source directory: pass SOURCE_DIR or set sources in the profile"),
+    };
+
+    let stats = process_files_with_extensions(source_dir, target_base, &config, &options)?;
+
+    if format == Format::Json {
+        print_json(&stats)?;
+        return Ok(exit_code(stats.errors.is_empty()));
+    }
+
+    print!("{}", summary::format_summary(&stats));
+    if !stats.inconsistent.is_empty() {
         eprintln!(
-            "Usage: {} <source_dir> <target_base> <config_file>",
-            args[0]
+            "{} files changed during backup and may be inconsistent:",
+            stats.inconsistent.len()
+        );
+        for path in &stats.inconsistent {
+            eprintln!("  {}", path.display());
+        }
+    }
+
+    Ok(exit_code(stats.errors.is_empty()))
+}
+
+fn run_restore(args: RestoreArgs, format: Format) -> Result<ExitCode> {
+    let stats = restore_snapshot(
+        &args.target_base,
+        &args.snapshot,
+        &args.dest,
+        args.overwrite,
+    )?;
+
+    if format == Format::Json {
+        print_json(&stats)?;
+    } else {
+        for path in &stats.skipped_existing {
+            eprintln!(
+                "Skipped existing file (use --overwrite): {}",
+                path.display()
+            );
+        }
+        for (path, md5_hex) in &stats.missing_objects {
+            eprintln!("Missing object {} for {}", md5_hex, path.display());
+        }
+        for (path, name) in &stats.skipped_xattrs {
+            eprintln!(
+                "Skipped extended attribute {} (not supported or not permitted): {}",
+                name,
+                path.display()
+            );
+        }
+        println!("Restored {} files to {}", stats.restored, args.dest);
+    }
+
+    Ok(exit_code(stats.is_complete()))
+}
+
+fn run_gc(args: GcArgs, format: Format) -> Result<()> {
+    let options = GcOptions {
+        dry_run: args.dry_run,
+        trash: args.trash,
+    };
+    let stats = collect_garbage(&args.target_base, options)?;
+
+    if format == Format::Json {
+        return print_json(&stats);
+    }
+    print_gc_stats(&stats, options);
+
+    Ok(())
+}
+
+fn print_gc_stats(stats: &gc::GcStats, options: GcOptions) {
+    let action = match (options.dry_run, options.trash) {
+        (true, _) => "Would remove",
+        (false, true) => "Moved to trash",
+        (false, false) => "Removed",
+    };
+    for (path, size) in &stats.unreferenced {
+        println!("{} {} ({} bytes)", action, path.display(), size);
+    }
+    // Временные файлы удаляются и при --trash
+    let temp_action = if options.dry_run {
+        "Would remove"
+    } else {
+        "Removed"
+    };
+    for (path, size) in &stats.temp_files {
+        println!(
+            "{} temporary file {} ({} bytes)",
+            temp_action,
+            path.display(),
+            size
         );
-        eprintln!("Example: {} ./src ./target config.toml", args[0]);
-        std::process::exit(1);
     }
+    println!(
+        "{} snapshots reference {} objects; {}: {} objects, {} temporary files, {} bytes",
+        stats.snapshots,
+        stats.referenced,
+        action.to_lowercase(),
+        stats.unreferenced.len(),
+        stats.temp_files.len(),
+        stats.freed_bytes
+    );
+}
+
+fn run_prune(args: PruneArgs, format: Format) -> Result<()> {
+    let mut policy = RetentionPolicy {
+        keep_last: args.keep_last,
+        keep_daily: args.keep_daily,
+        keep_weekly: args.keep_weekly,
+        keep_monthly: args.keep_monthly,
+        keep_within: args.keep_within,
+    };
+    let mut target_base = args.target_base;
+    // Правила из командной строки заменяют одноименные правила профиля
+    if let Some(profile) = &args.profile {
+        let config = Config::from_profile(&args.config, profile)?;
+        policy = policy.or(config.retention);
+        target_base = target_base.or(config.target);
+    }
+    let target_base = &target_base.context(NO_TARGET)?;
+    let dry_run = args.dry_run;
 
-    let source_dir = &args[1];
-    let target_base = &args[2];
-    let config_file = &args[3];
+    let decisions = prune_snapshots(target_base, &policy, Local::now().naive_local(), dry_run)?;
+    let gc_options = GcOptions {
+        dry_run,
+        trash: args.trash,
+    };
+    // При dry-run снимки не удалены: gc должен считать их удаленными,
+    // иначе он не покажет объекты, которые освободит prune
+    let gc_stats = match (args.gc, dry_run) {
+        (false, _) => None,
+        (true, false) => Some(collect_garbage(target_base, gc_options)?),
+        (true, true) => {
+            let removed = decisions
+                .iter()
+                .filter(|d| !d.keep())
+                .map(|d| d.snapshot.clone())
+                .collect();
+            Some(collect_garbage_without(target_base, gc_options, &removed)?)
+        }
+    };
 
-    let config = Config::from_file(config_file)?;
-    process_files_with_extensions(source_dir, target_base, &config.extensions)?;
+    if format == Format::Json {
+        return print_json(&serde_json::json!({
+            "dry_run": dry_run,
+            "decisions": decisions,
+            "gc": gc_stats,
+        }));
+    }
+
+    for decision in &decisions {
+        if decision.keep() {
+            println!(
+                "keep   {} ({})",
+                decision.snapshot,
+                decision.reasons.join(", ")
+            );
+        } else {
+            let action = if dry_run { "would remove" } else { "remove" };
+            let note = if decision.incomplete {
+                " (incomplete)"
+            } else {
+                ""
+            };
+            println!("{} {}{}", action, decision.snapshot, note);
+        }
+    }
+    if let Some(stats) = &gc_stats {
+        print_gc_stats(stats, gc_options);
+    }
 
     Ok(())
 }
+
+fn run_verify(args: VerifyArgs, format: Format) -> Result<ExitCode> {
+    let options = VerifyOptions {
+        sample_percent: args.sample,
+        ..Default::default()
+    };
+
+    let report = verify_repository(&args.target_base, options)?;
+
+    if format == Format::Json {
+        print_json(&report)?;
+    } else {
+        print_verify_report(&report);
+    }
+
+    Ok(exit_code(report.is_ok()))
+}
+
+fn print_verify_report(report: &verify::VerifyReport) {
+    for object in &report.corrupt {
+        println!(
+            "CORRUPT  {} (actual {})",
+            object.path.display(),
+            object.actual
+        );
+    }
+    for object in &report.missing {
+        println!(
+            "MISSING  {} in {}: {}",
+            object.hash,
+            object.snapshot,
+            object.path.display()
+        );
+    }
+    for path in &report.orphaned {
+        println!("ORPHANED {}", path.display());
+    }
+    for name in &report.incomplete {
+        println!("INCOMPLETE {} (backup was interrupted)", name);
+    }
+    for snapshot in &report.unreadable {
+        println!("UNREADABLE {}: {}", snapshot.snapshot, snapshot.error);
+    }
+    println!(
+        "{} snapshots, {} of {} objects re-hashed: {} corrupt, {} missing, {} orphaned, {} unreadable snapshots",
+        report.snapshots,
+        report.objects_checked,
+        report.objects_total,
+        report.corrupt.len(),
+        report.missing.len(),
+        report.orphaned.len(),
+        report.unreadable.len()
+    );
+}
+
+fn run_list(args: ListArgs, format: Format) -> Result<()> {
+    if let Some(snapshot) = &args.snapshot {
+        let entries = list::list_files(&args.target_base, snapshot)?;
+        if format == Format::Json {
+            return print_json_lines(&entries);
+        }
+
+        for entry in &entries {
+            match &entry.link_target {
+                Some(target) => println!("{} -> {}", entry.path.display(), target.display()),
+                None => println!(
+                    "{}  {}  {}",
+                    entry.hash,
+                    entry.size.map_or("-".to_string(), |size| size.to_string()),
+                    entry.path.display()
+                ),
+            }
+        }
+        return Ok(());
+    }
+
+    let summaries = list::list_snapshots(&args.target_base)?;
+    if format == Format::Json {
+        return print_json_lines(&summaries);
+    }
+
+    for summary in &summaries {
+        if summary.incomplete {
+            println!("{}  incomplete (backup was interrupted)", summary.name);
+            continue;
+        }
+        println!(
+            "{}  {} files, {} symlinks, {} bytes",
+            summary.name, summary.files, summary.symlinks, summary.bytes
+        );
+    }
+
+    Ok(())
+}
+
+fn run_diff(args: DiffArgs, format: Format) -> Result<()> {
+    let changes = diff::diff_snapshots(&args.target_base, &args.from, &args.to)?;
+    if format == Format::Json {
+        return print_json_lines(&changes);
+    }
+
+    for entry in &changes {
+        println!("{} {}", entry.change, entry.path.display());
+    }
+
+    Ok(())
+}
+
+fn run_stats(args: StatsArgs, format: Format) -> Result<()> {
+    let stats = stats::repository_stats(&args.target_base)?;
+    if format == Format::Json {
+        return print_json(&stats);
+    }
+
+    println!("Snapshots:          {}", stats.snapshots);
+    println!(
+        "Objects:            {} ({} referenced)",
+        stats.objects, stats.referenced_objects
+    );
+    println!("Stored bytes:       {}", stats.stored_bytes);
+    println!("Logical bytes:      {}", stats.logical_bytes);
+    if let Some(ratio) = stats.dedup_ratio() {
+        println!("Deduplication:      {:.2}x", ratio);
+    }
+
+    Ok(())
+}
+
+fn run_config(args: ConfigArgs, format: Format) -> Result<ExitCode> {
+    let ConfigCommand::Check(args) = args.command;
+    let diagnostics = check::check_config(&args.config)?;
+    let errors = diagnostics
+        .iter()
+        .filter(|d| d.severity == check::Severity::Error)
+        .count();
+
+    if format == Format::Json {
+        print_json_lines(&diagnostics)?;
+        return Ok(exit_code(errors == 0));
+    }
+
+    for diagnostic in &diagnostics {
+        match (diagnostic.line, diagnostic.column) {
+            (Some(line), Some(column)) => println!(
+                "{}:{}:{}: {}: {}",
+                args.config, line, column, diagnostic.severity, diagnostic.message
+            ),
+            _ => println!(
+                "{}: {}: {}",
+                args.config, diagnostic.severity, diagnostic.message
+            ),
+        }
+    }
+    println!(
+        "{}: {} errors, {} warnings",
+        args.config,
+        errors,
+        diagnostics.len() - errors
+    );
+
+    Ok(exit_code(errors == 0))
+}
@@ -0,0 +1,290 @@
+use crate::snapshot::ManifestEntry;
+use anyhow::{bail, Context, Result};
+use std::collections::BTreeMap;
+use std::fs::{self, File, FileTimes};
+use std::io::ErrorKind;
+use std::path::Path;
+use std::time::{Duration, UNIX_EPOCH};
+
+/// Права доступа Unix (без типа файла)
+#[cfg(unix)]
+pub fn file_mode(metadata: &fs::Metadata) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+    Some(metadata.permissions().mode() & 0o7777)
+}
+
+#[cfg(not(unix))]
+pub fn file_mode(_metadata: &fs::Metadata) -> Option<u32> {
+    None
+}
+
+/// Владелец файла: (uid, gid)
+#[cfg(unix)]
+pub fn file_owner(metadata: &fs::Metadata) -> (Option<u32>, Option<u32>) {
+    use std::os::unix::fs::MetadataExt;
+    (Some(metadata.uid()), Some(metadata.gid()))
+}
+
+#[cfg(not(unix))]
+pub fn file_owner(_metadata: &fs::Metadata) -> (Option<u32>, Option<u32>) {
+    (None, None)
+}
+
+/// Читает расширенные атрибуты файла; значения кодируются в hex.
+/// Если файловая система не поддерживает xattr, возвращается пустой набор
+#[cfg(unix)]
+pub fn read_xattrs(path: &Path) -> Result<BTreeMap<String, String>> {
+    let names = match xattr::list(path) {
+        Ok(names) => names,
+        Err(e) if e.kind() == ErrorKind::Unsupported => return Ok(BTreeMap::new()),
+        Err(e) => {
+            return Err(e).with_context(|| {
+                format!("Failed to list extended attributes: {}", path.display())
+            });
+        }
+    };
+
+    let mut xattrs = BTreeMap::new();
+    for name in names {
+        let value = xattr::get(path, &name).with_context(|| {
+            format!(
+                "Failed to read extended attribute {:?}: {}",
+                name,
+                path.display()
+            )
+        })?;
+        if let (Some(name), Some(value)) = (name.to_str(), value) {
+            xattrs.insert(name.to_string(), encode_hex(&value));
+        }
+    }
+
+    Ok(xattrs)
+}
+
+#[cfg(not(unix))]
+pub fn read_xattrs(_path: &Path) -> Result<BTreeMap<String, String>> {
+    Ok(BTreeMap::new())
+}
+
+/// Применяет к восстановленному файлу метаданные из записи снимка.
+/// Смена владельца и xattr выполняются по возможности: без прав root владелец
+/// не меняется, а атрибуты, которые нельзя установить (файловая система их
+/// не поддерживает или не хватает прав), пропускаются. Возвращает имена
+/// пропущенных атрибутов
+pub fn apply_metadata(path: &Path, entry: &ManifestEntry) -> Result<Vec<String>> {
+    let skipped_xattrs = apply_xattrs(path, &entry.xattrs)?;
+    apply_owner(path, entry.uid, entry.gid)?;
+
+    if let Some(mtime) = entry.mtime {
+        let modified =
+            UNIX_EPOCH + Duration::new(mtime.max(0) as u64, entry.mtime_nsec.unwrap_or(0));
+        // Владелец может менять время и без права записи: файл только для чтения
+        // (например, восстановленный поверх) открывается на чтение
+        let file =
+            File::open(path).with_context(|| format!("Failed to open file: {}", path.display()))?;
+        file.set_times(FileTimes::new().set_modified(modified))
+            .with_context(|| format!("Failed to set modification time: {}", path.display()))?;
+    }
+
+    // Права применяются последними: файл может стать доступным только для чтения
+    apply_mode(path, entry.mode)?;
+
+    Ok(skipped_xattrs)
+}
+
+#[cfg(unix)]
+fn apply_mode(path: &Path, mode: Option<u32>) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    if let Some(mode) = mode {
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))
+            .with_context(|| format!("Failed to set permissions: {}", path.display()))?;
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn apply_mode(_path: &Path, _mode: Option<u32>) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(unix)]
+fn apply_owner(path: &Path, uid: Option<u32>, gid: Option<u32>) -> Result<()> {
+    if uid.is_none() && gid.is_none() {
+        return Ok(());
+    }
+
+    match std::os::unix::fs::chown(path, uid, gid) {
+        Err(e) if e.kind() == ErrorKind::PermissionDenied => Ok(()),
+        result => result.with_context(|| format!("Failed to change owner: {}", path.display())),
+    }
+}
+
+#[cfg(not(unix))]
+fn apply_owner(_path: &Path, _uid: Option<u32>, _gid: Option<u32>) -> Result<()> {
+    Ok(())
+}
+
+/// Устанавливает xattr; возвращает имена атрибутов, которые файловая система
+/// не поддерживает или которые нельзя установить без прав (например,
+/// `security.*` и `trusted.*`, сохраненные от root)
+#[cfg(unix)]
+fn apply_xattrs(path: &Path, xattrs: &BTreeMap<String, String>) -> Result<Vec<String>> {
+    let mut skipped = Vec::new();
+    for (name, value) in xattrs {
+        let value = decode_hex(value)
+            .with_context(|| format!("Invalid value of extended attribute {}", name))?;
+        match xattr::set(path, name, &value) {
+            Ok(()) => {}
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    ErrorKind::Unsupported | ErrorKind::PermissionDenied
+                ) =>
+            {
+                skipped.push(name.clone());
+            }
+            Err(e) => {
+                return Err(e).with_context(|| {
+                    format!(
+                        "Failed to set extended attribute {}: {}",
+                        name,
+                        path.display()
+                    )
+                });
+            }
+        }
+    }
+    Ok(skipped)
+}
+
+#[cfg(not(unix))]
+fn apply_xattrs(_path: &Path, xattrs: &BTreeMap<String, String>) -> Result<Vec<String>> {
+    Ok(xattrs.keys().cloned().collect())
+}
+
+pub fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn decode_hex(hex: &str) -> Result<Vec<u8>> {
+    if !hex.is_ascii() || !hex.len().is_multiple_of(2) {
+        bail!("Invalid hex string: {:?}", hex);
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(&hex[i..i + 2], 16)
+                .with_context(|| format!("Invalid hex string: {:?}", hex))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use tempfile::TempDir;
+
+    fn test_entry() -> ManifestEntry {
+        ManifestEntry {
+            size: Some(7),
+            mtime: Some(1_000_000_000),
+            mtime_nsec: Some(123_456_789),
+            mode: Some(0o640),
+            ..ManifestEntry::from_hash(
+                PathBuf::from("file.txt"),
+                "9a0364b9e99bb480dd25e1f0284c8555".to_string(),
+            )
+        }
+    }
+
+    #[test]
+    fn test_hex_roundtrip() {
+        let bytes = [0x00, 0x7f, 0xff, 0x10];
+        assert_eq!(encode_hex(&bytes), "007fff10");
+        assert_eq!(decode_hex("007fff10").unwrap(), bytes);
+        assert!(decode_hex("abc").is_err());
+        assert!(decode_hex("zz").is_err());
+        assert!(decode_hex("žž").is_err());
+    }
+
+    #[test]
+    fn test_apply_mtime() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("file.txt");
+        fs::write(&path, "content").unwrap();
+
+        apply_metadata(&path, &test_entry()).unwrap();
+
+        let modified = fs::metadata(&path).unwrap().modified().unwrap();
+        assert_eq!(
+            modified.duration_since(UNIX_EPOCH).unwrap(),
+            Duration::new(1_000_000_000, 123_456_789)
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_apply_mode() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("file.txt");
+        fs::write(&path, "content").unwrap();
+
+        apply_metadata(&path, &test_entry()).unwrap();
+
+        assert_eq!(file_mode(&fs::metadata(&path).unwrap()), Some(0o640));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_xattrs_roundtrip() {
+        let temp_dir = TempDir::new().unwrap();
+        let source = temp_dir.path().join("source.txt");
+        let restored = temp_dir.path().join("restored.txt");
+        fs::write(&source, "content").unwrap();
+        fs::write(&restored, "content").unwrap();
+
+        // Не все файловые системы поддерживают пользовательские xattr
+        if xattr::set(&source, "user.backup_md5.test", b"value").is_err() {
+            return;
+        }
+
+        let xattrs = read_xattrs(&source).unwrap();
+        assert_eq!(
+            xattrs.get("user.backup_md5.test"),
+            Some(&encode_hex(b"value"))
+        );
+
+        let entry = ManifestEntry {
+            xattrs,
+            ..test_entry()
+        };
+        assert!(apply_metadata(&restored, &entry).unwrap().is_empty());
+
+        assert_eq!(
+            xattr::get(&restored, "user.backup_md5.test").unwrap(),
+            Some(b"value".to_vec())
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_unsupported_xattrs_are_skipped() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("file.txt");
+        fs::write(&path, "content").unwrap();
+
+        // Пространство имен, которое ядро не знает, дает ENOTSUP на любой файловой системе
+        let entry = ManifestEntry {
+            xattrs: BTreeMap::from([("unknown.backup_md5.test".to_string(), encode_hex(b"v"))]),
+            ..test_entry()
+        };
+        let skipped = apply_metadata(&path, &entry).unwrap();
+
+        assert_eq!(skipped, vec!["unknown.backup_md5.test".to_string()]);
+        // Остальные метаданные применены
+        assert_eq!(file_mode(&fs::metadata(&path).unwrap()), Some(0o640));
+    }
+}
@@ -0,0 +1,66 @@
+use anyhow::{Context, Result};
+use serde::{Serialize, Serializer};
+use std::io::{self, Write};
+use std::time::Duration;
+
+/// Сообщения ошибки от внешнего контекста к исходной причине
+pub fn error_chain(error: &anyhow::Error) -> Vec<String> {
+    error.chain().map(|cause| cause.to_string()).collect()
+}
+
+/// Ошибка, завершившая команду, для вывода в JSON
+#[derive(Debug, Serialize)]
+pub struct ErrorReport {
+    pub error: Vec<String>,
+}
+
+impl ErrorReport {
+    pub fn new(error: &anyhow::Error) -> Self {
+        Self {
+            error: error_chain(error),
+        }
+    }
+}
+
+/// Выводит значение одним JSON-документом
+pub fn print_json<T: Serialize>(value: &T) -> Result<()> {
+    print_json_lines([value])
+}
+
+/// Выводит значения в формате JSON Lines: по одному JSON-объекту на строку
+pub fn print_json_lines<T: Serialize>(values: impl IntoIterator<Item = T>) -> Result<()> {
+    let mut stdout = io::stdout().lock();
+    for value in values {
+        serde_json::to_writer(&mut stdout, &value).context("Failed to serialize output")?;
+        writeln!(stdout).context("Failed to write output")?;
+    }
+    Ok(())
+}
+
+/// Сериализует длительность как число секунд
+pub fn serialize_secs<S: Serializer>(
+    duration: &Duration,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(duration.as_secs_f64())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+
+    #[test]
+    fn test_error_chain() {
+        let error = anyhow!("Permission denied").context("Failed to open file: a.txt");
+
+        assert_eq!(
+            error_chain(&error),
+            vec!["Failed to open file: a.txt", "Permission denied"]
+        );
+        assert_eq!(
+            serde_json::to_string(&ErrorReport::new(&error)).unwrap(),
+            r#"{"error":["Failed to open file: a.txt","Permission denied"]}"#
+        );
+    }
+}
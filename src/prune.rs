@@ -0,0 +1,507 @@
+use crate::config::{InvalidValue, KeyPart};
+use crate::directory;
+use crate::repository::Repository;
+use anyhow::{bail, Context, Result};
+use chrono::{Datelike, Duration, NaiveDateTime};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::HashSet;
+use std::fs;
+
+/// Префикс, с которым снимок переименовывается перед удалением
+const REMOVING_PREFIX: &str = ".tmp-prune-";
+
+/// Правила хранения снимков. Снимок сохраняется, если его оставляет хотя бы одно правило
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct RetentionPolicy {
+    /// Последние N снимков
+    pub keep_last: Option<usize>,
+    /// Последний снимок за каждый из N последних дней, в которые были снимки
+    pub keep_daily: Option<usize>,
+    /// Последний снимок за каждую из N последних недель (ISO)
+    pub keep_weekly: Option<usize>,
+    /// Последний снимок за каждый из N последних месяцев
+    pub keep_monthly: Option<usize>,
+    /// Все снимки моложе указанного срока
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub keep_within: Option<Duration>,
+}
+
+impl RetentionPolicy {
+    pub fn is_empty(&self) -> bool {
+        self.keep_last.is_none()
+            && self.keep_daily.is_none()
+            && self.keep_weekly.is_none()
+            && self.keep_monthly.is_none()
+            && self.keep_within.is_none()
+    }
+
+    /// Нулевое число снимков не оставляет ни одного снимка по этому правилу, но
+    /// делает политику непустой: одно такое правило удалило бы все снимки
+    pub fn validate(&self) -> Result<()> {
+        for (name, count) in [
+            ("keep_last", self.keep_last),
+            ("keep_daily", self.keep_daily),
+            ("keep_weekly", self.keep_weekly),
+            ("keep_monthly", self.keep_monthly),
+        ] {
+            if count == Some(0) {
+                return Err(InvalidValue::new(
+                    vec![
+                        KeyPart::Field("retention".to_string()),
+                        KeyPart::Field(name.to_string()),
+                    ],
+                    format!("{} must be at least 1", name),
+                )
+                .into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Правила, не заданные в `self`, берутся из `defaults`
+    pub fn or(self, defaults: RetentionPolicy) -> Self {
+        Self {
+            keep_last: self.keep_last.or(defaults.keep_last),
+            keep_daily: self.keep_daily.or(defaults.keep_daily),
+            keep_weekly: self.keep_weekly.or(defaults.keep_weekly),
+            keep_monthly: self.keep_monthly.or(defaults.keep_monthly),
+            keep_within: self.keep_within.or(defaults.keep_within),
+        }
+    }
+}
+
+/// Решение по одному снимку: пустой список причин означает удаление
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PruneDecision {
+    pub snapshot: String,
+    pub reasons: Vec<String>,
+    /// Запись снимка прервана: он удаляется без учета правил хранения
+    pub incomplete: bool,
+}
+
+impl PruneDecision {
+    pub fn keep(&self) -> bool {
+        !self.reasons.is_empty()
+    }
+}
+
+/// Применяет правила хранения к снимкам хранилища и удаляет лишние (кроме dry_run).
+/// Незавершенные снимки удаляются всегда: пока хранилище заблокировано, ни один
+/// запуск не может их дописывать. Решения возвращаются от новых снимков к старым
+pub fn prune_snapshots(
+    target_base: &str,
+    policy: &RetentionPolicy,
+    now: NaiveDateTime,
+    dry_run: bool,
+) -> Result<Vec<PruneDecision>> {
+    if policy.is_empty() {
+        bail!("No retention rules given, refusing to remove all snapshots");
+    }
+    policy.validate()?;
+
+    let repository = Repository::open(target_base)?;
+    let _lock = repository.lock()?;
+    let mut decisions = evaluate(&repository.list_snapshots()?, policy, now);
+    decisions.extend(
+        repository
+            .list_incomplete_snapshots()?
+            .into_iter()
+            .map(|snapshot| PruneDecision {
+                snapshot,
+                reasons: Vec::new(),
+                incomplete: true,
+            }),
+    );
+    // Имена - временные метки одного формата: порядок строк совпадает с порядком времени
+    decisions.sort_by(|a, b| b.snapshot.cmp(&a.snapshot));
+
+    if !dry_run {
+        remove_interrupted(&repository)?;
+        for decision in decisions.iter().filter(|d| !d.keep()) {
+            remove_snapshot(&repository, &decision.snapshot)?;
+        }
+    }
+
+    Ok(decisions)
+}
+
+/// Удаляет снимок. Директория сначала уходит из пространства имен снимков, поэтому
+/// прерванное удаление не оставляет снимок, из которого удалена часть файлов
+fn remove_snapshot(repository: &Repository, name: &str) -> Result<()> {
+    let path = repository.snapshot_dir(name);
+    let removing = repository
+        .root()
+        .join(format!("{}{}", REMOVING_PREFIX, name));
+    fs::rename(&path, &removing)
+        .with_context(|| format!("Failed to remove snapshot: {}", path.display()))?;
+    directory::sync_dir(repository.root())?;
+    fs::remove_dir_all(&removing)
+        .with_context(|| format!("Failed to remove snapshot: {}", path.display()))
+}
+
+/// Дочищает снимки, удаление которых прервал предыдущий запуск
+fn remove_interrupted(repository: &Repository) -> Result<()> {
+    let root = repository.root();
+    for entry in fs::read_dir(root)
+        .with_context(|| format!("Failed to read directory: {}", root.display()))?
+    {
+        let entry =
+            entry.with_context(|| format!("Failed to read directory: {}", root.display()))?;
+        if entry
+            .file_name()
+            .to_string_lossy()
+            .starts_with(REMOVING_PREFIX)
+        {
+            fs::remove_dir_all(entry.path()).with_context(|| {
+                format!("Failed to remove directory: {}", entry.path().display())
+            })?;
+        }
+    }
+    Ok(())
+}
+
+/// Вычисляет решения для снимков с именами-временными метками
+pub fn evaluate(
+    snapshots: &[String],
+    policy: &RetentionPolicy,
+    now: NaiveDateTime,
+) -> Vec<PruneDecision> {
+    let mut dated: Vec<(&String, NaiveDateTime)> = snapshots
+        .iter()
+        .filter_map(|name| directory::parse_timestamp(name).map(|ts| (name, ts)))
+        .collect();
+    dated.sort_by_key(|&(_, timestamp)| std::cmp::Reverse(timestamp));
+
+    let mut daily = BucketRule::new("daily", policy.keep_daily);
+    let mut weekly = BucketRule::new("weekly", policy.keep_weekly);
+    let mut monthly = BucketRule::new("monthly", policy.keep_monthly);
+
+    dated
+        .into_iter()
+        .enumerate()
+        .map(|(index, (name, timestamp))| {
+            let mut reasons = Vec::new();
+
+            if let Some(n) = policy.keep_last
+                && index < n
+            {
+                reasons.push(format!("last {}", n));
+            }
+            if let Some(within) = policy.keep_within
+                && now - timestamp <= within
+            {
+                reasons.push(format!("within {}", format_duration(within)));
+            }
+
+            let date = timestamp.date();
+            let week = date.iso_week();
+            reasons.extend(daily.apply(date.to_string()));
+            reasons.extend(weekly.apply(format!("{}-W{:02}", week.year(), week.week())));
+            reasons.extend(monthly.apply(format!("{}-{:02}", date.year(), date.month())));
+
+            PruneDecision {
+                snapshot: name.clone(),
+                reasons,
+                incomplete: false,
+            }
+        })
+        .collect()
+}
+
+/// Правило «последний снимок в каждом из N периодов».
+/// Снимки подаются от новых к старым, поэтому первый снимок периода - самый новый
+struct BucketRule {
+    name: &'static str,
+    limit: usize,
+    seen: HashSet<String>,
+}
+
+impl BucketRule {
+    fn new(name: &'static str, limit: Option<usize>) -> Self {
+        Self {
+            name,
+            limit: limit.unwrap_or(0),
+            seen: HashSet::new(),
+        }
+    }
+
+    fn apply(&mut self, bucket: String) -> Option<String> {
+        if self.seen.len() >= self.limit || self.seen.contains(&bucket) {
+            return None;
+        }
+
+        let reason = format!("{} {}", self.name, bucket);
+        self.seen.insert(bucket);
+        Some(reason)
+    }
+}
+
+/// Разбирает срок вида 12h, 30d, 4w, 6m (30 дней) или 1y (365 дней).
+/// Срок должен быть положительным: отрицательный отодвинул бы границу в будущее
+pub fn parse_duration(value: &str) -> Result<Duration> {
+    let value = value.trim();
+    let Some(unit) = value.chars().last() else {
+        bail!("Empty duration");
+    };
+    let amount: i64 = value[..value.len() - unit.len_utf8()]
+        .parse()
+        .with_context(|| format!("Invalid duration: {:?}", value))?;
+    if amount <= 0 {
+        bail!("Duration must be positive: {:?}", value);
+    }
+
+    let duration = match unit {
+        'h' => Duration::try_hours(amount),
+        'd' => Duration::try_days(amount),
+        'w' => Duration::try_weeks(amount),
+        'm' => amount.checked_mul(30).and_then(Duration::try_days),
+        'y' => amount.checked_mul(365).and_then(Duration::try_days),
+        _ => bail!(
+            "Invalid duration unit in {:?}, expected h, d, w, m or y",
+            value
+        ),
+    };
+    duration.with_context(|| format!("Duration is too large: {:?}", value))
+}
+
+/// Срок в конфигурации записывается так же, как в `--keep-within`: "30d", "12h"
+fn deserialize_duration<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Duration>, D::Error> {
+    Option::<String>::deserialize(deserializer)?
+        .map(|value| parse_duration(&value).map_err(serde::de::Error::custom))
+        .transpose()
+}
+
+fn format_duration(duration: Duration) -> String {
+    if duration.num_hours() % 24 == 0 {
+        format!("{}d", duration.num_days())
+    } else {
+        format!("{}h", duration.num_hours())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::snapshot::MANIFEST_FILE;
+    use tempfile::TempDir;
+
+    fn timestamp(name: &str) -> NaiveDateTime {
+        directory::parse_timestamp(name).unwrap()
+    }
+
+    fn names(list: &[&str]) -> Vec<String> {
+        list.iter().map(|s| s.to_string()).collect()
+    }
+
+    fn kept(decisions: &[PruneDecision]) -> Vec<&str> {
+        decisions
+            .iter()
+            .filter(|d| d.keep())
+            .map(|d| d.snapshot.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn test_keep_last() {
+        let snapshots = names(&["20240101_000000", "20240102_000000", "20240103_000000"]);
+        let policy = RetentionPolicy {
+            keep_last: Some(2),
+            ..Default::default()
+        };
+
+        let decisions = evaluate(&snapshots, &policy, timestamp("20240104_000000"));
+
+        assert_eq!(kept(&decisions), vec!["20240103_000000", "20240102_000000"]);
+        assert_eq!(decisions[0].reasons, vec!["last 2"]);
+    }
+
+    #[test]
+    fn test_policy_or() {
+        let given = RetentionPolicy {
+            keep_last: Some(3),
+            ..Default::default()
+        };
+        let defaults = RetentionPolicy {
+            keep_last: Some(10),
+            keep_within: Some(Duration::days(30)),
+            ..Default::default()
+        };
+
+        let policy = given.or(defaults);
+
+        assert_eq!(policy.keep_last, Some(3));
+        assert_eq!(policy.keep_within, Some(Duration::days(30)));
+        assert_eq!(policy.keep_daily, None);
+    }
+
+    #[test]
+    fn test_keep_daily_takes_newest_of_day() {
+        let snapshots = names(&[
+            "20240101_080000",
+            "20240101_200000",
+            "20240102_080000",
+            "20240102_200000",
+            "20240103_080000",
+        ]);
+        let policy = RetentionPolicy {
+            keep_daily: Some(2),
+            ..Default::default()
+        };
+
+        let decisions = evaluate(&snapshots, &policy, timestamp("20240104_000000"));
+
+        assert_eq!(kept(&decisions), vec!["20240103_080000", "20240102_200000"]);
+        assert_eq!(decisions[1].reasons, vec!["daily 2024-01-02"]);
+    }
+
+    #[test]
+    fn test_keep_weekly_and_monthly() {
+        let snapshots = names(&[
+            "20240105_000000", // неделя 1, январь
+            "20240112_000000", // неделя 2, январь
+            "20240114_000000", // неделя 2, январь
+            "20240220_000000", // неделя 8, февраль
+        ]);
+        let policy = RetentionPolicy {
+            keep_weekly: Some(2),
+            keep_monthly: Some(2),
+            ..Default::default()
+        };
+
+        let decisions = evaluate(&snapshots, &policy, timestamp("20240301_000000"));
+
+        assert_eq!(kept(&decisions), vec!["20240220_000000", "20240114_000000"]);
+        assert_eq!(
+            decisions[1].reasons,
+            vec!["weekly 2024-W02", "monthly 2024-01"]
+        );
+    }
+
+    #[test]
+    fn test_keep_within() {
+        let snapshots = names(&["20240101_000000", "20240125_000000", "20240130_000000"]);
+        let policy = RetentionPolicy {
+            keep_within: Some(Duration::days(7)),
+            ..Default::default()
+        };
+
+        let decisions = evaluate(&snapshots, &policy, timestamp("20240131_000000"));
+
+        assert_eq!(kept(&decisions), vec!["20240130_000000", "20240125_000000"]);
+        assert_eq!(decisions[0].reasons, vec!["within 7d"]);
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("12h").unwrap(), Duration::hours(12));
+        assert_eq!(parse_duration("30d").unwrap(), Duration::days(30));
+        assert_eq!(parse_duration("2w").unwrap(), Duration::weeks(2));
+        assert_eq!(parse_duration("1m").unwrap(), Duration::days(30));
+        assert_eq!(parse_duration("1y").unwrap(), Duration::days(365));
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("10").is_err());
+        assert!(parse_duration("xd").is_err());
+        assert!(parse_duration("-5d").is_err());
+        assert!(parse_duration("0h").is_err());
+        assert!(parse_duration("99999999999999y").is_err());
+    }
+
+    #[test]
+    fn test_prune_removes_snapshots() {
+        let temp_dir = TempDir::new().unwrap();
+        let target = temp_dir.path().to_str().unwrap();
+        Repository::open_or_init(target, None).unwrap();
+        for name in ["20240101_000000", "20240102_000000", "20240103_000000"] {
+            fs::create_dir(temp_dir.path().join(name)).unwrap();
+            fs::write(temp_dir.path().join(name).join(MANIFEST_FILE), "").unwrap();
+        }
+        // Директория, прерванная до создания манифеста, не занимает место keep-last
+        // и удаляется независимо от правил
+        fs::create_dir(temp_dir.path().join("20240103_120000")).unwrap();
+
+        let policy = RetentionPolicy {
+            keep_last: Some(1),
+            ..Default::default()
+        };
+        let now = timestamp("20240104_000000");
+
+        let decisions = prune_snapshots(target, &policy, now, true).unwrap();
+        assert_eq!(decisions.iter().filter(|d| !d.keep()).count(), 3);
+        assert_eq!(decisions[0].snapshot, "20240103_120000");
+        assert!(decisions[0].incomplete);
+        assert_eq!(decisions[1].reasons, vec!["last 1"]);
+        assert!(temp_dir.path().join("20240101_000000").exists());
+        assert!(temp_dir.path().join("20240103_120000").exists());
+
+        prune_snapshots(target, &policy, now, false).unwrap();
+        assert!(!temp_dir.path().join("20240101_000000").exists());
+        assert!(!temp_dir.path().join("20240102_000000").exists());
+        assert!(!temp_dir.path().join("20240103_120000").exists());
+        assert!(temp_dir.path().join("20240103_000000").exists());
+    }
+
+    #[test]
+    fn test_prune_finishes_interrupted_removal() {
+        let temp_dir = TempDir::new().unwrap();
+        let target = temp_dir.path().to_str().unwrap();
+        Repository::open_or_init(target, None).unwrap();
+        fs::create_dir(temp_dir.path().join("20240102_000000")).unwrap();
+        fs::write(
+            temp_dir.path().join("20240102_000000").join(MANIFEST_FILE),
+            "",
+        )
+        .unwrap();
+        // Удаление прервано после manifest.jsonl: остаток вне пространства имен снимков
+        let leftover = temp_dir
+            .path()
+            .join(format!("{}20240101_000000", REMOVING_PREFIX));
+        fs::create_dir(&leftover).unwrap();
+        fs::write(leftover.join("summary.txt"), "Scanned: 1").unwrap();
+
+        let repository = Repository::open(target).unwrap();
+        assert_eq!(
+            repository.list_snapshots().unwrap(),
+            vec!["20240102_000000"]
+        );
+        assert!(repository.list_incomplete_snapshots().unwrap().is_empty());
+
+        let policy = RetentionPolicy {
+            keep_last: Some(1),
+            ..Default::default()
+        };
+        prune_snapshots(target, &policy, timestamp("20240104_000000"), false).unwrap();
+        assert!(!leftover.exists());
+        assert!(temp_dir.path().join("20240102_000000").exists());
+    }
+
+    #[test]
+    fn test_prune_requires_rules() {
+        let temp_dir = TempDir::new().unwrap();
+        let target = temp_dir.path().to_str().unwrap();
+        Repository::open_or_init(target, None).unwrap();
+
+        let result = prune_snapshots(
+            target,
+            &RetentionPolicy::default(),
+            timestamp("20240104_000000"),
+            false,
+        );
+        assert!(result.is_err());
+
+        // Нулевое правило не считается политикой хранения
+        let policy = RetentionPolicy {
+            keep_last: Some(0),
+            ..Default::default()
+        };
+        fs::create_dir(temp_dir.path().join("20240101_000000")).unwrap();
+        let result = prune_snapshots(target, &policy, timestamp("20240104_000000"), false);
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .contains("keep_last must be at least 1"));
+        assert!(temp_dir.path().join("20240101_000000").exists());
+    }
+}
@@ -0,0 +1,531 @@
+use crate::directory;
+use crate::hash::HashAlgorithm;
+use crate::snapshot::{self, ManifestEntry};
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fmt;
+use std::fs::{self, create_dir_all, File, OpenOptions, TryLockError};
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+/// Файл с метаданными хранилища в корне target_base
+pub const METADATA_FILE: &str = "repository.toml";
+
+/// Файл блокировки в корне target_base
+pub const LOCK_FILE: &str = "lock";
+
+#[derive(Debug, Serialize, Deserialize)]
+struct RepositoryMetadata {
+    hash_algorithm: HashAlgorithm,
+}
+
+/// Хранилище занято другим процессом (резервным копированием, gc, prune или verify)
+#[derive(Debug)]
+pub struct RepositoryLocked {
+    pub path: PathBuf,
+}
+
+impl fmt::Display for RepositoryLocked {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Repository is locked by another process: {}",
+            self.path.display()
+        )
+    }
+}
+
+impl std::error::Error for RepositoryLocked {}
+
+/// Эксклюзивная блокировка хранилища. Снимается, когда значение удаляется
+/// или процесс завершается, поэтому файл блокировки после сбоя не мешает
+#[derive(Debug)]
+pub struct RepositoryLock {
+    _file: File,
+}
+
+/// Содержимое файла в хранилище
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FileObject {
+    /// Целый объект в objects_dir
+    Whole(PathBuf),
+    /// Хеши фрагментов по порядку; содержимое файла - их конкатенация
+    Chunked(Vec<String>),
+}
+
+/// Хранилище резервных копий: объекты по хешам и снимки с временными метками
+#[derive(Debug, Clone)]
+pub struct Repository {
+    root: PathBuf,
+    algorithm: HashAlgorithm,
+}
+
+impl Repository {
+    /// Открывает существующее хранилище.
+    /// Хранилища без метаданных, созданные до выбора алгоритма, считаются MD5-хранилищами
+    pub fn open(target_base: &str) -> Result<Self> {
+        let root = PathBuf::from(target_base);
+
+        if let Some(algorithm) = read_metadata(&root)? {
+            return Ok(Self { root, algorithm });
+        }
+        if is_legacy_md5(&root) {
+            return Ok(Self {
+                root,
+                algorithm: HashAlgorithm::Md5,
+            });
+        }
+
+        bail!("Not a backup repository: {}", root.display())
+    }
+
+    /// Открывает хранилище или создает новое с алгоритмом `algorithm` (MD5 по умолчанию).
+    /// Если хранилище уже существует, его алгоритм должен совпадать с запрошенным
+    pub fn open_or_init(target_base: &str, algorithm: Option<HashAlgorithm>) -> Result<Self> {
+        let repository = Self::open_or_new(target_base, algorithm)?;
+
+        directory::create_directories(&[&repository.objects_dir()])?;
+        repository.write_metadata()?;
+
+        Ok(repository)
+    }
+
+    /// Как open_or_init, но ничего не создает на диске: для несуществующего
+    /// хранилища возвращается описание того, каким оно будет создано
+    pub fn open_or_new(target_base: &str, algorithm: Option<HashAlgorithm>) -> Result<Self> {
+        let root = PathBuf::from(target_base);
+
+        let existing = match read_metadata(&root)? {
+            Some(algorithm) => Some(algorithm),
+            None if is_legacy_md5(&root) => Some(HashAlgorithm::Md5),
+            None => None,
+        };
+
+        let repository = Self {
+            algorithm: existing.or(algorithm).unwrap_or_default(),
+            root,
+        };
+
+        if let (Some(existing), Some(requested)) = (existing, algorithm)
+            && existing != requested
+        {
+            bail!(
+                "Repository {} uses {} but {} was requested",
+                repository.root.display(),
+                existing,
+                requested
+            );
+        }
+
+        Ok(repository)
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn algorithm(&self) -> HashAlgorithm {
+        self.algorithm
+    }
+
+    /// Захватывает хранилище для изменения; если оно уже захвачено, возвращает
+    /// ошибку RepositoryLocked, не дожидаясь освобождения
+    pub fn lock(&self) -> Result<RepositoryLock> {
+        let path = self.root.join(LOCK_FILE);
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)
+            .with_context(|| format!("Failed to open file: {}", path.display()))?;
+
+        match file.try_lock() {
+            Ok(()) => Ok(RepositoryLock { _file: file }),
+            Err(TryLockError::WouldBlock) => Err(RepositoryLocked { path }.into()),
+            Err(TryLockError::Error(e)) => {
+                Err(e).with_context(|| format!("Failed to lock {}", path.display()))
+            }
+        }
+    }
+
+    /// Директория объектов, например files_by_md5 или files_by_sha256
+    pub fn objects_dir(&self) -> PathBuf {
+        self.root
+            .join(format!("files_by_{}", self.algorithm.name()))
+    }
+
+    pub fn object_path(&self, hash_hex: &str) -> PathBuf {
+        directory::object_path(&self.objects_dir(), hash_hex)
+    }
+
+    /// Директория списков фрагментов, например chunked_by_md5. Список называется
+    /// хешем всего файла, а сами фрагменты хранятся обычными объектами в objects_dir
+    pub fn chunk_lists_dir(&self) -> PathBuf {
+        self.root
+            .join(format!("chunked_by_{}", self.algorithm.name()))
+    }
+
+    pub fn chunk_list_path(&self, hash_hex: &str) -> PathBuf {
+        directory::object_path(&self.chunk_lists_dir(), hash_hex)
+    }
+
+    /// Содержимое с таким хешем есть в хранилище целым объектом или списком фрагментов
+    pub fn has_object(&self, hash_hex: &str) -> bool {
+        self.object_path(hash_hex).is_file() || self.chunk_list_path(hash_hex).is_file()
+    }
+
+    /// Находит содержимое файла по хешу; None, если его нет ни в каком виде.
+    /// Целый объект предпочтительнее списка фрагментов
+    pub fn find_object(&self, hash_hex: &str) -> Result<Option<FileObject>> {
+        let object = self.object_path(hash_hex);
+        if object.is_file() {
+            return Ok(Some(FileObject::Whole(object)));
+        }
+        if self.chunk_list_path(hash_hex).is_file() {
+            return Ok(Some(FileObject::Chunked(self.read_chunk_list(hash_hex)?)));
+        }
+        Ok(None)
+    }
+
+    /// Читает список фрагментов файла: хеши фрагментов по порядку, по одному в строке
+    pub fn read_chunk_list(&self, hash_hex: &str) -> Result<Vec<String>> {
+        self.read_chunk_list_file(&self.chunk_list_path(hash_hex))
+    }
+
+    /// Читает список фрагментов из произвольного файла, например еще не
+    /// переименованного временного
+    pub fn read_chunk_list_file(&self, path: &Path) -> Result<Vec<String>> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read chunk list: {}", path.display()))?;
+
+        content
+            .lines()
+            .enumerate()
+            .map(|(index, line)| {
+                if !self.algorithm.is_valid_hex(line) {
+                    bail!(
+                        "Invalid chunk list {}: line {} is not a {} hash",
+                        path.display(),
+                        index + 1,
+                        self.algorithm
+                    );
+                }
+                Ok(line.to_string())
+            })
+            .collect()
+    }
+
+    pub fn snapshot_dir(&self, name: &str) -> PathBuf {
+        self.root.join(name)
+    }
+
+    /// Читает записи снимка по имени; отсутствующий снимок - ошибка
+    pub fn read_snapshot(&self, name: &str) -> Result<Vec<ManifestEntry>> {
+        let snapshot_dir = self.snapshot_dir(name);
+        if !snapshot_dir.is_dir() {
+            bail!("Snapshot not found: {}", snapshot_dir.display());
+        }
+
+        snapshot::read_snapshot(&snapshot_dir, self.algorithm)
+            .with_context(|| format!("Failed to read snapshot {}", name))
+    }
+
+    /// Размер файла записи: из манифеста, а для снимков старого формата - по объекту
+    pub fn entry_size(&self, entry: &ManifestEntry) -> u64 {
+        if entry.link_target.is_some() {
+            return 0;
+        }
+        entry.size.unwrap_or_else(|| {
+            fs::metadata(self.object_path(&entry.hash))
+                .map(|m| m.len())
+                .unwrap_or(0)
+        })
+    }
+
+    /// Имена завершенных снимков (директорий с временной меткой), от старых к новым
+    pub fn list_snapshots(&self) -> Result<Vec<String>> {
+        let mut snapshots = self.list_snapshot_dirs()?;
+        snapshots.retain(|name| !snapshot::is_incomplete(&self.snapshot_dir(name)));
+        Ok(snapshots)
+    }
+
+    /// Снимки, запись которых прервана: они не читаются и не учитываются
+    /// командами, работающими со снимками
+    pub fn list_incomplete_snapshots(&self) -> Result<Vec<String>> {
+        let mut snapshots = self.list_snapshot_dirs()?;
+        snapshots.retain(|name| snapshot::is_incomplete(&self.snapshot_dir(name)));
+        Ok(snapshots)
+    }
+
+    fn list_snapshot_dirs(&self) -> Result<Vec<String>> {
+        let mut snapshots = Vec::new();
+
+        for entry in fs::read_dir(&self.root)
+            .with_context(|| format!("Failed to read directory: {}", self.root.display()))?
+        {
+            let entry = entry
+                .with_context(|| format!("Failed to read directory: {}", self.root.display()))?;
+            let Some(name) = entry.file_name().to_str().map(str::to_string) else {
+                continue;
+            };
+            if entry.path().is_dir() && directory::parse_timestamp(&name).is_some() {
+                snapshots.push(name);
+            }
+        }
+
+        snapshots.sort();
+        Ok(snapshots)
+    }
+
+    /// Все объекты хранилища (целые файлы и фрагменты): (хеш, путь). Файлы с именами,
+    /// не похожими на хеш алгоритма хранилища, пропускаются
+    pub fn list_objects(&self) -> Result<Vec<(String, PathBuf)>> {
+        self.list_hashed_files(&self.objects_dir())
+    }
+
+    /// Все списки фрагментов: (хеш файла, путь к списку)
+    pub fn list_chunk_lists(&self) -> Result<Vec<(String, PathBuf)>> {
+        let dir = self.chunk_lists_dir();
+        if !dir.exists() {
+            return Ok(Vec::new());
+        }
+        self.list_hashed_files(&dir)
+    }
+
+    fn list_hashed_files(&self, dir: &Path) -> Result<Vec<(String, PathBuf)>> {
+        let mut objects = Vec::new();
+
+        for entry in WalkDir::new(dir).min_depth(2).max_depth(2) {
+            let entry =
+                entry.with_context(|| format!("Failed to read directory: {}", dir.display()))?;
+            let Some(name) = entry.file_name().to_str() else {
+                continue;
+            };
+            if entry.file_type().is_file() && self.algorithm.is_valid_hex(name) {
+                objects.push((name.to_string(), entry.into_path()));
+            }
+        }
+
+        objects.sort();
+        Ok(objects)
+    }
+
+    fn write_metadata(&self) -> Result<()> {
+        let path = self.root.join(METADATA_FILE);
+        if path.exists() {
+            return Ok(());
+        }
+
+        create_dir_all(&self.root)
+            .with_context(|| format!("Failed to create directory: {}", self.root.display()))?;
+
+        let metadata = RepositoryMetadata {
+            hash_algorithm: self.algorithm,
+        };
+        let content = toml::to_string(&metadata)
+            .with_context(|| format!("Failed to serialize {}", path.display()))?;
+        fs::write(&path, content)
+            .with_context(|| format!("Failed to write file: {}", path.display()))?;
+
+        Ok(())
+    }
+}
+
+/// Добавляет к `referenced` (хешам содержимого из записей снимков) фрагменты
+/// каждого ссылаемого списка фрагментов, даже если то же содержимое хранится и
+/// целым объектом: так gc, verify и stats считают используемыми одни и те же
+/// объекты. `chunk_lists` - хеши файлов, сохраненных фрагментами, а
+/// `read_chunk_list` возвращает фрагменты списка по хешу файла
+pub fn add_referenced_chunks<'a>(
+    referenced: &mut HashSet<String>,
+    chunk_lists: impl IntoIterator<Item = &'a str>,
+    mut read_chunk_list: impl FnMut(&str) -> Result<Vec<String>>,
+) -> Result<()> {
+    let mut chunks = Vec::new();
+    for hash_hex in chunk_lists {
+        if referenced.contains(hash_hex) {
+            chunks.extend(read_chunk_list(hash_hex)?);
+        }
+    }
+    referenced.extend(chunks);
+    Ok(())
+}
+
+fn read_metadata(root: &Path) -> Result<Option<HashAlgorithm>> {
+    let path = root.join(METADATA_FILE);
+    if !path.exists() {
+        return Ok(None);
+    }
+
+    let content = fs::read_to_string(&path)
+        .with_context(|| format!("Failed to read file: {}", path.display()))?;
+    let metadata: RepositoryMetadata = toml::from_str(&content)
+        .with_context(|| format!("Failed to parse repository metadata: {}", path.display()))?;
+
+    Ok(Some(metadata.hash_algorithm))
+}
+
+fn is_legacy_md5(root: &Path) -> bool {
+    root.join("files_by_md5").is_dir()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_init_default_md5() {
+        let temp_dir = TempDir::new().unwrap();
+        let target = temp_dir.path().join("target");
+
+        let repository = Repository::open_or_init(target.to_str().unwrap(), None).unwrap();
+
+        assert_eq!(repository.algorithm(), HashAlgorithm::Md5);
+        assert!(target.join("files_by_md5").is_dir());
+        assert!(target.join(METADATA_FILE).is_file());
+    }
+
+    #[test]
+    fn test_init_sha256_and_reopen() {
+        let temp_dir = TempDir::new().unwrap();
+        let target = temp_dir.path().join("target");
+        let target = target.to_str().unwrap();
+
+        Repository::open_or_init(target, Some(HashAlgorithm::Sha256)).unwrap();
+
+        let repository = Repository::open(target).unwrap();
+        assert_eq!(repository.algorithm(), HashAlgorithm::Sha256);
+        assert_eq!(
+            repository.objects_dir(),
+            Path::new(target).join("files_by_sha256")
+        );
+
+        // Алгоритм не указан - используется алгоритм хранилища
+        let repository = Repository::open_or_init(target, None).unwrap();
+        assert_eq!(repository.algorithm(), HashAlgorithm::Sha256);
+    }
+
+    #[test]
+    fn test_algorithm_mismatch() {
+        let temp_dir = TempDir::new().unwrap();
+        let target = temp_dir.path().to_str().unwrap();
+
+        Repository::open_or_init(target, Some(HashAlgorithm::Blake3)).unwrap();
+        let result = Repository::open_or_init(target, Some(HashAlgorithm::Md5));
+
+        assert!(result.is_err());
+        assert!(result.unwrap_err().to_string().contains("uses blake3"));
+    }
+
+    #[test]
+    fn test_open_or_new_creates_nothing() {
+        let temp_dir = TempDir::new().unwrap();
+        let target = temp_dir.path().join("target");
+
+        let repository =
+            Repository::open_or_new(target.to_str().unwrap(), Some(HashAlgorithm::Sha256)).unwrap();
+
+        assert_eq!(repository.algorithm(), HashAlgorithm::Sha256);
+        assert!(!target.exists());
+    }
+
+    #[test]
+    fn test_lock_is_exclusive() {
+        let temp_dir = TempDir::new().unwrap();
+        let target = temp_dir.path().to_str().unwrap();
+        let repository = Repository::open_or_init(target, None).unwrap();
+
+        let lock = repository.lock().unwrap();
+        let error = repository.lock().unwrap_err();
+        assert!(error.is::<RepositoryLocked>());
+
+        // После снятия блокировки хранилище снова можно захватить
+        drop(lock);
+        assert!(repository.lock().is_ok());
+    }
+
+    #[test]
+    fn test_legacy_md5_repository() {
+        let temp_dir = TempDir::new().unwrap();
+        fs::create_dir(temp_dir.path().join("files_by_md5")).unwrap();
+        let target = temp_dir.path().to_str().unwrap();
+
+        let repository = Repository::open(target).unwrap();
+        assert_eq!(repository.algorithm(), HashAlgorithm::Md5);
+        assert!(!temp_dir.path().join(METADATA_FILE).exists());
+
+        assert!(Repository::open_or_init(target, Some(HashAlgorithm::Sha256)).is_err());
+    }
+
+    #[test]
+    fn test_open_missing_repository() {
+        let temp_dir = TempDir::new().unwrap();
+        let result = Repository::open(temp_dir.path().to_str().unwrap());
+
+        assert!(result.is_err());
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .contains("Not a backup repository"));
+    }
+
+    #[test]
+    fn test_list_snapshots() {
+        let temp_dir = TempDir::new().unwrap();
+        let repository = Repository::open_or_init(temp_dir.path().to_str().unwrap(), None).unwrap();
+        for name in ["20240102_000000", "20240101_120000"] {
+            fs::create_dir(temp_dir.path().join(name)).unwrap();
+            fs::write(temp_dir.path().join(name).join(snapshot::MANIFEST_FILE), "").unwrap();
+        }
+        // Прервана сразу после создания директории снимка
+        fs::create_dir(temp_dir.path().join("20240105_000000")).unwrap();
+        fs::create_dir(temp_dir.path().join("trash")).unwrap();
+        fs::write(temp_dir.path().join("20240103_000000"), "not a directory").unwrap();
+        let interrupted = temp_dir.path().join("20240104_000000");
+        fs::create_dir(&interrupted).unwrap();
+        fs::write(
+            interrupted.join(format!("{}abc", snapshot::TEMP_MANIFEST_PREFIX)),
+            "{\"path\":",
+        )
+        .unwrap();
+
+        assert_eq!(
+            repository.list_snapshots().unwrap(),
+            vec!["20240101_120000", "20240102_000000"]
+        );
+        assert_eq!(
+            repository.list_incomplete_snapshots().unwrap(),
+            vec!["20240104_000000", "20240105_000000"]
+        );
+    }
+
+    #[test]
+    fn test_list_objects() {
+        let temp_dir = TempDir::new().unwrap();
+        let repository = Repository::open_or_init(temp_dir.path().to_str().unwrap(), None).unwrap();
+        let object = repository.object_path("098f6bcd4621d373cade4e832627b4f6");
+        fs::create_dir_all(object.parent().unwrap()).unwrap();
+        fs::write(&object, "test").unwrap();
+        fs::write(object.with_file_name("unrelated.tmp"), "tmp").unwrap();
+
+        assert_eq!(
+            repository.list_objects().unwrap(),
+            vec![("098f6bcd4621d373cade4e832627b4f6".to_string(), object)]
+        );
+    }
+
+    #[test]
+    fn test_object_path() {
+        let temp_dir = TempDir::new().unwrap();
+        let repository = Repository::open_or_init(temp_dir.path().to_str().unwrap(), None).unwrap();
+
+        assert_eq!(
+            repository.object_path("098f6bcd4621d373cade4e832627b4f6"),
+            temp_dir
+                .path()
+                .join("files_by_md5/09/098f6bcd4621d373cade4e832627b4f6")
+        );
+    }
+}
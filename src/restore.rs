@@ -0,0 +1,628 @@
+use crate::repository::{FileObject, Repository};
+use crate::{chunking, metadata, snapshot};
+use anyhow::{bail, Context, Result};
+use serde::Serialize;
+use std::fs::{self, create_dir_all, File};
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+/// Итоги восстановления снимка
+#[derive(Debug, Default, Serialize)]
+pub struct RestoreStats {
+    pub restored: usize,
+    /// Файлы, которые уже существуют в destination и не были перезаписаны
+    pub skipped_existing: Vec<PathBuf>,
+    /// Записи снимка, для которых нет объекта в хранилище: (путь, хеш);
+    /// для файла из фрагментов - хеш первого недостающего фрагмента
+    pub missing_objects: Vec<(PathBuf, String)>,
+    /// Расширенные атрибуты, которые не удалось установить: (путь, имя атрибута).
+    /// Файлы при этом восстановлены, поэтому восстановление считается полным
+    pub skipped_xattrs: Vec<(PathBuf, String)>,
+}
+
+impl RestoreStats {
+    pub fn is_complete(&self) -> bool {
+        self.skipped_existing.is_empty() && self.missing_objects.is_empty()
+    }
+}
+
+/// Восстанавливает дерево файлов из снимка `snapshot` (имя директории с временной меткой
+/// внутри `target_base` или полный путь к ней) в директорию `dest`.
+/// Сохраненные в снимке метаданные (mtime, права, владелец, xattr) применяются повторно
+pub fn restore_snapshot(
+    target_base: &str,
+    snapshot: &str,
+    dest: &str,
+    overwrite: bool,
+) -> Result<RestoreStats> {
+    let repository = Repository::open(target_base)?;
+    let snapshot_dir = repository.snapshot_dir(snapshot);
+    let dest_dir = Path::new(dest);
+
+    if !snapshot_dir.is_dir() {
+        bail!("Snapshot not found: {}", snapshot_dir.display());
+    }
+
+    let mut stats = RestoreStats::default();
+
+    for entry in snapshot::read_snapshot(&snapshot_dir, repository.algorithm())? {
+        check_entry_path(dest_dir, &entry.path)?;
+        let target_path = dest_dir.join(&entry.path);
+
+        if let Some(link_target) = &entry.link_target {
+            if path_exists(&target_path) && !overwrite {
+                stats.skipped_existing.push(target_path);
+                continue;
+            }
+
+            restore_symlink(link_target, &target_path)?;
+            stats.restored += 1;
+            continue;
+        }
+
+        let object = match repository.find_object(&entry.hash)? {
+            Some(FileObject::Chunked(chunks)) => {
+                // Без любого из фрагментов файл не восстановить целиком
+                let missing = chunks
+                    .iter()
+                    .find(|chunk_hash| !repository.object_path(chunk_hash).is_file());
+                if let Some(chunk_hash) = missing {
+                    stats.missing_objects.push((entry.path, chunk_hash.clone()));
+                    continue;
+                }
+                FileObject::Chunked(chunks)
+            }
+            Some(object) => object,
+            None => {
+                stats.missing_objects.push((entry.path, entry.hash));
+                continue;
+            }
+        };
+
+        if path_exists(&target_path) && !overwrite {
+            stats.skipped_existing.push(target_path);
+            continue;
+        }
+
+        match object {
+            FileObject::Whole(object) => restore_file(&object, &target_path)?,
+            FileObject::Chunked(chunks) => restore_chunked(&repository, &chunks, &target_path)?,
+        }
+        let skipped = metadata::apply_metadata(&target_path, &entry)?;
+        stats
+            .skipped_xattrs
+            .extend(skipped.into_iter().map(|name| (target_path.clone(), name)));
+        stats.restored += 1;
+    }
+
+    Ok(stats)
+}
+
+/// Путь записи должен оставаться внутри `dest`: только обычные компоненты
+/// (без корня, `..` и `.`), и ни одна из родительских директорий, уже созданных
+/// в `dest`, не может быть символической ссылкой
+fn check_entry_path(dest_dir: &Path, path: &Path) -> Result<()> {
+    if path.as_os_str().is_empty()
+        || !path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+    {
+        bail!("Unsafe path in snapshot: {}", path.display());
+    }
+
+    let mut parent = dest_dir.to_path_buf();
+    for component in path.parent().into_iter().flat_map(Path::components) {
+        parent.push(component);
+        if fs::symlink_metadata(&parent).is_ok_and(|metadata| metadata.file_type().is_symlink()) {
+            bail!(
+                "Unsafe path in snapshot: {} (leads through symlink {})",
+                path.display(),
+                parent.display()
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Проверяет существование пути, включая битые символические ссылки
+fn path_exists(path: &Path) -> bool {
+    fs::symlink_metadata(path).is_ok()
+}
+
+/// Готовит место для восстановления: создает родительские директории
+/// и удаляет существующий файл (он мог быть восстановлен ранее только для чтения)
+fn prepare_target(target_path: &Path) -> Result<()> {
+    if let Some(parent) = target_path.parent() {
+        create_dir_all(parent)
+            .with_context(|| format!("Failed to create directory: {}", parent.display()))?;
+    }
+
+    if path_exists(target_path) {
+        fs::remove_file(target_path)
+            .with_context(|| format!("Failed to remove file: {}", target_path.display()))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(unix)]
+fn restore_symlink(link_target: &Path, target_path: &Path) -> Result<()> {
+    prepare_target(target_path)?;
+    std::os::unix::fs::symlink(link_target, target_path).with_context(|| {
+        format!(
+            "Failed to create symlink {} -> {}",
+            target_path.display(),
+            link_target.display()
+        )
+    })
+}
+
+#[cfg(not(unix))]
+fn restore_symlink(link_target: &Path, target_path: &Path) -> Result<()> {
+    bail!(
+        "Symlinks are not supported on this platform: {} -> {}",
+        target_path.display(),
+        link_target.display()
+    )
+}
+
+/// Копирует содержимое объекта в новый файл. `fs::copy` перенес бы и права объекта,
+/// а они могут запрещать запись: права из снимка применяются после метаданных
+fn restore_file(object: &Path, target_path: &Path) -> Result<()> {
+    prepare_target(target_path)?;
+
+    let mut source =
+        File::open(object).with_context(|| format!("Failed to open file: {}", object.display()))?;
+    let mut file = File::create(target_path)
+        .with_context(|| format!("Failed to create file: {}", target_path.display()))?;
+    io::copy(&mut source, &mut file).with_context(|| {
+        format!(
+            "Failed to copy {} to {}",
+            object.display(),
+            target_path.display()
+        )
+    })?;
+
+    Ok(())
+}
+
+/// Собирает файл из фрагментов по порядку
+fn restore_chunked(repository: &Repository, chunks: &[String], target_path: &Path) -> Result<()> {
+    prepare_target(target_path)?;
+
+    let mut file = File::create(target_path)
+        .with_context(|| format!("Failed to create file: {}", target_path.display()))?;
+    chunking::write_chunks(repository, chunks, &mut file)
+        .with_context(|| format!("Failed to restore {}", target_path.display()))?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunking::ChunkingConfig;
+    use crate::config::{Config, SymlinkPolicy};
+    use crate::file_processor::{process_files_with_extensions, BackupOptions};
+    use tempfile::TempDir;
+
+    fn txt_config() -> Config {
+        Config {
+            extensions: ["txt"].iter().map(|&s| s.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    // Создает резервную копию source и возвращает имя созданного снимка
+    fn create_backup(source_dir: &Path, target_dir: &Path) -> String {
+        create_backup_with(source_dir, target_dir, &txt_config())
+    }
+
+    fn create_backup_with(source_dir: &Path, target_dir: &Path, config: &Config) -> String {
+        process_files_with_extensions(
+            source_dir.to_str().unwrap(),
+            target_dir.to_str().unwrap(),
+            config,
+            &BackupOptions::default(),
+        )
+        .unwrap();
+
+        Repository::open(target_dir.to_str().unwrap())
+            .unwrap()
+            .list_snapshots()
+            .unwrap()
+            .pop()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_backup_writes_single_manifest() {
+        let temp_dir = TempDir::new().unwrap();
+        let source_dir = temp_dir.path().join("source");
+        fs::create_dir(&source_dir).unwrap();
+        fs::write(source_dir.join("a.txt"), "first").unwrap();
+
+        let target_dir = temp_dir.path().join("target");
+        let snapshot_name = create_backup(&source_dir, &target_dir);
+
+        // Снимок состоит из манифеста и итогов запуска, без файлов-записей
+        let mut snapshot_files: Vec<_> = fs::read_dir(target_dir.join(&snapshot_name))
+            .unwrap()
+            .filter_map(|e| e.ok())
+            .map(|e| e.file_name().to_string_lossy().to_string())
+            .collect();
+        snapshot_files.sort();
+        assert_eq!(
+            snapshot_files,
+            vec![snapshot::MANIFEST_FILE, crate::summary::SUMMARY_FILE]
+        );
+    }
+
+    #[test]
+    fn test_restore_snapshot_roundtrip() {
+        let temp_dir = TempDir::new().unwrap();
+        let source_dir = temp_dir.path().join("source");
+        fs::create_dir_all(source_dir.join("nested/dir")).unwrap();
+        fs::write(source_dir.join("a.txt"), "first").unwrap();
+        fs::write(source_dir.join("nested/dir/b.txt"), "second").unwrap();
+
+        let target_dir = temp_dir.path().join("target");
+        let snapshot = create_backup(&source_dir, &target_dir);
+
+        let dest_dir = temp_dir.path().join("restored");
+        let stats = restore_snapshot(
+            target_dir.to_str().unwrap(),
+            &snapshot,
+            dest_dir.to_str().unwrap(),
+            false,
+        )
+        .unwrap();
+
+        assert_eq!(stats.restored, 2);
+        assert!(stats.is_complete());
+        assert_eq!(fs::read_to_string(dest_dir.join("a.txt")).unwrap(), "first");
+        assert_eq!(
+            fs::read_to_string(dest_dir.join("nested/dir/b.txt")).unwrap(),
+            "second"
+        );
+    }
+
+    #[test]
+    fn test_restore_preserves_mtime() {
+        let temp_dir = TempDir::new().unwrap();
+        let source_dir = temp_dir.path().join("source");
+        fs::create_dir(&source_dir).unwrap();
+        let source_file = source_dir.join("photo.txt");
+        fs::write(&source_file, "image").unwrap();
+
+        let mtime = std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_500_000_000);
+        fs::File::options()
+            .write(true)
+            .open(&source_file)
+            .unwrap()
+            .set_modified(mtime)
+            .unwrap();
+
+        let target_dir = temp_dir.path().join("target");
+        let snapshot = create_backup(&source_dir, &target_dir);
+
+        let dest_dir = temp_dir.path().join("restored");
+        restore_snapshot(
+            target_dir.to_str().unwrap(),
+            &snapshot,
+            dest_dir.to_str().unwrap(),
+            false,
+        )
+        .unwrap();
+
+        let restored = fs::metadata(dest_dir.join("photo.txt")).unwrap();
+        assert_eq!(restored.modified().unwrap(), mtime);
+        assert_eq!(
+            metadata::file_mode(&restored),
+            metadata::file_mode(&fs::metadata(&source_file).unwrap())
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_restore_read_only_file() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp_dir = TempDir::new().unwrap();
+        let source_dir = temp_dir.path().join("source");
+        fs::create_dir(&source_dir).unwrap();
+        let source_file = source_dir.join("a.txt");
+        fs::write(&source_file, "read only").unwrap();
+        fs::set_permissions(&source_file, fs::Permissions::from_mode(0o444)).unwrap();
+
+        let target_dir = temp_dir.path().join("target");
+        let snapshot = create_backup(&source_dir, &target_dir);
+        let target = target_dir.to_str().unwrap();
+
+        let dest_dir = temp_dir.path().join("restored");
+        let dest = dest_dir.to_str().unwrap();
+        let stats = restore_snapshot(target, &snapshot, dest, false).unwrap();
+        assert_eq!(stats.restored, 1);
+        // Повторное восстановление поверх файла только для чтения
+        let stats = restore_snapshot(target, &snapshot, dest, true).unwrap();
+        assert_eq!(stats.restored, 1);
+
+        let restored = fs::metadata(dest_dir.join("a.txt")).unwrap();
+        assert_eq!(metadata::file_mode(&restored), Some(0o444));
+        assert_eq!(
+            restored.modified().unwrap(),
+            fs::metadata(&source_file).unwrap().modified().unwrap()
+        );
+        assert_eq!(
+            fs::read_to_string(dest_dir.join("a.txt")).unwrap(),
+            "read only"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_restore_recorded_symlink() {
+        let temp_dir = TempDir::new().unwrap();
+        let source_dir = temp_dir.path().join("source");
+        fs::create_dir(&source_dir).unwrap();
+        fs::write(source_dir.join("a.txt"), "first").unwrap();
+        std::os::unix::fs::symlink("a.txt", source_dir.join("link.txt")).unwrap();
+
+        let target_dir = temp_dir.path().join("target");
+        let config = Config {
+            symlinks: SymlinkPolicy::Record,
+            ..txt_config()
+        };
+        let snapshot = create_backup_with(&source_dir, &target_dir, &config);
+
+        let dest_dir = temp_dir.path().join("restored");
+        let stats = restore_snapshot(
+            target_dir.to_str().unwrap(),
+            &snapshot,
+            dest_dir.to_str().unwrap(),
+            false,
+        )
+        .unwrap();
+
+        assert_eq!(stats.restored, 2);
+        assert_eq!(
+            fs::read_link(dest_dir.join("link.txt")).unwrap(),
+            PathBuf::from("a.txt")
+        );
+        assert_eq!(
+            fs::read_to_string(dest_dir.join("link.txt")).unwrap(),
+            "first"
+        );
+    }
+
+    #[test]
+    fn test_restore_chunked_file() {
+        let temp_dir = TempDir::new().unwrap();
+        let source_dir = temp_dir.path().join("source");
+        fs::create_dir(&source_dir).unwrap();
+        let large: String = (0..2000).map(|i| format!("line {}\n", i)).collect();
+        fs::write(source_dir.join("large.txt"), &large).unwrap();
+        fs::write(source_dir.join("small.txt"), "first").unwrap();
+
+        let target_dir = temp_dir.path().join("target");
+        let config = Config {
+            chunking: ChunkingConfig {
+                enabled: true,
+                min_size: 64,
+                avg_size: 256,
+                max_size: 1024,
+            },
+            ..txt_config()
+        };
+        let snapshot = create_backup_with(&source_dir, &target_dir, &config);
+        let target = target_dir.to_str().unwrap();
+
+        let dest_dir = temp_dir.path().join("restored");
+        let stats = restore_snapshot(target, &snapshot, dest_dir.to_str().unwrap(), false).unwrap();
+        assert_eq!(stats.restored, 2);
+        assert_eq!(
+            fs::read_to_string(dest_dir.join("large.txt")).unwrap(),
+            large
+        );
+        assert_eq!(
+            fs::read_to_string(dest_dir.join("small.txt")).unwrap(),
+            "first"
+        );
+
+        // Без одного фрагмента файл не восстанавливается
+        let repository = Repository::open(target).unwrap();
+        let chunk_lists = repository.list_chunk_lists().unwrap();
+        assert_eq!(chunk_lists.len(), 1);
+        let chunks = repository.read_chunk_list(&chunk_lists[0].0).unwrap();
+        fs::remove_file(repository.object_path(&chunks[1])).unwrap();
+
+        let dest_dir = temp_dir.path().join("restored_again");
+        let stats = restore_snapshot(target, &snapshot, dest_dir.to_str().unwrap(), false).unwrap();
+        assert_eq!(stats.restored, 1);
+        assert_eq!(
+            stats.missing_objects,
+            vec![(PathBuf::from("large.txt"), chunks[1].clone())]
+        );
+        assert!(!dest_dir.join("large.txt").exists());
+    }
+
+    #[test]
+    fn test_restore_refuses_to_overwrite() {
+        let temp_dir = TempDir::new().unwrap();
+        let source_dir = temp_dir.path().join("source");
+        fs::create_dir(&source_dir).unwrap();
+        fs::write(source_dir.join("a.txt"), "backup").unwrap();
+
+        let target_dir = temp_dir.path().join("target");
+        let snapshot = create_backup(&source_dir, &target_dir);
+
+        let dest_dir = temp_dir.path().join("restored");
+        fs::create_dir(&dest_dir).unwrap();
+        fs::write(dest_dir.join("a.txt"), "local").unwrap();
+
+        let target = target_dir.to_str().unwrap();
+        let dest = dest_dir.to_str().unwrap();
+
+        let stats = restore_snapshot(target, &snapshot, dest, false).unwrap();
+        assert_eq!(stats.restored, 0);
+        assert_eq!(stats.skipped_existing, vec![dest_dir.join("a.txt")]);
+        assert_eq!(fs::read_to_string(dest_dir.join("a.txt")).unwrap(), "local");
+
+        let stats = restore_snapshot(target, &snapshot, dest, true).unwrap();
+        assert_eq!(stats.restored, 1);
+        assert_eq!(
+            fs::read_to_string(dest_dir.join("a.txt")).unwrap(),
+            "backup"
+        );
+    }
+
+    #[test]
+    fn test_restore_reports_missing_objects() {
+        let temp_dir = TempDir::new().unwrap();
+        let target_dir = temp_dir.path().join("target");
+        let snapshot_dir = target_dir.join("20240101_000000");
+        fs::create_dir_all(&snapshot_dir).unwrap();
+        fs::create_dir_all(target_dir.join("files_by_md5")).unwrap();
+        fs::write(
+            snapshot_dir.join("lost.txt"),
+            "098f6bcd4621d373cade4e832627b4f6",
+        )
+        .unwrap();
+
+        let dest_dir = temp_dir.path().join("restored");
+        let stats = restore_snapshot(
+            target_dir.to_str().unwrap(),
+            "20240101_000000",
+            dest_dir.to_str().unwrap(),
+            false,
+        )
+        .unwrap();
+
+        assert_eq!(stats.restored, 0);
+        assert_eq!(
+            stats.missing_objects,
+            vec![(
+                PathBuf::from("lost.txt"),
+                "098f6bcd4621d373cade4e832627b4f6".to_string()
+            )]
+        );
+        assert!(!dest_dir.join("lost.txt").exists());
+    }
+
+    #[test]
+    fn test_restore_rejects_unsafe_paths() {
+        let temp_dir = TempDir::new().unwrap();
+        let target_dir = temp_dir.path().join("target");
+        let dest_dir = temp_dir.path().join("restored");
+        let snapshot_dir = target_dir.join("20240101_000000");
+        fs::create_dir_all(&snapshot_dir).unwrap();
+        fs::create_dir_all(target_dir.join("files_by_md5")).unwrap();
+
+        let escaped = temp_dir.path().join("escaped.txt");
+        for path in [
+            "../escaped.txt".to_string(),
+            "nested/../../escaped.txt".to_string(),
+            escaped.to_str().unwrap().to_string(),
+        ] {
+            fs::write(
+                snapshot_dir.join(snapshot::MANIFEST_FILE),
+                format!(
+                    "{{\"path\":{:?},\"hash\":\"098f6bcd4621d373cade4e832627b4f6\"}}\n",
+                    path
+                ),
+            )
+            .unwrap();
+
+            let error = restore_snapshot(
+                target_dir.to_str().unwrap(),
+                "20240101_000000",
+                dest_dir.to_str().unwrap(),
+                true,
+            )
+            .unwrap_err();
+            assert!(error.to_string().contains("Unsafe path"), "{}", error);
+            assert!(!escaped.exists(), "{}", path);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_restore_rejects_path_through_symlink() {
+        let temp_dir = TempDir::new().unwrap();
+        let source_dir = temp_dir.path().join("source");
+        fs::create_dir_all(source_dir.join("nested")).unwrap();
+        fs::write(source_dir.join("nested/a.txt"), "backup").unwrap();
+
+        let target_dir = temp_dir.path().join("target");
+        let snapshot = create_backup(&source_dir, &target_dir);
+
+        // В dest уже есть ссылка nested на директорию вне dest
+        let outside = temp_dir.path().join("outside");
+        fs::create_dir(&outside).unwrap();
+        let dest_dir = temp_dir.path().join("restored");
+        fs::create_dir(&dest_dir).unwrap();
+        std::os::unix::fs::symlink(&outside, dest_dir.join("nested")).unwrap();
+
+        let error = restore_snapshot(
+            target_dir.to_str().unwrap(),
+            &snapshot,
+            dest_dir.to_str().unwrap(),
+            true,
+        )
+        .unwrap_err();
+        assert!(error.to_string().contains("Unsafe path"), "{}", error);
+        assert!(!outside.join("a.txt").exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_restore_rejects_path_through_restored_symlink() {
+        let temp_dir = TempDir::new().unwrap();
+        let target_dir = temp_dir.path().join("target");
+        let dest_dir = temp_dir.path().join("restored");
+        let outside = temp_dir.path().join("outside");
+        fs::create_dir(&outside).unwrap();
+        let snapshot_dir = target_dir.join("20240101_000000");
+        fs::create_dir_all(&snapshot_dir).unwrap();
+        fs::create_dir_all(target_dir.join("files_by_md5")).unwrap();
+
+        // Первая запись - ссылка на директорию вне dest, вторая ведет через нее
+        fs::write(
+            snapshot_dir.join(snapshot::MANIFEST_FILE),
+            format!(
+                "{{\"path\":\"link\",\"link_target\":{:?}}}\n\
+                 {{\"path\":\"link/escaped.txt\",\"hash\":\"098f6bcd4621d373cade4e832627b4f6\"}}\n",
+                outside.to_str().unwrap()
+            ),
+        )
+        .unwrap();
+
+        let error = restore_snapshot(
+            target_dir.to_str().unwrap(),
+            "20240101_000000",
+            dest_dir.to_str().unwrap(),
+            false,
+        )
+        .unwrap_err();
+        assert!(error.to_string().contains("Unsafe path"), "{}", error);
+        assert!(!outside.join("escaped.txt").exists());
+    }
+
+    #[test]
+    fn test_restore_missing_snapshot() {
+        let temp_dir = TempDir::new().unwrap();
+        fs::create_dir(temp_dir.path().join("files_by_md5")).unwrap();
+        let result = restore_snapshot(
+            temp_dir.path().to_str().unwrap(),
+            "20240101_000000",
+            temp_dir.path().join("restored").to_str().unwrap(),
+            false,
+        );
+
+        assert!(result.is_err());
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .contains("Snapshot not found"));
+    }
+}
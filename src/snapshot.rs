@@ -0,0 +1,454 @@
+use crate::hash::HashAlgorithm;
+use crate::{directory, metadata};
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use tempfile::NamedTempFile;
+use walkdir::WalkDir;
+
+/// Манифест снимка: одна JSON-строка на каждый сохраненный файл
+pub const MANIFEST_FILE: &str = "manifest.jsonl";
+
+/// Префикс временного файла, в который пишется манифест до переименования.
+/// Снимок с таким файлом и без манифеста не завершен (резервное копирование прервано)
+pub const TEMP_MANIFEST_PREFIX: &str = ".tmp-manifest-";
+
+/// Запись манифеста о файле в снимке.
+/// Для снимков старого формата (файл-запись с хешем) известны только путь и хеш
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    /// Путь относительно исходной директории
+    pub path: PathBuf,
+    /// Хеш содержимого; пустой для символических ссылок
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub hash: String,
+    /// Цель символической ссылки, если запись описывает ссылку
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub link_target: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    /// Время модификации: секунды от UNIX_EPOCH
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mtime: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mtime_nsec: Option<u32>,
+    /// Права доступа Unix
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uid: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gid: Option<u32>,
+    /// Расширенные атрибуты: имя -> значение в hex
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub xattrs: BTreeMap<String, String>,
+}
+
+impl ManifestEntry {
+    /// Запись с метаданными файла (без расширенных атрибутов)
+    pub fn new(path: PathBuf, hash: String, metadata: &fs::Metadata) -> Self {
+        let modified = metadata
+            .modified()
+            .ok()
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok());
+        let (uid, gid) = metadata::file_owner(metadata);
+
+        Self {
+            path,
+            hash,
+            link_target: None,
+            size: Some(metadata.len()),
+            mtime: modified.map(|d| d.as_secs() as i64),
+            mtime_nsec: modified.map(|d| d.subsec_nanos()),
+            mode: metadata::file_mode(metadata),
+            uid,
+            gid,
+            xattrs: BTreeMap::new(),
+        }
+    }
+
+    /// Запись о символической ссылке; метаданные берутся у самой ссылки
+    pub fn symlink(path: PathBuf, link_target: PathBuf, metadata: &fs::Metadata) -> Self {
+        Self {
+            link_target: Some(link_target),
+            mode: None,
+            ..Self::new(path, String::new(), metadata)
+        }
+    }
+
+    /// Запись, для которой известны только путь и хеш
+    pub fn from_hash(path: PathBuf, hash: String) -> Self {
+        Self {
+            path,
+            hash,
+            link_target: None,
+            size: None,
+            mtime: None,
+            mtime_nsec: None,
+            mode: None,
+            uid: None,
+            gid: None,
+            xattrs: BTreeMap::new(),
+        }
+    }
+}
+
+/// Последовательно записывает манифест снимка во временный файл; манифест
+/// появляется под своим именем только в finish, поэтому прерванный запуск
+/// не оставляет обрезанный манифест
+pub struct ManifestWriter {
+    path: PathBuf,
+    output: BufWriter<NamedTempFile>,
+}
+
+impl ManifestWriter {
+    pub fn create(snapshot_dir: &Path) -> Result<Self> {
+        let path = snapshot_dir.join(MANIFEST_FILE);
+        let temp = tempfile::Builder::new()
+            .prefix(TEMP_MANIFEST_PREFIX)
+            .tempfile_in(snapshot_dir)
+            .with_context(|| {
+                format!(
+                    "Failed to create temporary file in {}",
+                    snapshot_dir.display()
+                )
+            })?;
+
+        Ok(Self {
+            path,
+            output: BufWriter::new(temp),
+        })
+    }
+
+    pub fn write(&mut self, entry: &ManifestEntry) -> Result<()> {
+        serde_json::to_writer(&mut self.output, entry)
+            .with_context(|| format!("Failed to write to file: {}", self.path.display()))?;
+        writeln!(self.output)
+            .with_context(|| format!("Failed to write to file: {}", self.path.display()))?;
+        Ok(())
+    }
+
+    /// Сбрасывает буфер, синхронизирует манифест с диском и переименовывает его в
+    /// manifest.jsonl
+    pub fn finish(self) -> Result<()> {
+        let temp = self
+            .output
+            .into_inner()
+            .map_err(|e| e.into_error())
+            .with_context(|| format!("Failed to write to file: {}", self.path.display()))?;
+        temp.as_file()
+            .sync_all()
+            .with_context(|| format!("Failed to sync file: {}", temp.path().display()))?;
+        temp.persist(&self.path)
+            .map_err(|e| e.error)
+            .with_context(|| format!("Failed to rename manifest to {}", self.path.display()))?;
+
+        let snapshot_dir = self
+            .path
+            .parent()
+            .with_context(|| format!("Invalid manifest path: {}", self.path.display()))?;
+        directory::sync_dir(snapshot_dir)
+    }
+}
+
+/// Читает все записи снимка: из манифеста, а для снимков старого формата -
+/// из дерева файлов-записей, каждая из которых содержит только хеш.
+/// Незавершенный снимок - ошибка
+pub fn read_snapshot(snapshot_dir: &Path, algorithm: HashAlgorithm) -> Result<Vec<ManifestEntry>> {
+    let manifest_path = snapshot_dir.join(MANIFEST_FILE);
+    if manifest_path.is_file() {
+        read_manifest(&manifest_path, algorithm)
+    } else if is_incomplete(snapshot_dir) {
+        bail!(
+            "Snapshot is incomplete (backup was interrupted): {}",
+            snapshot_dir.display()
+        )
+    } else {
+        read_legacy_records(snapshot_dir, algorithm)
+    }
+}
+
+/// Снимок нового формата, запись которого прервана: манифеста нет, но есть его
+/// временный файл, или в директории нет ни одного файла. Пустая директория
+/// остается, если запуск прерван до создания манифеста, и не должна читаться
+/// как снимок старого формата без записей
+pub fn is_incomplete(snapshot_dir: &Path) -> bool {
+    if snapshot_dir.join(MANIFEST_FILE).is_file() {
+        return false;
+    }
+    let Ok(entries) = fs::read_dir(snapshot_dir) else {
+        return false;
+    };
+    let has_temp_manifest = entries.filter_map(|e| e.ok()).any(|e| {
+        e.file_name()
+            .to_string_lossy()
+            .starts_with(TEMP_MANIFEST_PREFIX)
+    });
+    has_temp_manifest
+        || !WalkDir::new(snapshot_dir)
+            .into_iter()
+            .filter_map(|e| e.ok())
+            .any(|e| e.file_type().is_file())
+}
+
+/// Читает манифест; хеш каждой записи, кроме символических ссылок, проверяется
+/// по алгоритму хранилища, чтобы поврежденная строка не стала путем к объекту
+fn read_manifest(manifest_path: &Path, algorithm: HashAlgorithm) -> Result<Vec<ManifestEntry>> {
+    let file = File::open(manifest_path)
+        .with_context(|| format!("Failed to open file: {}", manifest_path.display()))?;
+
+    let mut entries = Vec::new();
+    for (index, line) in BufReader::new(file).lines().enumerate() {
+        let line =
+            line.with_context(|| format!("Failed to read file: {}", manifest_path.display()))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let mut entry: ManifestEntry = serde_json::from_str(&line).with_context(|| {
+            format!(
+                "Failed to parse manifest {} at line {}",
+                manifest_path.display(),
+                index + 1
+            )
+        })?;
+        if entry.link_target.is_none() {
+            if !algorithm.is_valid_hex(&entry.hash) {
+                bail!(
+                    "Invalid hash in manifest {} at line {}: {:?}",
+                    manifest_path.display(),
+                    index + 1,
+                    entry.hash
+                );
+            }
+            entry.hash.make_ascii_lowercase();
+        }
+        entries.push(entry);
+    }
+
+    Ok(entries)
+}
+
+fn read_legacy_records(
+    snapshot_dir: &Path,
+    algorithm: HashAlgorithm,
+) -> Result<Vec<ManifestEntry>> {
+    let mut entries = Vec::new();
+
+    for entry in WalkDir::new(snapshot_dir)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_file())
+    {
+        let relative_path = entry.path().strip_prefix(snapshot_dir).with_context(|| {
+            format!("Failed to get relative path for {}", entry.path().display())
+        })?;
+
+        entries.push(ManifestEntry::from_hash(
+            relative_path.to_path_buf(),
+            read_record(entry.path(), algorithm)?,
+        ));
+    }
+
+    Ok(entries)
+}
+
+/// Читает запись снимка и проверяет, что она содержит хеш алгоритма хранилища
+fn read_record(record_path: &Path, algorithm: HashAlgorithm) -> Result<String> {
+    let content = fs::read_to_string(record_path)
+        .with_context(|| format!("Failed to read record: {}", record_path.display()))?;
+    let hash_hex = content.trim();
+
+    if !algorithm.is_valid_hex(hash_hex) {
+        bail!("Invalid record {}: {:?}", record_path.display(), hash_hex);
+    }
+
+    Ok(hash_hex.to_lowercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    const TEST_MD5: &str = "098f6bcd4621d373cade4e832627b4f6";
+
+    #[test]
+    fn test_manifest_roundtrip() {
+        let temp_dir = TempDir::new().unwrap();
+        let source_file = temp_dir.path().join("file.txt");
+        fs::write(&source_file, "content").unwrap();
+        let metadata = fs::metadata(&source_file).unwrap();
+
+        let entry = ManifestEntry::new(
+            PathBuf::from("nested/file.txt"),
+            TEST_MD5.to_string(),
+            &metadata,
+        );
+        assert_eq!(entry.size, Some(7));
+        assert!(entry.mtime.is_some());
+
+        let mut entry = entry;
+        entry
+            .xattrs
+            .insert("user.comment".to_string(), "6869".to_string());
+
+        let mut writer = ManifestWriter::create(temp_dir.path()).unwrap();
+        writer.write(&entry).unwrap();
+        writer.finish().unwrap();
+
+        let entries = read_snapshot(temp_dir.path(), HashAlgorithm::Md5).unwrap();
+        assert_eq!(entries, vec![entry]);
+    }
+
+    #[test]
+    fn test_manifest_is_single_file() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut writer = ManifestWriter::create(temp_dir.path()).unwrap();
+        for name in ["a.txt", "b/c.txt", "b/d/e.txt"] {
+            writer
+                .write(&ManifestEntry {
+                    size: Some(4),
+                    mtime: Some(0),
+                    mode: Some(0o644),
+                    ..ManifestEntry::from_hash(PathBuf::from(name), TEST_MD5.to_string())
+                })
+                .unwrap();
+        }
+        writer.finish().unwrap();
+
+        let files: Vec<_> = fs::read_dir(temp_dir.path()).unwrap().collect();
+        assert_eq!(files.len(), 1);
+
+        let entries = read_snapshot(temp_dir.path(), HashAlgorithm::Md5).unwrap();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[1].path, PathBuf::from("b/c.txt"));
+    }
+
+    #[test]
+    fn test_interrupted_manifest() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut writer = ManifestWriter::create(temp_dir.path()).unwrap();
+        writer
+            .write(&ManifestEntry::from_hash(
+                PathBuf::from("a.txt"),
+                TEST_MD5.to_string(),
+            ))
+            .unwrap();
+        // Процесс убит до finish: временный файл остается, манифеста нет
+        std::mem::forget(writer);
+
+        assert!(!temp_dir.path().join(MANIFEST_FILE).exists());
+        assert!(is_incomplete(temp_dir.path()));
+        let error = read_snapshot(temp_dir.path(), HashAlgorithm::Md5).unwrap_err();
+        assert!(error.to_string().contains("Snapshot is incomplete"));
+    }
+
+    #[test]
+    fn test_empty_snapshot_dir_is_incomplete() {
+        let temp_dir = TempDir::new().unwrap();
+        // Процесс убит между созданием директории снимка и манифеста
+        fs::create_dir(temp_dir.path().join("nested")).unwrap();
+
+        assert!(is_incomplete(temp_dir.path()));
+        let error = read_snapshot(temp_dir.path(), HashAlgorithm::Md5).unwrap_err();
+        assert!(error.to_string().contains("Snapshot is incomplete"));
+
+        // Пустой манифест - завершенный снимок без файлов
+        ManifestWriter::create(temp_dir.path())
+            .unwrap()
+            .finish()
+            .unwrap();
+        assert!(!is_incomplete(temp_dir.path()));
+        assert!(read_snapshot(temp_dir.path(), HashAlgorithm::Md5)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_symlink_entry_roundtrip() {
+        let temp_dir = TempDir::new().unwrap();
+        let metadata = fs::symlink_metadata(temp_dir.path()).unwrap();
+
+        let entry = ManifestEntry::symlink(
+            PathBuf::from("link.txt"),
+            PathBuf::from("target.txt"),
+            &metadata,
+        );
+        assert_eq!(entry.link_target, Some(PathBuf::from("target.txt")));
+
+        let line = serde_json::to_string(&entry).unwrap();
+        assert!(!line.contains("\"hash\""));
+
+        let parsed: ManifestEntry = serde_json::from_str(&line).unwrap();
+        assert_eq!(parsed, entry);
+    }
+
+    #[test]
+    fn test_read_legacy_snapshot() {
+        let temp_dir = TempDir::new().unwrap();
+        fs::create_dir_all(temp_dir.path().join("nested/dir")).unwrap();
+        fs::write(temp_dir.path().join("a.txt"), TEST_MD5).unwrap();
+        fs::write(temp_dir.path().join("nested/dir/b.txt"), TEST_MD5).unwrap();
+
+        let entries = read_snapshot(temp_dir.path(), HashAlgorithm::Md5).unwrap();
+
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].path, PathBuf::from("a.txt"));
+        assert_eq!(entries[0].hash, TEST_MD5);
+        assert_eq!(entries[0].size, None);
+        assert_eq!(entries[1].path, PathBuf::from("nested/dir/b.txt"));
+    }
+
+    #[test]
+    fn test_read_legacy_invalid_record() {
+        let temp_dir = TempDir::new().unwrap();
+        fs::write(temp_dir.path().join("a.txt"), "not a hash").unwrap();
+
+        let result = read_snapshot(temp_dir.path(), HashAlgorithm::Md5);
+        assert!(result.is_err());
+        assert!(result.unwrap_err().to_string().contains("Invalid record"));
+    }
+
+    #[test]
+    fn test_read_manifest_invalid_line() {
+        let temp_dir = TempDir::new().unwrap();
+        fs::write(
+            temp_dir.path().join(MANIFEST_FILE),
+            format!("{{\"path\":\"a.txt\",\"hash\":\"{}\"}}\nbroken\n", TEST_MD5),
+        )
+        .unwrap();
+
+        let result = read_snapshot(temp_dir.path(), HashAlgorithm::Md5);
+        assert!(result.is_err());
+        assert!(result.unwrap_err().to_string().contains("at line 2"));
+    }
+
+    #[test]
+    fn test_read_manifest_invalid_hash() {
+        let temp_dir = TempDir::new().unwrap();
+        for hash in ["", "0", "zz0f6bcd4621d373cade4e832627b4f6"] {
+            fs::write(
+                temp_dir.path().join(MANIFEST_FILE),
+                format!(
+                    "{{\"path\":\"a.txt\",\"hash\":\"{}\"}}\n{{\"path\":\"b.txt\",\"hash\":\"{}\"}}\n",
+                    TEST_MD5, hash
+                ),
+            )
+            .unwrap();
+
+            let error = read_snapshot(temp_dir.path(), HashAlgorithm::Md5).unwrap_err();
+            assert!(
+                error.to_string().contains("Invalid hash in manifest"),
+                "{}",
+                error
+            );
+            assert!(error.to_string().contains("at line 2"), "{}", error);
+        }
+    }
+}
@@ -0,0 +1,160 @@
+use crate::repository::{self, Repository};
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::collections::HashSet;
+use std::fs;
+
+/// Сводка по хранилищу
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct RepositoryStats {
+    pub snapshots: usize,
+    /// Объекты (целые файлы и фрагменты) и списки фрагментов
+    pub objects: usize,
+    /// Объем объектов и списков фрагментов на диске
+    pub stored_bytes: u64,
+    /// Суммарный размер файлов всех снимков до дедупликации
+    pub logical_bytes: u64,
+    /// Число различных объектов и списков фрагментов, на которые ссылаются снимки
+    /// (как и в `objects`, вместе с фрагментами файлов из фрагментов)
+    pub referenced_objects: usize,
+}
+
+impl RepositoryStats {
+    /// Во сколько раз дедупликация уменьшила объем; None для пустого хранилища
+    pub fn dedup_ratio(&self) -> Option<f64> {
+        (self.stored_bytes > 0).then(|| self.logical_bytes as f64 / self.stored_bytes as f64)
+    }
+}
+
+pub fn repository_stats(target_base: &str) -> Result<RepositoryStats> {
+    let repository = Repository::open(target_base)?;
+    let mut stats = RepositoryStats::default();
+    let mut referenced = HashSet::new();
+
+    for name in repository.list_snapshots()? {
+        stats.snapshots += 1;
+        for entry in repository.read_snapshot(&name)? {
+            stats.logical_bytes += repository.entry_size(&entry);
+            if entry.link_target.is_none() {
+                referenced.insert(entry.hash);
+            }
+        }
+    }
+
+    let objects = repository.list_objects()?;
+    let chunk_lists = repository.list_chunk_lists()?;
+    repository::add_referenced_chunks(
+        &mut referenced,
+        chunk_lists.iter().map(|(hash_hex, _)| hash_hex.as_str()),
+        |hash_hex| repository.read_chunk_list(hash_hex),
+    )?;
+    stats.referenced_objects = referenced.len();
+
+    for (_, path) in objects.iter().chain(&chunk_lists) {
+        stats.objects += 1;
+        stats.stored_bytes += fs::metadata(path)
+            .with_context(|| format!("Failed to read metadata: {}", path.display()))?
+            .len();
+    }
+
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::snapshot::{ManifestEntry, ManifestWriter};
+    use std::path::PathBuf;
+    use tempfile::TempDir;
+
+    const TEST_MD5: &str = "098f6bcd4621d373cade4e832627b4f6";
+
+    #[test]
+    fn test_repository_stats() {
+        let temp_dir = TempDir::new().unwrap();
+        let target = temp_dir.path().to_str().unwrap();
+        let repository = Repository::open_or_init(target, None).unwrap();
+
+        let object = repository.object_path(TEST_MD5);
+        fs::create_dir_all(object.parent().unwrap()).unwrap();
+        fs::write(&object, "test").unwrap();
+
+        // Два снимка с двумя копиями одного файла каждый
+        for name in ["20240101_000000", "20240102_000000"] {
+            let snapshot_dir = repository.snapshot_dir(name);
+            fs::create_dir(&snapshot_dir).unwrap();
+            let mut manifest = ManifestWriter::create(&snapshot_dir).unwrap();
+            for path in ["a.txt", "b.txt"] {
+                manifest
+                    .write(&ManifestEntry {
+                        size: Some(4),
+                        ..ManifestEntry::from_hash(PathBuf::from(path), TEST_MD5.to_string())
+                    })
+                    .unwrap();
+            }
+            manifest.finish().unwrap();
+        }
+
+        let stats = repository_stats(target).unwrap();
+
+        assert_eq!(
+            stats,
+            RepositoryStats {
+                snapshots: 2,
+                objects: 1,
+                stored_bytes: 4,
+                logical_bytes: 16,
+                referenced_objects: 1,
+            }
+        );
+        assert_eq!(stats.dedup_ratio(), Some(4.0));
+    }
+
+    #[test]
+    fn test_chunked_repository_stats() {
+        let temp_dir = TempDir::new().unwrap();
+        let target = temp_dir.path().to_str().unwrap();
+        let repository = Repository::open_or_init(target, None).unwrap();
+        // "test" хранится списком из фрагментов "te" и "st"
+        let chunks = [
+            ("569ef72642be0fadd711d6a468d68ee1", "te"),
+            ("627fcdb6cc9a5e16d657ca6cdef0a6bb", "st"),
+        ];
+        for (hash_hex, content) in chunks {
+            let object = repository.object_path(hash_hex);
+            fs::create_dir_all(object.parent().unwrap()).unwrap();
+            fs::write(&object, content).unwrap();
+        }
+        let chunk_list = repository.chunk_list_path(TEST_MD5);
+        fs::create_dir_all(chunk_list.parent().unwrap()).unwrap();
+        fs::write(&chunk_list, format!("{}\n{}\n", chunks[0].0, chunks[1].0)).unwrap();
+
+        let snapshot_dir = repository.snapshot_dir("20240101_000000");
+        fs::create_dir(&snapshot_dir).unwrap();
+        let mut manifest = ManifestWriter::create(&snapshot_dir).unwrap();
+        manifest
+            .write(&ManifestEntry {
+                size: Some(4),
+                ..ManifestEntry::from_hash(PathBuf::from("a.txt"), TEST_MD5.to_string())
+            })
+            .unwrap();
+        manifest.finish().unwrap();
+
+        let stats = repository_stats(target).unwrap();
+
+        // Фрагменты и список фрагментов учитываются одинаково в обоих счетчиках
+        assert_eq!(stats.objects, 3);
+        assert_eq!(stats.referenced_objects, 3);
+    }
+
+    #[test]
+    fn test_empty_repository_stats() {
+        let temp_dir = TempDir::new().unwrap();
+        let target = temp_dir.path().to_str().unwrap();
+        Repository::open_or_init(target, None).unwrap();
+
+        let stats = repository_stats(target).unwrap();
+        assert_eq!(stats, RepositoryStats::default());
+        assert_eq!(stats.dedup_ratio(), None);
+    }
+}
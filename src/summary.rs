@@ -0,0 +1,111 @@
+use crate::file_processor::BackupStats;
+use anyhow::{Context, Result};
+use std::fs;
+use std::path::Path;
+
+/// Итоги запуска, сохраняемые рядом с манифестом снимка
+pub const SUMMARY_FILE: &str = "summary.txt";
+
+const MIB: f64 = 1024.0 * 1024.0;
+
+/// Итоги запуска в виде текста: одна строка `Название: значение` на показатель
+pub fn format_summary(stats: &BackupStats) -> String {
+    let mut lines = vec![
+        match &stats.snapshot {
+            Some(name) => format!("Snapshot: {}", name),
+            None => "Dry run: no snapshot or objects written".to_string(),
+        },
+        format!("Files scanned: {}", stats.scanned),
+        format!("Matched: {}", stats.matched),
+        format!(
+            "Skipped: {} hidden, {} excluded, {} by extension, {} special, {} symlinks",
+            stats.hidden_skipped,
+            stats.excluded_skipped,
+            stats.extension_skipped,
+            stats.special_files_skipped,
+            stats.symlinks_skipped
+        ),
+        format!("Errors: {}", stats.errors.len()),
+        format!(
+            "Files: {} ({} hashes from cache)",
+            stats.files, stats.cached
+        ),
+        format!("Symlinks: {}", stats.symlinks_recorded),
+        format!(
+            "New objects: {} ({} bytes stored)",
+            stats.new_objects, stats.new_bytes
+        ),
+        format!(
+            "Duplicates: {} ({} bytes deduplicated)",
+            stats.duplicates, stats.deduplicated_bytes
+        ),
+        format!("Bytes read: {}", stats.bytes_read),
+    ];
+    if !stats.inconsistent.is_empty() {
+        lines.push(format!("Inconsistent: {}", stats.inconsistent.len()));
+    }
+    lines.push(format!("Elapsed: {:.2} s", stats.elapsed.as_secs_f64()));
+    if let Some(throughput) = stats.throughput() {
+        lines.push(format!("Throughput: {:.1} MiB/s", throughput / MIB));
+    }
+
+    lines.iter().map(|line| format!("{}\n", line)).collect()
+}
+
+/// Сохраняет итоги запуска в директорию снимка
+pub fn write_summary(snapshot_dir: &Path, stats: &BackupStats) -> Result<()> {
+    let path = snapshot_dir.join(SUMMARY_FILE);
+    fs::write(&path, format_summary(stats))
+        .with_context(|| format!("Failed to write summary: {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_format_summary() {
+        let stats = BackupStats {
+            snapshot: Some("20240101_000000".to_string()),
+            scanned: 10,
+            matched: 6,
+            hidden_skipped: 1,
+            extension_skipped: 4,
+            files: 5,
+            new_objects: 2,
+            new_bytes: 3 * 1024 * 1024,
+            duplicates: 3,
+            deduplicated_bytes: 100,
+            bytes_read: 3 * 1024 * 1024,
+            elapsed: Duration::from_secs(2),
+            ..Default::default()
+        };
+
+        let text = format_summary(&stats);
+
+        assert!(text.starts_with("Snapshot: 20240101_000000\n"));
+        assert!(
+            text.contains("Skipped: 1 hidden, 0 excluded, 4 by extension, 0 special, 0 symlinks\n")
+        );
+        assert!(text.contains("New objects: 2 (3145728 bytes stored)\n"));
+        assert!(text.contains("Duplicates: 3 (100 bytes deduplicated)\n"));
+        assert!(text.contains("Elapsed: 2.00 s\n"));
+        assert!(text.contains("Throughput: 1.5 MiB/s\n"));
+        assert!(!text.contains("Inconsistent"));
+    }
+
+    #[test]
+    fn test_write_summary() {
+        let temp_dir = TempDir::new().unwrap();
+        let stats = BackupStats::default();
+
+        write_summary(temp_dir.path(), &stats).unwrap();
+
+        let text = fs::read_to_string(temp_dir.path().join(SUMMARY_FILE)).unwrap();
+        assert_eq!(text, format_summary(&stats));
+        // Без измеренного времени скорость не выводится
+        assert!(!text.contains("Throughput"));
+    }
+}
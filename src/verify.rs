@@ -0,0 +1,479 @@
+use crate::hash::{self, DEFAULT_BUFFER_SIZE};
+use crate::repository::{self, Repository};
+use crate::{chunking, snapshot};
+use anyhow::{bail, Result};
+use serde::Serialize;
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, HashSet};
+use std::hash::BuildHasher;
+use std::io;
+use std::path::PathBuf;
+
+#[derive(Debug, Clone, Copy)]
+pub struct VerifyOptions {
+    /// Доля объектов для перехеширования, в процентах; None - все объекты
+    pub sample_percent: Option<f64>,
+    pub buffer_size: usize,
+}
+
+impl Default for VerifyOptions {
+    fn default() -> Self {
+        Self {
+            sample_percent: None,
+            buffer_size: DEFAULT_BUFFER_SIZE,
+        }
+    }
+}
+
+/// Объект, содержимое которого не совпадает с его именем. Для объекта или списка
+/// фрагментов, который не удалось прочитать, `actual` содержит цепочку сообщений ошибки
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CorruptObject {
+    pub path: PathBuf,
+    pub expected: String,
+    pub actual: String,
+}
+
+/// Запись снимка, для которой нет объекта в хранилище или одного из фрагментов
+/// (тогда `hash` - хеш недостающего фрагмента)
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct MissingObject {
+    pub snapshot: String,
+    pub path: PathBuf,
+    pub hash: String,
+}
+
+/// Снимок, манифест которого не удалось прочитать (поврежден или недоступен)
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct UnreadableSnapshot {
+    pub snapshot: String,
+    /// Цепочка сообщений ошибки чтения
+    pub error: String,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct VerifyReport {
+    pub snapshots: usize,
+    pub objects_total: usize,
+    pub objects_checked: usize,
+    pub corrupt: Vec<CorruptObject>,
+    pub missing: Vec<MissingObject>,
+    /// Объекты, на которые не ссылается ни один снимок (освобождаются командой gc)
+    pub orphaned: Vec<PathBuf>,
+    /// Снимки, запись которых прервана; их записи не проверяются
+    pub incomplete: Vec<String>,
+    /// Снимки, которые не удалось прочитать; объекты, на которые ссылаются только
+    /// они, попадают в `orphaned`
+    pub unreadable: Vec<UnreadableSnapshot>,
+}
+
+impl VerifyReport {
+    /// Хранилище целостно: нет поврежденных объектов и снимков и битых ссылок.
+    /// Осиротевшие объекты не считаются повреждением
+    pub fn is_ok(&self) -> bool {
+        self.corrupt.is_empty() && self.missing.is_empty() && self.unreadable.is_empty()
+    }
+}
+
+/// Проверяет хранилище: перехеширует объекты и файлы из фрагментов (все или выборку),
+/// проверяет, что каждая запись каждого снимка указывает на существующий объект или
+/// полный набор фрагментов, и ищет осиротевшие объекты. Хранилище блокируется на
+/// время проверки: иначе объекты запуска, который еще не записал манифест, выглядели
+/// бы осиротевшими, а удаленные gc во время проверки - потерянными
+pub fn verify_repository(target_base: &str, options: VerifyOptions) -> Result<VerifyReport> {
+    if let Some(percent) = options.sample_percent
+        && !(percent > 0.0 && percent <= 100.0)
+    {
+        bail!("Sample percent must be in (0, 100], got {}", percent);
+    }
+
+    let repository = Repository::open(target_base)?;
+    let _lock = repository.lock()?;
+    let mut report = VerifyReport::default();
+
+    let objects = repository.list_objects()?;
+    let stored: HashSet<&str> = objects.iter().map(|(hash, _)| hash.as_str()).collect();
+    let mut referenced = HashSet::new();
+
+    // Список фрагментов, который не читается, считается поврежденным объектом
+    let mut chunk_lists = Vec::new();
+    for (hash_hex, path) in repository.list_chunk_lists()? {
+        match repository.read_chunk_list(&hash_hex) {
+            Ok(chunks) => chunk_lists.push((hash_hex, path, chunks)),
+            Err(e) => report.corrupt.push(CorruptObject {
+                path,
+                expected: hash_hex,
+                actual: format!("{:#}", e),
+            }),
+        }
+    }
+    let chunked: HashMap<&str, &[String]> = chunk_lists
+        .iter()
+        .map(|(hash, _, chunks)| (hash.as_str(), chunks.as_slice()))
+        .collect();
+    let unreadable: HashSet<String> = report.corrupt.iter().map(|c| c.expected.clone()).collect();
+
+    let snapshots = repository.list_snapshots()?;
+    report.snapshots = snapshots.len();
+    report.incomplete = repository.list_incomplete_snapshots()?;
+    for name in snapshots {
+        // Поврежденный снимок - находка проверки: он попадает в отчет,
+        // а проверка продолжается с остальными снимками
+        let entries = match snapshot::read_snapshot(
+            &repository.snapshot_dir(&name),
+            repository.algorithm(),
+        ) {
+            Ok(entries) => entries,
+            Err(e) => {
+                report.unreadable.push(UnreadableSnapshot {
+                    snapshot: name,
+                    error: format!("{:#}", e),
+                });
+                continue;
+            }
+        };
+
+        for entry in entries.into_iter().filter(|e| e.link_target.is_none()) {
+            if !stored.contains(entry.hash.as_str()) {
+                match chunked.get(entry.hash.as_str()) {
+                    // Файл из фрагментов цел, только если на месте каждый фрагмент
+                    Some(chunks) => {
+                        for chunk_hash in chunks.iter() {
+                            if !stored.contains(chunk_hash.as_str()) {
+                                report.missing.push(MissingObject {
+                                    snapshot: name.clone(),
+                                    path: entry.path.clone(),
+                                    hash: chunk_hash.clone(),
+                                });
+                            }
+                        }
+                    }
+                    None if unreadable.contains(&entry.hash) => {}
+                    None => report.missing.push(MissingObject {
+                        snapshot: name.clone(),
+                        path: entry.path,
+                        hash: entry.hash.clone(),
+                    }),
+                }
+            }
+            referenced.insert(entry.hash);
+        }
+    }
+    repository::add_referenced_chunks(&mut referenced, chunked.keys().copied(), |hash_hex| {
+        Ok(chunked[hash_hex].to_vec())
+    })?;
+
+    // Случайное зерно на каждый запуск: ночные выборки покрывают разные объекты
+    let sampler = RandomState::new();
+    let is_sampled = |hash_hex: &str| match options.sample_percent {
+        Some(percent) => ((sampler.hash_one(hash_hex) % 10_000) as f64) < percent * 100.0,
+        None => true,
+    };
+    report.objects_total = objects.len() + chunk_lists.len();
+
+    for (hash_hex, path) in &objects {
+        if !referenced.contains(hash_hex) {
+            report.orphaned.push(path.clone());
+        }
+        if !is_sampled(hash_hex) {
+            continue;
+        }
+
+        // Нечитаемый объект (например, ошибка ввода-вывода) тоже поврежден,
+        // проверка продолжается со следующего
+        let actual = hash::calculate_hash(path, repository.algorithm(), options.buffer_size)
+            .unwrap_or_else(|e| format!("{:#}", e));
+        report.objects_checked += 1;
+        if &actual != hash_hex {
+            report.corrupt.push(CorruptObject {
+                path: path.clone(),
+                expected: hash_hex.clone(),
+                actual,
+            });
+        }
+    }
+
+    // Список фрагментов проверяется по хешу собранного из них содержимого
+    for (hash_hex, path, chunks) in &chunk_lists {
+        if !referenced.contains(hash_hex) {
+            report.orphaned.push(path.clone());
+        }
+        let complete = chunks.iter().all(|chunk| stored.contains(chunk.as_str()));
+        if !complete || !is_sampled(hash_hex) {
+            continue;
+        }
+
+        let actual = chunking::write_chunks(&repository, chunks, &mut io::sink())
+            .map_or_else(|e| format!("{:#}", e), |(actual, _)| actual);
+        report.objects_checked += 1;
+        if &actual != hash_hex {
+            report.corrupt.push(CorruptObject {
+                path: path.clone(),
+                expected: hash_hex.clone(),
+                actual,
+            });
+        }
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunking::ChunkingConfig;
+    use crate::repository::RepositoryLocked;
+    use crate::snapshot::{ManifestEntry, ManifestWriter};
+    use std::fs;
+    use tempfile::TempDir;
+
+    const TEST_MD5: &str = "098f6bcd4621d373cade4e832627b4f6"; // "test"
+    const CONTENT_MD5: &str = "9a0364b9e99bb480dd25e1f0284c8555"; // "content"
+    const LOST_MD5: &str = "d41d8cd98f00b204e9800998ecf8427e";
+
+    fn store(repository: &Repository, hash_hex: &str, content: &str) {
+        let object = repository.object_path(hash_hex);
+        fs::create_dir_all(object.parent().unwrap()).unwrap();
+        fs::write(object, content).unwrap();
+    }
+
+    fn write_snapshot(repository: &Repository, name: &str, hashes: &[&str]) {
+        let snapshot_dir = repository.snapshot_dir(name);
+        fs::create_dir(&snapshot_dir).unwrap();
+        let mut manifest = ManifestWriter::create(&snapshot_dir).unwrap();
+        for (i, hash_hex) in hashes.iter().enumerate() {
+            manifest
+                .write(&ManifestEntry::from_hash(
+                    PathBuf::from(format!("file{}.txt", i)),
+                    hash_hex.to_string(),
+                ))
+                .unwrap();
+        }
+        manifest.finish().unwrap();
+    }
+
+    #[test]
+    fn test_verify_clean_repository() {
+        let temp_dir = TempDir::new().unwrap();
+        let target = temp_dir.path().to_str().unwrap();
+        let repository = Repository::open_or_init(target, None).unwrap();
+        store(&repository, TEST_MD5, "test");
+        write_snapshot(&repository, "20240101_000000", &[TEST_MD5]);
+
+        let report = verify_repository(target, VerifyOptions::default()).unwrap();
+
+        assert!(report.is_ok());
+        assert_eq!(report.snapshots, 1);
+        assert_eq!(report.objects_checked, 1);
+        assert!(report.orphaned.is_empty());
+    }
+
+    #[test]
+    fn test_verify_locks_repository() {
+        let temp_dir = TempDir::new().unwrap();
+        let target = temp_dir.path().to_str().unwrap();
+        let repository = Repository::open_or_init(target, None).unwrap();
+
+        let lock = repository.lock().unwrap();
+        let error = verify_repository(target, VerifyOptions::default()).unwrap_err();
+        assert!(error.is::<RepositoryLocked>());
+
+        drop(lock);
+        assert!(verify_repository(target, VerifyOptions::default()).is_ok());
+    }
+
+    #[test]
+    fn test_verify_detects_problems() {
+        let temp_dir = TempDir::new().unwrap();
+        let target = temp_dir.path().to_str().unwrap();
+        let repository = Repository::open_or_init(target, None).unwrap();
+        store(&repository, TEST_MD5, "bit rot");
+        store(&repository, CONTENT_MD5, "content");
+        write_snapshot(&repository, "20240101_000000", &[TEST_MD5, LOST_MD5]);
+
+        let report = verify_repository(target, VerifyOptions::default()).unwrap();
+
+        assert!(!report.is_ok());
+        assert_eq!(report.corrupt.len(), 1);
+        assert_eq!(report.corrupt[0].expected, TEST_MD5);
+        assert_eq!(
+            report.missing,
+            vec![MissingObject {
+                snapshot: "20240101_000000".to_string(),
+                path: PathBuf::from("file1.txt"),
+                hash: LOST_MD5.to_string(),
+            }]
+        );
+        assert_eq!(report.orphaned, vec![repository.object_path(CONTENT_MD5)]);
+    }
+
+    #[test]
+    fn test_verify_chunked_objects() {
+        let temp_dir = TempDir::new().unwrap();
+        let target = temp_dir.path().join("target");
+        let target = target.to_str().unwrap();
+        let repository = Repository::open_or_init(target, None).unwrap();
+        let source = temp_dir.path().join("large.txt");
+        let content: String = (0..2000).map(|i| format!("line {}\n", i)).collect();
+        fs::write(&source, content).unwrap();
+        let chunking = ChunkingConfig {
+            enabled: true,
+            min_size: 64,
+            avg_size: 256,
+            max_size: 1024,
+        };
+        let stored =
+            chunking::store_chunked(&source, &repository, &chunking, DEFAULT_BUFFER_SIZE).unwrap();
+        write_snapshot(&repository, "20240101_000000", &[&stored.hash]);
+        let chunks = repository.read_chunk_list(&stored.hash).unwrap();
+
+        let report = verify_repository(target, VerifyOptions::default()).unwrap();
+        assert!(report.is_ok());
+        assert!(report.orphaned.is_empty());
+        // Фрагменты и сам список фрагментов
+        assert_eq!(report.objects_total, chunks.len() + 1);
+        assert_eq!(report.objects_checked, chunks.len() + 1);
+
+        fs::remove_file(repository.object_path(&chunks[0])).unwrap();
+        fs::write(repository.object_path(&chunks[1]), "bit rot").unwrap();
+
+        let report = verify_repository(target, VerifyOptions::default()).unwrap();
+        assert_eq!(
+            report.missing,
+            vec![MissingObject {
+                snapshot: "20240101_000000".to_string(),
+                path: PathBuf::from("file0.txt"),
+                hash: chunks[0].clone(),
+            }]
+        );
+        assert_eq!(report.corrupt.len(), 1);
+        assert_eq!(report.corrupt[0].expected, chunks[1]);
+    }
+
+    #[test]
+    fn test_verify_chunks_of_content_stored_in_both_forms() {
+        let temp_dir = TempDir::new().unwrap();
+        let target = temp_dir.path().to_str().unwrap();
+        let repository = Repository::open_or_init(target, None).unwrap();
+        // "test" хранится целым объектом и списком из фрагментов "te" и "st"
+        let te_md5 = "569ef72642be0fadd711d6a468d68ee1";
+        let st_md5 = "627fcdb6cc9a5e16d657ca6cdef0a6bb";
+        store(&repository, TEST_MD5, "test");
+        store(&repository, te_md5, "te");
+        store(&repository, st_md5, "st");
+        let chunk_list = repository.chunk_list_path(TEST_MD5);
+        fs::create_dir_all(chunk_list.parent().unwrap()).unwrap();
+        fs::write(&chunk_list, format!("{}\n{}\n", te_md5, st_md5)).unwrap();
+        write_snapshot(&repository, "20240101_000000", &[TEST_MD5]);
+
+        let report = verify_repository(target, VerifyOptions::default()).unwrap();
+
+        // Фрагменты ссылаемого списка не осиротели: gc их тоже не удаляет
+        assert!(report.is_ok());
+        assert!(report.orphaned.is_empty(), "{:?}", report.orphaned);
+        assert_eq!(report.objects_checked, 4);
+        let gc = crate::gc::collect_garbage(
+            target,
+            crate::gc::GcOptions {
+                dry_run: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert!(gc.unreferenced.is_empty());
+    }
+
+    #[test]
+    fn test_verify_skips_incomplete_snapshot() {
+        let temp_dir = TempDir::new().unwrap();
+        let target = temp_dir.path().to_str().unwrap();
+        let repository = Repository::open_or_init(target, None).unwrap();
+        store(&repository, TEST_MD5, "test");
+        write_snapshot(&repository, "20240101_000000", &[TEST_MD5]);
+
+        let interrupted_dir = repository.snapshot_dir("20240102_000000");
+        fs::create_dir(&interrupted_dir).unwrap();
+        let mut manifest = ManifestWriter::create(&interrupted_dir).unwrap();
+        manifest
+            .write(&ManifestEntry::from_hash(
+                PathBuf::from("lost.txt"),
+                LOST_MD5.to_string(),
+            ))
+            .unwrap();
+        std::mem::forget(manifest);
+
+        let report = verify_repository(target, VerifyOptions::default()).unwrap();
+
+        assert!(report.is_ok());
+        assert_eq!(report.snapshots, 1);
+        assert_eq!(report.incomplete, vec!["20240102_000000"]);
+    }
+
+    #[test]
+    fn test_verify_reports_unreadable_snapshot() {
+        let temp_dir = TempDir::new().unwrap();
+        let target = temp_dir.path().to_str().unwrap();
+        let repository = Repository::open_or_init(target, None).unwrap();
+        store(&repository, TEST_MD5, "test");
+        write_snapshot(&repository, "20240101_000000", &[TEST_MD5]);
+        write_snapshot(&repository, "20240103_000000", &[LOST_MD5]);
+
+        let broken_dir = repository.snapshot_dir("20240102_000000");
+        fs::create_dir(&broken_dir).unwrap();
+        fs::write(broken_dir.join(snapshot::MANIFEST_FILE), "broken\n").unwrap();
+
+        let report = verify_repository(target, VerifyOptions::default()).unwrap();
+
+        assert!(!report.is_ok());
+        assert_eq!(report.unreadable.len(), 1);
+        assert_eq!(report.unreadable[0].snapshot, "20240102_000000");
+        assert!(report.unreadable[0].error.contains("at line 1"));
+        // Снимок после поврежденного тоже проверен
+        assert_eq!(report.missing.len(), 1);
+        assert_eq!(report.missing[0].snapshot, "20240103_000000");
+        assert_eq!(report.objects_checked, 1);
+    }
+
+    #[test]
+    fn test_verify_sampling() {
+        let temp_dir = TempDir::new().unwrap();
+        let target = temp_dir.path().to_str().unwrap();
+        let repository = Repository::open_or_init(target, None).unwrap();
+        let hashes: Vec<String> = (0..200)
+            .map(|i| {
+                let content = format!("object {}", i);
+                let mut hasher = repository.algorithm().hasher();
+                hasher.update(content.as_bytes());
+                let hash_hex = hasher.finalize_hex();
+                store(&repository, &hash_hex, &content);
+                hash_hex
+            })
+            .collect();
+        let hashes: Vec<&str> = hashes.iter().map(String::as_str).collect();
+        write_snapshot(&repository, "20240101_000000", &hashes);
+
+        let full = VerifyOptions {
+            sample_percent: Some(100.0),
+            ..Default::default()
+        };
+        let report = verify_repository(target, full).unwrap();
+        assert_eq!(report.objects_total, 200);
+        assert_eq!(report.objects_checked, 200);
+
+        // 10% из 200: пустая или полная выборка практически невозможна
+        let sampled = VerifyOptions {
+            sample_percent: Some(10.0),
+            ..Default::default()
+        };
+        let report = verify_repository(target, sampled).unwrap();
+        assert_eq!(report.objects_total, 200);
+        assert!(report.objects_checked > 0);
+        assert!(report.objects_checked < report.objects_total);
+
+        let invalid = VerifyOptions {
+            sample_percent: Some(0.0),
+            ..Default::default()
+        };
+        assert!(verify_repository(target, invalid).is_err());
+    }
+}